@@ -0,0 +1,10 @@
+-- Add down migration script here
+alter table collateral
+	drop column service_xpub_id,
+	drop column lender_xpub_id,
+	drop column borrower_xpub_id,
+	drop column derivation_index;
+
+DROP SEQUENCE collateral_derivation_index_seq;
+drop table party_xpub;
+DROP TYPE party_role;
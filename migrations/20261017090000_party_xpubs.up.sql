@@ -0,0 +1,24 @@
+-- Add up migration script here
+CREATE TYPE party_role AS ENUM ('borrower', 'lender', 'service');
+
+create table party_xpub (
+	id uuid NOT NULL PRIMARY KEY default gen_random_uuid(),
+	user_id uuid,
+	role party_role not null,
+	xpub TEXT not null UNIQUE,
+	master_fingerprint TEXT not null,
+	derivation_path TEXT not null,
+	created_at timestamptz NOT NULL DEFAULT NOW(),
+	updated_at timestamptz NOT NULL DEFAULT NOW(),
+
+	foreign key (user_id) references "user"(id)
+);
+
+-- every loan derives all three collateral keys at the same fresh child index
+CREATE SEQUENCE collateral_derivation_index_seq AS integer MINVALUE 0 START WITH 0;
+
+alter table collateral
+	add column derivation_index int UNIQUE,
+	add column borrower_xpub_id uuid references party_xpub(id),
+	add column lender_xpub_id uuid references party_xpub(id),
+	add column service_xpub_id uuid references party_xpub(id);
@@ -0,0 +1,5 @@
+-- Add down migration script here
+alter table collateral
+	drop column threshold;
+
+drop table loan_lender;
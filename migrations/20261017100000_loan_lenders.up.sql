@@ -0,0 +1,19 @@
+-- Add up migration script here
+-- lenders of a syndicated loan; forfeited collateral is split by amount_lent
+create table loan_lender (
+	id uuid NOT NULL PRIMARY KEY default gen_random_uuid(),
+	loan_request_id uuid not null,
+	lender_id uuid not null,
+	amount_lent double precision not null default 0,
+	collateral_pubkey TEXT not null,
+	payout_address TEXT not null,
+	created_at timestamptz NOT NULL DEFAULT NOW(),
+	updated_at timestamptz NOT NULL DEFAULT NOW(),
+
+	UNIQUE (loan_request_id, lender_id),
+	foreign key (loan_request_id) references loan_request(id),
+	foreign key (lender_id) references lender(id)
+);
+
+alter table collateral
+	add column threshold int not null default 2;
@@ -0,0 +1,8 @@
+-- Add down migration script here
+alter table collateral rename column bitcoin_amount_sats to bitcoin_amount;
+
+alter table collateral
+	drop constraint collateral_bitcoin_amount_non_negative,
+	alter column bitcoin_amount drop default,
+	alter column bitcoin_amount type double precision using bitcoin_amount / 100000000.0,
+	alter column bitcoin_amount set default 0;
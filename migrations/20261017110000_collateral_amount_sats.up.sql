@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- store collateral amounts as integer satoshis instead of floating point BTC
+alter table collateral
+	alter column bitcoin_amount drop default,
+	alter column bitcoin_amount type bigint using round(bitcoin_amount * 100000000)::bigint,
+	alter column bitcoin_amount set default 0,
+	add constraint collateral_bitcoin_amount_non_negative check (bitcoin_amount >= 0);
+
+alter table collateral rename column bitcoin_amount to bitcoin_amount_sats;
@@ -0,0 +1,5 @@
+-- Add down migration script here
+drop table collateral_deposit;
+
+-- postgres cannot drop an enum value, so loans are moved back and 'funded' is left unused
+update loan_request set status = 'approved' where status = 'funded';
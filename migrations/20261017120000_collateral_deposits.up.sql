@@ -0,0 +1,17 @@
+-- Add up migration script here
+ALTER TYPE loan_status ADD VALUE IF NOT EXISTS 'funded' AFTER 'approved';
+
+create table collateral_deposit (
+	id uuid NOT NULL PRIMARY KEY default gen_random_uuid(),
+	collateral_id uuid not null,
+	txid TEXT not null,
+	vout int not null,
+	amount_sats bigint not null check (amount_sats >= 0),
+	block_height int not null,
+	confirmations int not null default 0,
+	created_at timestamptz NOT NULL DEFAULT NOW(),
+	updated_at timestamptz NOT NULL DEFAULT NOW(),
+
+	unique (txid, vout),
+	foreign key (collateral_id) references collateral(id)
+);
@@ -0,0 +1,8 @@
+-- Add down migration script here
+delete from collateral_utxo where block_height is null;
+
+alter table collateral_utxo
+	drop column spent,
+	alter column block_height set not null;
+
+alter table collateral_utxo rename to collateral_deposit;
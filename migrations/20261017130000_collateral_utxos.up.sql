@@ -0,0 +1,7 @@
+-- Add up migration script here
+-- track every output locked at a collateral address, including top-ups and mempool deposits
+alter table collateral_deposit rename to collateral_utxo;
+
+alter table collateral_utxo
+	alter column block_height drop not null,
+	add column spent boolean not null default false;
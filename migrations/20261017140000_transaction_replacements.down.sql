@@ -0,0 +1,3 @@
+-- Add down migration script here
+drop table transaction_replacement;
+DROP TYPE collateral_txn_kind;
@@ -0,0 +1,17 @@
+-- Add up migration script here
+CREATE TYPE collateral_txn_kind AS ENUM ('funding', 'redemption', 'liquidation');
+
+-- every fee bump, so the latest transaction in a chain of replacements can be found
+create table transaction_replacement (
+	id uuid NOT NULL PRIMARY KEY default gen_random_uuid(),
+	loan_request_id uuid not null,
+	kind collateral_txn_kind not null,
+	original_txid TEXT not null,
+	replacement_txid TEXT not null UNIQUE,
+	fee_sats bigint not null check (fee_sats >= 0),
+	created_at timestamptz NOT NULL DEFAULT NOW(),
+
+	foreign key (loan_request_id) references loan_request(id)
+);
+
+create index transaction_replacement_original_txid on transaction_replacement(original_txid);
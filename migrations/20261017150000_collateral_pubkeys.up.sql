@@ -0,0 +1,19 @@
+-- Add up migration script here
+-- the keys locked in each collateral script, so key reuse is an index lookup
+create table collateral_pubkey (
+	collateral_id uuid not null references collateral(id) on delete cascade,
+	role party_role not null,
+	pubkey TEXT not null,
+	created_at timestamptz NOT NULL DEFAULT NOW(),
+
+	primary key (collateral_id, pubkey)
+);
+
+create index collateral_pubkey_pubkey_idx on collateral_pubkey (pubkey);
+
+-- backfill the 2-of-3 scripts: OP_2 <borrower> <lender> <service> OP_3 OP_CHECKMULTISIG
+insert into collateral_pubkey (collateral_id, role, pubkey)
+select collateral.id, key.role::party_role, substr(collateral.redeem_script, key.start, 66)
+from collateral
+cross join (values ('borrower', 5), ('lender', 73), ('service', 141)) as key(role, start)
+where collateral.redeem_script ~ '^5221[0-9a-f]{66}21[0-9a-f]{66}21[0-9a-f]{66}53ae$';
@@ -0,0 +1,2 @@
+-- Add down migration script here
+alter table collateral drop column watch_imported_at;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- set once the multisig address is imported into the node wallet as watch-only
+alter table collateral add column watch_imported_at timestamptz;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- where a liquidation sends the borrower what is left of the collateral after the debt
+alter table borrower add column payout_address TEXT;
@@ -1,9 +1,31 @@
+use crate::utils::get_feerate::FeeTarget;
+use bitcoin::Amount;
 use config::{Config, ConfigError, File};
 
 #[derive(serde::Deserialize, Debug)]
 pub struct Settings {
 	pub application_port: u16,
 	pub database: DatabaseSettings,
+	#[serde(default)]
+	pub fees: FeeSettings,
+	#[serde(default)]
+	pub watcher: WatcherSettings,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct WatcherSettings {
+	/// confirmations a deposit needs before it counts towards the collateral
+	pub confirmations: u32,
+	pub poll_interval_secs: u64,
+}
+
+impl Default for WatcherSettings {
+	fn default() -> Self {
+		Self {
+			confirmations: 3,
+			poll_interval_secs: 60,
+		}
+	}
 }
 
 #[derive(serde::Deserialize, Debug)]
@@ -15,6 +37,68 @@ pub struct DatabaseSettings {
 	pub database_name: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "collateral_txn_kind", rename_all = "lowercase")]
+pub enum TxnKind {
+	Funding,
+	Redemption,
+	Liquidation,
+}
+
+/// Default fee target per transaction kind and the limits no transaction may exceed
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct FeeSettings {
+	pub funding: FeeTarget,
+	pub redemption: FeeTarget,
+	pub liquidation: FeeTarget,
+	/// absolute fee limit in satoshis
+	pub max_fee_sats: u64,
+	/// fee limit as a fraction of the amount being sent
+	pub max_fee_ratio: f64,
+}
+
+impl Default for FeeSettings {
+	fn default() -> Self {
+		Self {
+			funding: FeeTarget::Fastest,
+			redemption: FeeTarget::Fastest,
+			liquidation: FeeTarget::Fastest,
+			max_fee_sats: 1_000_000,
+			max_fee_ratio: 0.05,
+		}
+	}
+}
+
+impl FeeSettings {
+	pub fn target(&self, kind: TxnKind) -> FeeTarget {
+		match kind {
+			TxnKind::Funding => self.funding,
+			TxnKind::Redemption => self.redemption,
+			TxnKind::Liquidation => self.liquidation,
+		}
+	}
+
+	/// Refuses a fee above the absolute limit or above `max_fee_ratio` of `amount`
+	pub fn check_fee(&self, fee: Amount, amount: Amount) -> Result<(), String> {
+		if fee > Amount::from_sat(self.max_fee_sats) {
+			return Err(format!(
+				"Fee of {} exceeds the maximum of {}",
+				fee,
+				Amount::from_sat(self.max_fee_sats)
+			));
+		}
+		if fee.to_sat() as f64 > amount.to_sat() as f64 * self.max_fee_ratio {
+			return Err(format!(
+				"Fee of {} is more than {}% of the {} being sent",
+				fee,
+				self.max_fee_ratio * 100.0,
+				amount
+			));
+		}
+		Ok(())
+	}
+}
+
 impl Settings {
 	pub fn get_configuration() -> Result<Self, ConfigError> {
 		let settings = Config::builder()
@@ -34,3 +118,23 @@ impl DatabaseSettings {
 		)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_check_fee() {
+		let fees = FeeSettings::default();
+
+		assert!(fees
+			.check_fee(Amount::from_sat(5_000), Amount::from_sat(1_000_000))
+			.is_ok());
+		assert!(fees
+			.check_fee(Amount::from_sat(60_000), Amount::from_sat(1_000_000))
+			.is_err());
+		assert!(fees
+			.check_fee(Amount::from_sat(1_500_000), Amount::from_int_btc(10))
+			.is_err());
+	}
+}
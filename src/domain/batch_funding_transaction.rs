@@ -0,0 +1,444 @@
+use crate::config::{FeeSettings, TxnKind};
+use crate::constants::set_network;
+use crate::domain::collateral_utxo::CollateralUtxo;
+use crate::domain::finalize_psbt::{broadcast_finalized, broadcast_signed_replacement};
+use crate::domain::funding_transaction::{
+	bump_funding, funding_psbt, previous_txns, ScriptKeyOrigins,
+};
+use crate::domain::MultisigAddress;
+use crate::repository::collateral_utxo::record_batch_utxos;
+use crate::utils::get_feerate::FeeTarget;
+use crate::utils::transaction_utils::{
+	get_outpoints_txouts, locktime_at_tip, ConstructedTxn, OutputOrdering, Txn,
+};
+use crate::utils::validate_address::validate_address;
+use bitcoin::absolute::LockTime;
+use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxOut};
+use bitcoin::{Amount, FeeRate, Network, Psbt, ScriptBuf, Txid};
+use bitcoincore_rpc::Client;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use std::collections::HashSet;
+
+/// A loan's collateral funded by a batch
+#[derive(Debug, Clone)]
+pub struct BatchRecipient {
+	pub collateral_id: Uuid,
+	pub multisig: MultisigAddress,
+	pub amount: Amount,
+}
+
+/// Funds the collateral of several loans in one transaction, e.g. for a broker onboarding
+/// many borrowers. Outputs are built in the order of `recipients`, with change last,
+/// then ordered by `output_ordering`.
+#[derive(Debug, Clone)]
+pub struct BatchFundingTxn {
+	pub recipients: Vec<BatchRecipient>,
+	pub inputs: Vec<OutPoint>,
+	pub change_address: String,
+	/// overrides the configured default for funding transactions
+	pub fee_target: Option<FeeTarget>,
+	pub output_ordering: OutputOrdering,
+	/// height the anti-fee-sniping locktime is set from, bitcoind's tip when None
+	pub tip_height: Option<u32>,
+}
+
+impl BatchFundingTxn {
+	pub fn new(
+		recipients: Vec<BatchRecipient>,
+		inputs: Vec<OutPoint>,
+		change_address: String,
+	) -> Result<Self, String> {
+		if recipients.is_empty() {
+			return Err("A batch needs at least one recipient".to_string());
+		}
+
+		let mut seen = HashSet::new();
+		for recipient in &recipients {
+			let address = recipient.multisig.create_p2wsh_address();
+			if recipient.amount < address.script_pubkey().dust_value() {
+				return Err(format!(
+					"Amount of {} for {} is below the dust limit",
+					recipient.amount, address
+				));
+			}
+			if !seen.insert(address.script_pubkey()) {
+				return Err(format!("{} appears more than once in the batch", address));
+			}
+		}
+
+		Ok(Self {
+			recipients,
+			inputs,
+			change_address,
+			fee_target: None,
+			output_ordering: OutputOrdering::default(),
+			tip_height: None,
+		})
+	}
+
+	pub fn with_fee_target(mut self, fee_target: FeeTarget) -> Self {
+		self.fee_target = Some(fee_target);
+		self
+	}
+
+	pub fn with_output_ordering(mut self, output_ordering: OutputOrdering) -> Self {
+		self.output_ordering = output_ordering;
+		self
+	}
+
+	/// Sets the locktime from this height instead of bitcoind's tip
+	pub fn with_tip_height(mut self, tip_height: u32) -> Self {
+		self.tip_height = Some(tip_height);
+		self
+	}
+
+	pub fn total_amount(&self) -> Result<Amount, String> {
+		self.recipients
+			.iter()
+			.try_fold(Amount::ZERO, |total, recipient| {
+				total.checked_add(recipient.amount)
+			})
+			.ok_or_else(|| "Batch total overflows".to_string())
+	}
+
+	pub fn construct_trxn(
+		&self,
+		client: Option<&Client>,
+		fee_settings: &FeeSettings,
+	) -> Result<ConstructedTxn, String> {
+		let spent_outputs = match client {
+			Some(rpc_client) if set_network() == Network::Regtest => {
+				get_outpoints_txouts(&self.inputs, Some(rpc_client))?
+			}
+			_ => get_outpoints_txouts(&self.inputs, None)?,
+		};
+
+		self.build_trxn(
+			&spent_outputs,
+			fee_settings,
+			locktime_at_tip(self.tip_height, client)?,
+		)
+	}
+
+	/// Builds the transaction from the outputs its inputs spend
+	fn build_trxn(
+		&self,
+		spent_outputs: &[TxOut],
+		fee_settings: &FeeSettings,
+		lock_time: LockTime,
+	) -> Result<ConstructedTxn, String> {
+		let fee_rate = self
+			.fee_target
+			.unwrap_or(fee_settings.target(TxnKind::Funding))
+			.fee_rate()?;
+
+		let constructed = BatchFundingTxn::build_payment_trxn(
+			&self.inputs,
+			spent_outputs,
+			self.calculate_outputs(None)?,
+			&self.change_script()?,
+			fee_rate,
+			self.output_ordering,
+			lock_time,
+		)?;
+		fee_settings.check_fee(constructed.fee, self.total_amount()?)?;
+
+		Ok(constructed)
+	}
+
+	/// Builds a BIP-174 PSBT the funder can sign in any PSBT-capable wallet, with every
+	/// collateral output marked. `origins` adds BIP-32 derivations for the inputs and
+	/// change output whose keys are known.
+	pub fn create_psbt(
+		&self,
+		client: Option<&Client>,
+		fee_settings: &FeeSettings,
+		origins: &ScriptKeyOrigins,
+	) -> Result<Psbt, String> {
+		let constructed = self.construct_trxn(client, fee_settings)?;
+		let previous_txns = previous_txns(&self.inputs, client)?;
+
+		self.psbt_from_parts(constructed.transaction, &previous_txns, origins)
+	}
+
+	/// Rebuilds the stuck batch `txid`, or its latest recorded replacement, at `fee_rate`,
+	/// paying the extra fee out of the change, and returns the replacement as a PSBT to
+	/// sign again. Broadcast the signed batch with `broadcast_replacement`.
+	pub async fn bump_fee(
+		&self,
+		pool: &PgPool,
+		txid: Txid,
+		fee_rate: FeeRate,
+		client: Option<&Client>,
+		fee_settings: &FeeSettings,
+		origins: &ScriptKeyOrigins,
+	) -> Result<Psbt, String> {
+		let replacement = bump_funding(
+			pool,
+			txid,
+			&self.inputs,
+			&self.collateral_scripts(),
+			&self.change_script()?,
+			fee_rate,
+			client,
+		)
+		.await?;
+		fee_settings.check_fee(replacement.fee, self.total_amount()?)?;
+
+		let previous_txns = previous_txns(&self.inputs, client)?;
+		self.psbt_from_parts(replacement.transaction, &previous_txns, origins)
+	}
+
+	fn psbt_from_parts(
+		&self,
+		unsigned_txn: Transaction,
+		previous_txns: &[Transaction],
+		origins: &ScriptKeyOrigins,
+	) -> Result<Psbt, String> {
+		funding_psbt(
+			unsigned_txn,
+			&self.inputs,
+			previous_txns,
+			&self.collateral_scripts(),
+			origins,
+		)
+	}
+
+	/// The collateral output scripts, in the order of `recipients`
+	fn collateral_scripts(&self) -> Vec<ScriptBuf> {
+		self.recipients
+			.iter()
+			.map(|recipient| recipient.multisig.create_p2wsh_address().script_pubkey())
+			.collect()
+	}
+
+	fn change_script(&self) -> Result<ScriptBuf, String> {
+		Ok(validate_address(&self.change_address, set_network())?.script_pubkey())
+	}
+
+	fn calculate_outputs(&self, change: Option<Amount>) -> Result<Vec<TxOut>, String> {
+		let mut tx_outputs: Vec<TxOut> = self
+			.recipients
+			.iter()
+			.map(|recipient| TxOut {
+				value: recipient.amount,
+				script_pubkey: recipient.multisig.create_p2wsh_address().script_pubkey(),
+			})
+			.collect();
+
+		if let Some(change_amount) = change {
+			tx_outputs.push(TxOut {
+				value: change_amount,
+				script_pubkey: self.change_script()?,
+			});
+		}
+		Ok(tx_outputs)
+	}
+
+	/// Broadcasts the batch the borrower signed and finalized, and records each
+	/// collateral output against its collateral row
+	pub async fn broadcast(
+		&self,
+		pool: &PgPool,
+		signed: Psbt,
+		client: Option<&Client>,
+	) -> Result<Txid, String> {
+		let transaction = signed
+			.clone()
+			.extract_tx()
+			.map_err(|e| format!("Error extracting the batch: {}", e))?;
+		let utxos = self.collateral_utxos(&transaction)?;
+
+		let txid = broadcast_finalized(signed, client)?;
+		self.record_outputs(pool, &utxos).await?;
+		Ok(txid)
+	}
+
+	/// Like `broadcast` for a fee bump of `txid`, also recording the replacement against
+	/// `loan_request_id`, one of the loans the batch funds, so a later bump starts from it
+	pub async fn broadcast_replacement(
+		&self,
+		pool: &PgPool,
+		loan_request_id: Uuid,
+		txid: Txid,
+		signed: Psbt,
+		client: Option<&Client>,
+	) -> Result<Txid, String> {
+		let transaction = signed
+			.clone()
+			.extract_tx()
+			.map_err(|e| format!("Error extracting the batch: {}", e))?;
+		let utxos = self.collateral_utxos(&transaction)?;
+
+		let replacement = broadcast_signed_replacement(
+			pool,
+			loan_request_id,
+			TxnKind::Funding,
+			txid,
+			signed,
+			client,
+		)
+		.await?;
+		self.record_outputs(pool, &utxos).await?;
+		Ok(replacement)
+	}
+
+	async fn record_outputs(
+		&self,
+		pool: &PgPool,
+		utxos: &[(Uuid, CollateralUtxo)],
+	) -> Result<(), String> {
+		record_batch_utxos(pool, utxos)
+			.await
+			.map_err(|e| format!("Error recording the batch outputs: {:?}", e))
+	}
+
+	/// Maps each collateral output of the broadcast batch back to its collateral row, as
+	/// unconfirmed UTXOs the deposit watcher confirms later
+	pub fn collateral_utxos(
+		&self,
+		transaction: &Transaction,
+	) -> Result<Vec<(Uuid, CollateralUtxo)>, String> {
+		let txid = transaction.txid();
+
+		self.recipients
+			.iter()
+			.map(|recipient| {
+				let script_pubkey = recipient.multisig.create_p2wsh_address().script_pubkey();
+				let vout = transaction
+					.output
+					.iter()
+					.position(|output| output.script_pubkey == script_pubkey)
+					.ok_or_else(|| {
+						format!("No output funds collateral {}", recipient.collateral_id)
+					})?;
+
+				Ok((
+					recipient.collateral_id,
+					CollateralUtxo {
+						outpoint: OutPoint::new(txid, vout as u32),
+						amount: transaction.output[vout].value,
+						height: None,
+					},
+				))
+			})
+			.collect()
+	}
+}
+
+impl Txn for BatchFundingTxn {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::transaction_utils::collateral_output_key;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use bitcoin::transaction::Version;
+	use bitcoin::PublicKey;
+
+	fn multisig(first: u8) -> MultisigAddress {
+		let secp = Secp256k1::new();
+		let [borrower, lender, service] = [first, first + 1, first + 2].map(|byte| {
+			PublicKey::new(
+				SecretKey::from_slice(&[byte; 32])
+					.unwrap()
+					.public_key(&secp),
+			)
+		});
+		MultisigAddress::new(borrower, lender, service)
+	}
+
+	fn recipient(first: u8, sats: u64) -> BatchRecipient {
+		BatchRecipient {
+			collateral_id: Uuid::from_bytes([first; 16]),
+			multisig: multisig(first),
+			amount: Amount::from_sat(sats),
+		}
+	}
+
+	fn batch(recipients: Vec<BatchRecipient>) -> Result<BatchFundingTxn, String> {
+		BatchFundingTxn::new(
+			recipients,
+			Vec::new(),
+			"bcrt1qeygjhsgt5sumtlqnyfu58harh3737z96m0zmqv".to_string(),
+		)
+	}
+
+	#[test]
+	fn test_outputs_map_back_to_collateral() {
+		let batch = batch(vec![recipient(1, 500_000), recipient(4, 700_000)]).unwrap();
+		let transaction = Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: Vec::new(),
+			output: batch
+				.calculate_outputs(Some(Amount::from_sat(10_000)))
+				.unwrap(),
+		};
+
+		assert_eq!(batch.total_amount().unwrap(), Amount::from_sat(1_200_000));
+		assert_eq!(transaction.output.len(), 3);
+
+		let utxos = batch.collateral_utxos(&transaction).unwrap();
+		assert_eq!(utxos[1].0, Uuid::from_bytes([4; 16]));
+		assert_eq!(utxos[1].1.outpoint.vout, 1);
+		assert_eq!(utxos[1].1.amount, Amount::from_sat(700_000));
+	}
+
+	#[test]
+	fn test_invalid_batches() {
+		assert!(batch(Vec::new()).is_err());
+		assert!(batch(vec![recipient(1, 100)]).is_err());
+		assert!(batch(vec![recipient(1, 500_000), recipient(1, 600_000)]).is_err());
+	}
+
+	#[test]
+	fn test_batch_psbt_marks_every_collateral() {
+		let batch = batch(vec![recipient(1, 500_000), recipient(4, 700_000)])
+			.unwrap()
+			.with_fee_target(FeeTarget::SatPerVb(5));
+		let wallet_script = batch.change_script().unwrap();
+		let previous_txn = Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: Vec::new(),
+			output: vec![TxOut {
+				value: Amount::from_sat(2_000_000),
+				script_pubkey: wallet_script,
+			}],
+		};
+		let batch = BatchFundingTxn {
+			inputs: vec![OutPoint::new(previous_txn.txid(), 0)],
+			..batch
+		};
+
+		let constructed = batch
+			.build_trxn(
+				&previous_txn.output,
+				&FeeSettings::default(),
+				LockTime::ZERO,
+			)
+			.unwrap();
+		assert_eq!(constructed.output_map.len(), 3);
+		assert_eq!(
+			constructed.transaction.output[constructed.output_map[1] as usize].value,
+			Amount::from_sat(700_000)
+		);
+
+		let psbt = batch
+			.psbt_from_parts(
+				constructed.transaction,
+				&[previous_txn],
+				&ScriptKeyOrigins::new(),
+			)
+			.unwrap();
+		for (index, vout) in constructed.output_map.iter().enumerate() {
+			let marked = psbt.outputs[*vout as usize]
+				.proprietary
+				.contains_key(&collateral_output_key());
+			assert_eq!(marked, index < 2);
+		}
+		assert!(psbt.inputs[0].witness_utxo.is_some());
+	}
+}
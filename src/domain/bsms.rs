@@ -0,0 +1,543 @@
+// BIP-129 Bitcoin Secure Multisig Setup for the collateral 2-of-3.
+//
+// Round 1: the coordinator hands a token to the borrower and lender, whose signers
+// return key records signed with the key they contribute.
+// Round 2: the coordinator combines the keys with the service key and distributes a
+// descriptor record; each signer checks its key is included and the first address matches.
+use crate::constants::set_network;
+use crate::domain::collateral_descriptor::{
+	CollateralDescriptor, CollateralKeyOrigins, KeyOrigin, MultisigKind,
+};
+use crate::domain::party_role::PartyRole;
+use crate::domain::MultisigAddress;
+use aes::Aes256;
+use base64::{engine::general_purpose, Engine as _};
+use bitcoin::hashes::{hmac, sha256, sha512, Hash, HashEngine};
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+use bitcoin::sign_message::{signed_msg_hash, MessageSignature};
+use bitcoin::{Address, PublicKey};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+pub const BSMS_VERSION: &str = "BSMS 1.0";
+const NO_PATH_RESTRICTIONS: &str = "No path restrictions";
+const PBKDF2_PASSWORD: &[u8] = b"No SPOF";
+const PBKDF2_ITERATIONS: usize = 2048;
+const MAC_LEN: usize = 32;
+
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenSize {
+	Bits64,
+	Bits128,
+}
+
+/// Session token shared with the signers; an empty token ("00") disables encryption
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token(Vec<u8>);
+
+impl Token {
+	pub fn generate(size: TokenSize) -> Self {
+		let mut token = match size {
+			TokenSize::Bits64 => vec![0u8; 8],
+			TokenSize::Bits128 => vec![0u8; 16],
+		};
+		thread_rng().fill_bytes(&mut token);
+		Self(token)
+	}
+
+	pub fn no_encryption() -> Self {
+		Self(Vec::new())
+	}
+
+	pub fn is_encrypted(&self) -> bool {
+		!self.0.is_empty()
+	}
+
+	fn encryption_key(&self) -> [u8; 32] {
+		pbkdf2_sha512(PBKDF2_PASSWORD, &self.0, PBKDF2_ITERATIONS)
+	}
+
+	fn mac(&self, key: &[u8; 32], data: &[u8]) -> [u8; MAC_LEN] {
+		let mac_key = sha256::Hash::hash(key);
+		let mut engine = hmac::HmacEngine::<sha256::Hash>::new(mac_key.as_byte_array());
+		engine.input(&self.0);
+		engine.input(data);
+		hmac::Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+	}
+
+	/// Encrypts a record: hex(MAC || AES-256-CTR(record)), or the plain record without a token
+	pub fn encrypt(&self, record: &str) -> String {
+		if !self.is_encrypted() {
+			return record.to_string();
+		}
+
+		let key = self.encryption_key();
+		let mac = self.mac(&key, record.as_bytes());
+		let mut data = record.as_bytes().to_vec();
+		let mut iv = [0u8; 16];
+		iv.copy_from_slice(&mac[..16]);
+
+		Aes256Ctr::new(&key.into(), &iv.into()).apply_keystream(&mut data);
+
+		hex::encode([mac.as_slice(), data.as_slice()].concat())
+	}
+
+	pub fn decrypt(&self, encrypted: &str) -> Result<String, String> {
+		if !self.is_encrypted() {
+			return Ok(encrypted.to_string());
+		}
+
+		let bytes = hex::decode(encrypted.trim())
+			.map_err(|e| format!("Encrypted record is not hex: {:?}", e))?;
+		if bytes.len() <= MAC_LEN {
+			return Err("Encrypted record is too short".to_string());
+		}
+
+		let (mac, data) = bytes.split_at(MAC_LEN);
+		let key = self.encryption_key();
+		let mut data = data.to_vec();
+		let mut iv = [0u8; 16];
+		iv.copy_from_slice(&mac[..16]);
+
+		Aes256Ctr::new(&key.into(), &iv.into()).apply_keystream(&mut data);
+
+		if self.mac(&key, &data) != mac {
+			return Err("Record MAC does not match, wrong token or tampered record".to_string());
+		}
+		String::from_utf8(data).map_err(|_| "Decrypted record is not valid UTF-8".to_string())
+	}
+}
+
+impl fmt::Display for Token {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.is_encrypted() {
+			true => write!(f, "{}", hex::encode(&self.0)),
+			false => write!(f, "00"),
+		}
+	}
+}
+
+impl FromStr for Token {
+	type Err = String;
+
+	fn from_str(token: &str) -> Result<Self, Self::Err> {
+		if token == "00" {
+			return Ok(Self::no_encryption());
+		}
+
+		let bytes = hex::decode(token).map_err(|e| format!("Invalid token: {:?}", e))?;
+		match bytes.len() {
+			8 | 16 => Ok(Self(bytes)),
+			len => Err(format!("Token must be 64 or 128 bits, found {} bytes", len)),
+		}
+	}
+}
+
+/// PBKDF2 with HMAC-SHA512; a single block is enough for the 32-byte key
+fn pbkdf2_sha512(password: &[u8], salt: &[u8], iterations: usize) -> [u8; 32] {
+	let prf = |data: &[&[u8]]| {
+		let mut engine = hmac::HmacEngine::<sha512::Hash>::new(password);
+		for chunk in data {
+			engine.input(chunk);
+		}
+		hmac::Hmac::<sha512::Hash>::from_engine(engine).to_byte_array()
+	};
+
+	let mut block = prf(&[salt, &1u32.to_be_bytes()]);
+	let mut output = block;
+	for _ in 1..iterations {
+		block = prf(&[&block]);
+		output
+			.iter_mut()
+			.zip(block.iter())
+			.for_each(|(out, byte)| *out ^= byte);
+	}
+
+	let mut key = [0u8; 32];
+	key.copy_from_slice(&output[..32]);
+	key
+}
+
+/// Round 1: a signer's key, signed with that key
+#[derive(Debug, Clone)]
+pub struct KeyRecord {
+	pub token: Token,
+	pub origin: KeyOrigin,
+	pub pubkey: PublicKey,
+	pub description: String,
+	pub signature: MessageSignature,
+}
+
+impl KeyRecord {
+	fn message(token: &Token, origin: &KeyOrigin, pubkey: &PublicKey, description: &str) -> String {
+		format!(
+			"{}\n{}\n{}{}\n{}",
+			BSMS_VERSION, token, origin, pubkey, description
+		)
+	}
+
+	/// Creates a key record the way a signer would
+	pub fn sign(
+		token: Token,
+		origin: KeyOrigin,
+		secret_key: &SecretKey,
+		description: &str,
+	) -> Self {
+		let secp = Secp256k1::new();
+		let pubkey = PublicKey::new(secret_key.public_key(&secp));
+		let hash = signed_msg_hash(&Self::message(&token, &origin, &pubkey, description));
+		let message = Message::from_digest(hash.to_byte_array());
+
+		Self {
+			signature: MessageSignature::new(
+				secp.sign_ecdsa_recoverable(&message, secret_key),
+				pubkey.compressed,
+			),
+			token,
+			origin,
+			pubkey,
+			description: description.to_string(),
+		}
+	}
+
+	/// Checks the signature was made by the key in the record
+	pub fn verify(&self) -> Result<(), String> {
+		let secp = Secp256k1::verification_only();
+		let hash = signed_msg_hash(&Self::message(
+			&self.token,
+			&self.origin,
+			&self.pubkey,
+			&self.description,
+		));
+
+		let signer = self
+			.signature
+			.recover_pubkey(&secp, hash)
+			.map_err(|e| format!("Invalid key record signature: {:?}", e))?;
+
+		if signer != self.pubkey {
+			return Err("Key record is not signed by the key it contains".to_string());
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for KeyRecord {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{}\n{}",
+			Self::message(&self.token, &self.origin, &self.pubkey, &self.description),
+			general_purpose::STANDARD.encode(self.signature.serialize())
+		)
+	}
+}
+
+impl FromStr for KeyRecord {
+	type Err = String;
+
+	fn from_str(record: &str) -> Result<Self, Self::Err> {
+		let lines: Vec<&str> = record.trim().lines().map(str::trim).collect();
+		let [version, token, key, description, signature] = lines.as_slice() else {
+			return Err(format!(
+				"Key record must have 5 lines, found {}",
+				lines.len()
+			));
+		};
+
+		if *version != BSMS_VERSION {
+			return Err(format!("Unsupported BSMS version: {}", version));
+		}
+
+		let (origin, pubkey) = key
+			.split_once(']')
+			.ok_or_else(|| format!("Key must include its origin: {}", key))?;
+		let signature = general_purpose::STANDARD
+			.decode(signature)
+			.map_err(|e| format!("Signature is not base64: {:?}", e))?;
+
+		Ok(Self {
+			token: Token::from_str(token)?,
+			origin: KeyOrigin::from_str(&format!("{}]", origin))?,
+			pubkey: PublicKey::from_str(pubkey).map_err(|e| format!("Invalid key: {:?}", e))?,
+			description: description.to_string(),
+			signature: MessageSignature::from_slice(&signature)
+				.map_err(|e| format!("Invalid signature: {:?}", e))?,
+		})
+	}
+}
+
+/// Round 2: the collateral descriptor and the address signers must confirm
+#[derive(Debug, Clone)]
+pub struct DescriptorRecord {
+	pub descriptor: CollateralDescriptor,
+	pub first_address: Address,
+}
+
+impl DescriptorRecord {
+	/// Run by each signer: checks its key is part of the descriptor and that the
+	/// descriptor derives the advertised address
+	pub fn verify(&self, pubkey: &PublicKey) -> Result<(), String> {
+		let multisig = &self.descriptor.multisig;
+		let keys = [
+			multisig.borrower_pubkey,
+			multisig.lender_pubkey,
+			multisig.service_pubkey,
+		];
+
+		if !keys.contains(pubkey) {
+			return Err("Descriptor does not contain the signer's key".to_string());
+		}
+		if self.descriptor.address()? != self.first_address {
+			return Err("First address does not match the descriptor".to_string());
+		}
+		Ok(())
+	}
+}
+
+impl fmt::Display for DescriptorRecord {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(
+			f,
+			"{}\n{}\n{}\n{}",
+			BSMS_VERSION, self.descriptor, NO_PATH_RESTRICTIONS, self.first_address
+		)
+	}
+}
+
+impl FromStr for DescriptorRecord {
+	type Err = String;
+
+	fn from_str(record: &str) -> Result<Self, Self::Err> {
+		let lines: Vec<&str> = record.trim().lines().map(str::trim).collect();
+		let [version, descriptor, _path_restrictions, first_address] = lines.as_slice() else {
+			return Err(format!(
+				"Descriptor record must have 4 lines, found {}",
+				lines.len()
+			));
+		};
+
+		if *version != BSMS_VERSION {
+			return Err(format!("Unsupported BSMS version: {}", version));
+		}
+
+		let descriptor = CollateralDescriptor::from_str(descriptor)?;
+		let first_address = Address::from_str(first_address)
+			.map_err(|e| format!("Invalid first address: {:?}", e))?
+			.require_network(set_network())
+			.map_err(|e| format!("Invalid first address: {:?}", e))?;
+
+		Ok(Self {
+			descriptor,
+			first_address,
+		})
+	}
+}
+
+/// Collects the borrower and lender key records for one loan and produces the
+/// descriptor record once both are in
+#[derive(Debug, Clone)]
+pub struct BsmsCoordinator {
+	pub token: Token,
+	pub service_pubkey: PublicKey,
+	pub service_origin: Option<KeyOrigin>,
+	pub borrower: Option<KeyRecord>,
+	pub lender: Option<KeyRecord>,
+}
+
+impl BsmsCoordinator {
+	pub fn new(token: Token, service_pubkey: PublicKey, service_origin: Option<KeyOrigin>) -> Self {
+		Self {
+			token,
+			service_pubkey,
+			service_origin,
+			borrower: None,
+			lender: None,
+		}
+	}
+
+	/// Decrypts, parses and verifies a key record submitted for the given role
+	pub fn add_key_record(&mut self, role: PartyRole, record: &str) -> Result<(), String> {
+		let record = KeyRecord::from_str(&self.token.decrypt(record)?)?;
+
+		if record.token != self.token {
+			return Err("Key record was made for a different session".to_string());
+		}
+		record.verify()?;
+
+		match role {
+			PartyRole::Borrower => self.borrower = Some(record),
+			PartyRole::Lender => self.lender = Some(record),
+			PartyRole::Service => {
+				return Err("The service key is set by the coordinator".to_string())
+			}
+		}
+		Ok(())
+	}
+
+	/// Builds the descriptor record, validating the keys against those already in use
+	pub fn descriptor_record(
+		&self,
+		used_keys: &HashSet<PublicKey>,
+	) -> Result<DescriptorRecord, String> {
+		let (borrower, lender) = match (&self.borrower, &self.lender) {
+			(Some(borrower), Some(lender)) => (borrower, lender),
+			_ => return Err("Waiting for borrower and lender key records".to_string()),
+		};
+
+		let multisig = MultisigAddress::try_new(
+			borrower.pubkey,
+			lender.pubkey,
+			self.service_pubkey,
+			used_keys,
+		)
+		.map_err(|e| e.to_string())?;
+
+		let descriptor = multisig.to_descriptor(
+			MultisigKind::Multi,
+			CollateralKeyOrigins {
+				borrower: Some(borrower.origin.clone()),
+				lender: Some(lender.origin.clone()),
+				service: self.service_origin.clone(),
+			},
+		);
+
+		Ok(DescriptorRecord {
+			first_address: descriptor.address()?,
+			descriptor,
+		})
+	}
+
+	/// The descriptor record as distributed to the signers, encrypted with the session token
+	pub fn encrypted_descriptor_record(
+		&self,
+		used_keys: &HashSet<PublicKey>,
+	) -> Result<String, String> {
+		let record = self.descriptor_record(used_keys)?;
+		Ok(self.token.encrypt(&record.to_string()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn secret_key(byte: u8) -> SecretKey {
+		SecretKey::from_slice(&[byte; 32]).unwrap()
+	}
+
+	fn origin(account: u32) -> KeyOrigin {
+		KeyOrigin::from_str(&format!("[c258d2e4/48'/1'/{}'/2']", account)).unwrap()
+	}
+
+	fn coordinator(token: Token) -> BsmsCoordinator {
+		let service = PublicKey::new(secret_key(3).public_key(&Secp256k1::new()));
+		BsmsCoordinator::new(token, service, Some(origin(3)))
+	}
+
+	#[test]
+	fn test_key_record_round_trip() {
+		let record = KeyRecord::sign(
+			Token::generate(TokenSize::Bits64),
+			origin(1),
+			&secret_key(1),
+			"borrower coldcard",
+		);
+		let parsed = KeyRecord::from_str(&record.to_string()).unwrap();
+
+		assert!(parsed.verify().is_ok());
+		assert_eq!(parsed.pubkey, record.pubkey);
+	}
+
+	#[test]
+	fn test_tampered_key_record() {
+		let record = KeyRecord::sign(
+			Token::no_encryption(),
+			origin(1),
+			&secret_key(1),
+			"borrower",
+		);
+		let tampered = record.to_string().replace("borrower", "lender");
+
+		assert!(KeyRecord::from_str(&tampered).unwrap().verify().is_err());
+	}
+
+	#[test]
+	fn test_encryption_round_trip() {
+		let token = Token::generate(TokenSize::Bits128);
+		let encrypted = token.encrypt("BSMS 1.0");
+
+		assert_ne!(encrypted, "BSMS 1.0");
+		assert_eq!(token.decrypt(&encrypted).unwrap(), "BSMS 1.0");
+		assert!(Token::generate(TokenSize::Bits128)
+			.decrypt(&encrypted)
+			.is_err());
+	}
+
+	#[test]
+	fn test_encryption_vector() {
+		// BIP-129 token and key; the session token is the PBKDF2 salt
+		let token = Token::from_str("a54044308ceac9b7").unwrap();
+		let record = "BSMS 1.0\n00\n[c258d2e4/48'/1'/0'/2']tpubD6NzVbkrYhZ4X\nborrower";
+		let encrypted = "f514a3b87d1a0830e47a2b65f0d5f465605f0e3ef110cd2d85c38f047d666d73\
+			25f28049bcca52ed1a19a79fc115ed38843c5698559cf5b82528ab91679fb434a0cd32a3dbd3f1aafc80\
+			e2b641da29a11181d8b9f5f7d6ae25d9bc7a05";
+
+		assert_eq!(
+			hex::encode(token.encryption_key()),
+			"7673ffd9efd70336a5442eda0b31457f7b6cdf7b42fe17f274434df55efa9839"
+		);
+		assert_eq!(token.encrypt(record), encrypted);
+		assert_eq!(token.decrypt(encrypted).unwrap(), record);
+	}
+
+	#[test]
+	fn test_setup_flow() {
+		let token = Token::generate(TokenSize::Bits64);
+		let mut coordinator = coordinator(token.clone());
+
+		let borrower = KeyRecord::sign(token.clone(), origin(1), &secret_key(1), "borrower");
+		let lender = KeyRecord::sign(token.clone(), origin(2), &secret_key(2), "lender");
+
+		assert!(coordinator.descriptor_record(&HashSet::new()).is_err());
+		coordinator
+			.add_key_record(PartyRole::Borrower, &token.encrypt(&borrower.to_string()))
+			.unwrap();
+		coordinator
+			.add_key_record(PartyRole::Lender, &token.encrypt(&lender.to_string()))
+			.unwrap();
+
+		let distributed = coordinator
+			.encrypted_descriptor_record(&HashSet::new())
+			.unwrap();
+		let record = DescriptorRecord::from_str(&token.decrypt(&distributed).unwrap()).unwrap();
+
+		assert!(record.verify(&borrower.pubkey).is_ok());
+		assert!(record.verify(&lender.pubkey).is_ok());
+		assert_eq!(
+			record.first_address,
+			MultisigAddress::new(borrower.pubkey, lender.pubkey, coordinator.service_pubkey)
+				.create_p2wsh_address()
+		);
+	}
+
+	#[test]
+	fn test_rejects_record_from_other_session() {
+		let mut coordinator = coordinator(Token::no_encryption());
+		let record = KeyRecord::sign(
+			Token::generate(TokenSize::Bits64),
+			origin(1),
+			&secret_key(1),
+			"borrower",
+		);
+
+		assert!(coordinator
+			.add_key_record(PartyRole::Borrower, &record.to_string())
+			.is_err());
+	}
+}
@@ -0,0 +1,334 @@
+use crate::constants::set_network;
+use crate::domain::collateral_utxo::CollateralUtxo;
+use crate::domain::threshold_multisig::ThresholdMultisig;
+use crate::domain::MultisigAddress;
+use crate::utils::miniscript_compat::{from_ms_address, from_ms_pubkey, to_ms_network};
+use bdk::miniscript::descriptor::{DescriptorPublicKey, SinglePubKey, WshInner};
+use bdk::miniscript::{Descriptor, Terminal};
+use bitcoin::bip32::{DerivationPath, Fingerprint, KeySource};
+use bitcoin::psbt::Input;
+use bitcoin::{secp256k1, Address, PublicKey, Transaction};
+use bitcoincore_rpc::Client;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// BIP-380 key origin: master key fingerprint and derivation path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyOrigin {
+	pub fingerprint: Fingerprint,
+	pub path: DerivationPath,
+}
+
+impl fmt::Display for KeyOrigin {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "[{}", self.fingerprint)?;
+		for child in self.path.as_ref() {
+			write!(f, "/{}", child)?;
+		}
+		write!(f, "]")
+	}
+}
+
+impl FromStr for KeyOrigin {
+	type Err = String;
+
+	/// Parses `[fingerprint/path]`, e.g. `[c258d2e4/48'/1'/0'/2']`
+	fn from_str(origin: &str) -> Result<Self, Self::Err> {
+		let inner = origin
+			.strip_prefix('[')
+			.and_then(|origin| origin.strip_suffix(']'))
+			.ok_or_else(|| format!("Key origin must be enclosed in brackets: {}", origin))?;
+
+		let (fingerprint, path) = inner.split_once('/').unwrap_or((inner, ""));
+		let fingerprint = Fingerprint::from_str(fingerprint)
+			.map_err(|e| format!("Invalid key origin fingerprint: {:?}", e))?;
+		let path = match path {
+			"" => DerivationPath::master(),
+			path => DerivationPath::from_str(&format!("m/{}", path))
+				.map_err(|e| format!("Invalid key origin path: {:?}", e))?,
+		};
+
+		Ok(Self { fingerprint, path })
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MultisigKind {
+	/// wsh(multi(...)): keys appear in the script in borrower, lender, service order,
+	/// matching `MultisigAddress::redeem_script`
+	Multi,
+	/// wsh(sortedmulti(...)): keys are sorted lexicographically in the script
+	SortedMulti,
+}
+
+/// Key origins of the collateral parties, when known
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CollateralKeyOrigins {
+	pub borrower: Option<KeyOrigin>,
+	pub lender: Option<KeyOrigin>,
+	pub service: Option<KeyOrigin>,
+}
+
+/// The collateral 2-of-3 as an output descriptor
+#[derive(Debug, Clone)]
+pub struct CollateralDescriptor {
+	pub multisig: MultisigAddress,
+	pub kind: MultisigKind,
+	pub origins: CollateralKeyOrigins,
+}
+
+impl CollateralDescriptor {
+	fn descriptor(&self) -> Result<Descriptor<DescriptorPublicKey>, String> {
+		let key = |origin: &Option<KeyOrigin>, pubkey: &PublicKey| match origin {
+			Some(origin) => format!("{}{}", origin, pubkey),
+			None => pubkey.to_string(),
+		};
+
+		let fragment = match self.kind {
+			MultisigKind::Multi => "multi",
+			MultisigKind::SortedMulti => "sortedmulti",
+		};
+
+		let descriptor = format!(
+			"wsh({}(2,{},{},{}))",
+			fragment,
+			key(&self.origins.borrower, &self.multisig.borrower_pubkey),
+			key(&self.origins.lender, &self.multisig.lender_pubkey),
+			key(&self.origins.service, &self.multisig.service_pubkey),
+		);
+
+		Descriptor::from_str(&descriptor).map_err(|e| format!("Invalid descriptor: {}", e))
+	}
+
+	/// The address the descriptor pays to; for `SortedMulti` this differs from
+	/// `MultisigAddress::create_p2wsh_address`
+	pub fn address(&self) -> Result<Address, String> {
+		let network = set_network();
+		let descriptor = self
+			.descriptor()?
+			.at_derivation_index(0)
+			.map_err(|e| format!("Invalid descriptor: {}", e))?;
+		let address = descriptor
+			.address(to_ms_network(network))
+			.map_err(|e| format!("Error deriving address: {}", e))?;
+		from_ms_address(&address, network)
+	}
+}
+
+impl fmt::Display for CollateralDescriptor {
+	/// Writes the descriptor with its checksum
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let descriptor = self.descriptor().map_err(|_| fmt::Error)?;
+		write!(f, "{}", descriptor)
+	}
+}
+
+impl FromStr for CollateralDescriptor {
+	type Err = String;
+
+	/// Parses a `wsh(multi(2,...))` or `wsh(sortedmulti(2,...))` descriptor with exactly three
+	/// keys, taken in borrower, lender, service order. The checksum is verified when present.
+	fn from_str(descriptor: &str) -> Result<Self, Self::Err> {
+		let descriptor = Descriptor::<DescriptorPublicKey>::from_str(descriptor)
+			.map_err(|e| format!("Invalid descriptor: {}", e))?;
+
+		let wsh = match descriptor {
+			Descriptor::Wsh(wsh) => wsh,
+			_ => return Err("Only wsh() collateral descriptors are supported".to_string()),
+		};
+
+		let (kind, threshold, keys) = match wsh.as_inner() {
+			WshInner::SortedMulti(sorted) => {
+				(MultisigKind::SortedMulti, sorted.k, sorted.pks.clone())
+			}
+			WshInner::Ms(ms) => match &ms.node {
+				Terminal::Multi(k, keys) => (MultisigKind::Multi, *k, keys.clone()),
+				_ => return Err("Descriptor is not a multi() or sortedmulti() script".to_string()),
+			},
+		};
+
+		if threshold != 2 || keys.len() != 3 {
+			return Err(format!(
+				"Expected a 2-of-3 descriptor, found {}-of-{}",
+				threshold,
+				keys.len()
+			));
+		}
+
+		let mut pubkeys = Vec::new();
+		let mut origins = Vec::new();
+		for key in keys {
+			let single = match key {
+				DescriptorPublicKey::Single(single) => single,
+				_ => return Err("Only single public keys are supported".to_string()),
+			};
+			let pubkey = match single.key {
+				SinglePubKey::FullKey(pubkey) => from_ms_pubkey(&pubkey)?,
+				SinglePubKey::XOnly(_) => {
+					return Err("X-only keys are not valid in wsh()".to_string())
+				}
+			};
+			let origin = match single.origin {
+				Some((fingerprint, path)) => Some(KeyOrigin {
+					fingerprint: Fingerprint::from(fingerprint.to_bytes()),
+					path: DerivationPath::from_str(&path.to_string())
+						.map_err(|e| format!("Invalid key origin path: {:?}", e))?,
+				}),
+				None => None,
+			};
+			pubkeys.push(pubkey);
+			origins.push(origin);
+		}
+
+		Ok(Self {
+			multisig: MultisigAddress::new(pubkeys[0], pubkeys[1], pubkeys[2]),
+			kind,
+			origins: CollateralKeyOrigins {
+				borrower: origins[0].clone(),
+				lender: origins[1].clone(),
+				service: origins[2].clone(),
+			},
+		})
+	}
+}
+
+impl MultisigAddress {
+	pub fn to_descriptor(
+		&self,
+		kind: MultisigKind,
+		origins: CollateralKeyOrigins,
+	) -> CollateralDescriptor {
+		CollateralDescriptor {
+			multisig: self.clone(),
+			kind,
+			origins,
+		}
+	}
+
+	pub fn from_descriptor(descriptor: &str) -> Result<Self, String> {
+		Ok(CollateralDescriptor::from_str(descriptor)?.multisig)
+	}
+
+	/// Derivations of the keys with a known origin, keyed as PSBT signers look them up
+	pub fn key_derivations(
+		&self,
+		origins: &CollateralKeyOrigins,
+	) -> BTreeMap<secp256k1::PublicKey, KeySource> {
+		[
+			(&self.borrower_pubkey, &origins.borrower),
+			(&self.lender_pubkey, &origins.lender),
+			(&self.service_pubkey, &origins.service),
+		]
+		.into_iter()
+		.filter_map(|(pubkey, origin)| {
+			origin
+				.as_ref()
+				.map(|origin| (pubkey.inner, (origin.fingerprint, origin.path.clone())))
+		})
+		.collect()
+	}
+
+	/// PSBT input spending output `vout` of `previous_txn` from the P2WSH, carrying the
+	/// derivations of the 2-of-3 keys
+	pub fn psbt_input(
+		&self,
+		previous_txn: &Transaction,
+		vout: u32,
+		origins: &CollateralKeyOrigins,
+	) -> Result<Input, String> {
+		ThresholdMultisig::from(self).psbt_input(previous_txn, vout, &self.key_derivations(origins))
+	}
+
+	/// PSBT inputs spending `utxos`, see [`ThresholdMultisig::psbt_inputs`]
+	pub fn psbt_inputs(
+		&self,
+		utxos: &[CollateralUtxo],
+		previous_txns: &[Transaction],
+		origins: &CollateralKeyOrigins,
+		client: Option<&Client>,
+	) -> Result<Vec<Input>, String> {
+		ThresholdMultisig::from(self).psbt_inputs(
+			utxos,
+			previous_txns,
+			&self.key_derivations(origins),
+			client,
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn multisig() -> MultisigAddress {
+		MultisigAddress::new(
+			PublicKey::from_str(
+				"02f0eaa04e609b0044ef1fe09a350dc4b744a5a8604a6fa77bc9bf6443ea50739f",
+			)
+			.unwrap(),
+			PublicKey::from_str(
+				"037c60db011a840523f216e7198054ef071c5acd3d4b466cf2658b7faf30c11e33",
+			)
+			.unwrap(),
+			PublicKey::from_str(
+				"02ca49f36d3de1e135e033052611dd0873af55b57f07d5d0d1090ceb267ac34e6b",
+			)
+			.unwrap(),
+		)
+	}
+
+	fn origins() -> CollateralKeyOrigins {
+		CollateralKeyOrigins {
+			borrower: Some(KeyOrigin::from_str("[c258d2e4/48'/1'/0'/2']").unwrap()),
+			lender: None,
+			service: Some(KeyOrigin::from_str("[0a0b0c0d/48'/1'/3'/2']").unwrap()),
+		}
+	}
+
+	#[test]
+	fn test_export_multi_descriptor() {
+		let descriptor = multisig().to_descriptor(MultisigKind::Multi, origins());
+		let exported = descriptor.to_string();
+
+		assert!(exported.starts_with("wsh(multi(2,[c258d2e4/48'/1'/0'/2']02f0eaa0"));
+		assert!(exported.contains('#'));
+		assert_eq!(
+			descriptor.address().unwrap(),
+			multisig().create_p2wsh_address()
+		);
+	}
+
+	#[test]
+	fn test_descriptor_round_trip() {
+		for kind in [MultisigKind::Multi, MultisigKind::SortedMulti] {
+			let exported = multisig().to_descriptor(kind, origins()).to_string();
+			let imported = CollateralDescriptor::from_str(&exported).unwrap();
+
+			assert_eq!(imported.kind, kind);
+			assert_eq!(imported.origins, origins());
+			assert_eq!(
+				imported.multisig.redeem_script(),
+				multisig().redeem_script()
+			);
+		}
+	}
+
+	#[test]
+	fn test_invalid_checksum() {
+		let exported = multisig()
+			.to_descriptor(MultisigKind::Multi, CollateralKeyOrigins::default())
+			.to_string();
+		let (body, _) = exported.split_once('#').unwrap();
+
+		assert!(MultisigAddress::from_descriptor(body).is_ok());
+		assert!(MultisigAddress::from_descriptor(&format!("{}#qqqqqqqq", body)).is_err());
+	}
+
+	#[test]
+	fn test_rejects_non_collateral_descriptor() {
+		let descriptor = "wpkh(02f0eaa04e609b0044ef1fe09a350dc4b744a5a8604a6fa77bc9bf6443ea50739f)";
+
+		assert!(MultisigAddress::from_descriptor(descriptor).is_err());
+	}
+}
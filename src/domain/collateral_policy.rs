@@ -0,0 +1,324 @@
+use crate::constants::set_network;
+use crate::domain::MultisigAddress;
+use crate::utils::miniscript_compat::{
+	from_ms_script, to_ms_pubkey, to_ms_signature, witness_from_stack,
+};
+use bdk::bitcoin as ms_bitcoin;
+use bdk::miniscript::Descriptor;
+use bitcoin::absolute::LockTime;
+use bitcoin::{ecdsa, Address, PublicKey, ScriptBuf, Sequence, Witness};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+pub const BLOCKS_PER_DAY: u32 = 144;
+pub const DEFAULT_GRACE_PERIOD_DAYS: u32 = 180;
+// the largest block-based relative timelock that fits in nSequence
+const MAX_RELATIVE_BLOCKS: u32 = 0xffff;
+
+/// How the borrower's recovery branch is timelocked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryTimelock {
+	/// CSV: counted from the confirmation of the collateral deposit
+	Relative,
+	/// CLTV: counted from the given block height (usually the loan start)
+	Absolute { start_height: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpendingBranch {
+	/// Any 2 of borrower, lender and service; available at any time
+	Multisig,
+	/// Borrower alone, once the loan term and grace period have elapsed
+	BorrowerRecovery,
+}
+
+/// Collateral policy with a unilateral borrower recovery path:
+///
+/// wsh(or_d(multi(2,borrower,lender,service),and_v(v:pk(borrower),<timelock>)))
+#[derive(Debug, Clone)]
+pub struct CollateralPolicy {
+	pub keys: MultisigAddress,
+	/// loan term in days, as stored in `loan_request.loan_term`
+	pub loan_term_days: u32,
+	pub grace_period_days: u32,
+	pub timelock: RecoveryTimelock,
+}
+
+impl CollateralPolicy {
+	pub fn new(
+		keys: MultisigAddress,
+		loan_term: i32,
+		timelock: RecoveryTimelock,
+	) -> Result<Self, String> {
+		let loan_term_days =
+			u32::try_from(loan_term).map_err(|_| format!("Invalid loan term: {}", loan_term))?;
+
+		let policy = Self {
+			keys,
+			loan_term_days,
+			grace_period_days: DEFAULT_GRACE_PERIOD_DAYS,
+			timelock,
+		};
+		policy.recovery_timelock()?;
+		Ok(policy)
+	}
+
+	pub fn with_grace_period(mut self, grace_period_days: u32) -> Result<Self, String> {
+		self.grace_period_days = grace_period_days;
+		self.recovery_timelock()?;
+		Ok(self)
+	}
+
+	/// Number of blocks before the borrower recovery branch becomes spendable
+	pub fn recovery_blocks(&self) -> Result<u32, String> {
+		self.loan_term_days
+			.checked_add(self.grace_period_days)
+			.and_then(|days| days.checked_mul(BLOCKS_PER_DAY))
+			.ok_or_else(|| "Loan term and grace period are too long".to_string())
+	}
+
+	/// The miniscript fragment guarding the recovery branch: older(n) or after(n)
+	fn recovery_timelock(&self) -> Result<String, String> {
+		let blocks = self.recovery_blocks()?;
+
+		match self.timelock {
+			RecoveryTimelock::Relative => {
+				if blocks > MAX_RELATIVE_BLOCKS {
+					return Err(format!(
+						"Recovery delay of {} blocks exceeds the CSV limit of {} blocks, use an absolute timelock",
+						blocks, MAX_RELATIVE_BLOCKS
+					));
+				}
+				Ok(format!("older({})", blocks))
+			}
+			RecoveryTimelock::Absolute { start_height } => {
+				let height = start_height
+					.checked_add(blocks)
+					.filter(|height| LockTime::from_height(*height).is_ok())
+					.ok_or_else(|| "Recovery height is not a valid block height".to_string())?;
+				Ok(format!("after({})", height))
+			}
+		}
+	}
+
+	pub fn descriptor(&self) -> Result<Descriptor<ms_bitcoin::PublicKey>, String> {
+		let borrower = to_ms_pubkey(&self.keys.borrower_pubkey)?;
+		let lender = to_ms_pubkey(&self.keys.lender_pubkey)?;
+		let service = to_ms_pubkey(&self.keys.service_pubkey)?;
+
+		let descriptor = format!(
+			"wsh(or_d(multi(2,{},{},{}),and_v(v:pk({}),{})))",
+			borrower,
+			lender,
+			service,
+			borrower,
+			self.recovery_timelock()?
+		);
+
+		Descriptor::from_str(&descriptor).map_err(|e| format!("Invalid collateral policy: {}", e))
+	}
+
+	pub fn witness_script(&self) -> Result<ScriptBuf, String> {
+		let script = self
+			.descriptor()?
+			.explicit_script()
+			.map_err(|e| format!("Error deriving witness script: {}", e))?;
+		Ok(from_ms_script(script))
+	}
+
+	pub fn create_p2wsh_address(&self) -> Result<Address, String> {
+		Ok(Address::p2wsh(&self.witness_script()?, set_network()))
+	}
+
+	/// nSequence the spending input must carry for the given branch
+	pub fn sequence(&self, branch: SpendingBranch) -> Result<Sequence, String> {
+		match (branch, self.timelock) {
+			(SpendingBranch::BorrowerRecovery, RecoveryTimelock::Relative) => {
+				Ok(Sequence::from_height(self.recovery_blocks()? as u16))
+			}
+			_ => Ok(Sequence::ENABLE_RBF_NO_LOCKTIME),
+		}
+	}
+
+	/// nLockTime the spending transaction must carry for the given branch
+	pub fn lock_time(&self, branch: SpendingBranch) -> Result<LockTime, String> {
+		match (branch, self.timelock) {
+			(SpendingBranch::BorrowerRecovery, RecoveryTimelock::Absolute { start_height }) => {
+				let height = start_height
+					.checked_add(self.recovery_blocks()?)
+					.ok_or_else(|| "Recovery height is not a valid block height".to_string())?;
+				LockTime::from_height(height)
+					.map_err(|e| format!("Invalid recovery height: {:?}", e))
+			}
+			_ => Ok(LockTime::ZERO),
+		}
+	}
+
+	/// Keys whose signatures are used to satisfy the given branch
+	pub fn branch_keys(&self, branch: SpendingBranch) -> Vec<PublicKey> {
+		match branch {
+			SpendingBranch::Multisig => vec![
+				self.keys.borrower_pubkey,
+				self.keys.lender_pubkey,
+				self.keys.service_pubkey,
+			],
+			SpendingBranch::BorrowerRecovery => vec![self.keys.borrower_pubkey],
+		}
+	}
+
+	/// Builds the input witness spending through the given branch.
+	/// The signatures must commit to the sequence and lock time returned for that branch.
+	pub fn witness(
+		&self,
+		branch: SpendingBranch,
+		signatures: &BTreeMap<PublicKey, ecdsa::Signature>,
+	) -> Result<Witness, String> {
+		let descriptor = self.descriptor()?;
+		let mut sigs = HashMap::new();
+
+		for pubkey in self.branch_keys(branch) {
+			if let Some(signature) = signatures.get(&pubkey) {
+				sigs.insert(to_ms_pubkey(&pubkey)?, to_ms_signature(signature)?);
+			}
+		}
+
+		let sequence = ms_bitcoin::Sequence(self.sequence(branch)?.to_consensus_u32());
+		let lock_time = ms_bitcoin::absolute::LockTime::from_consensus(
+			self.lock_time(branch)?.to_consensus_u32(),
+		);
+
+		let (stack, _) = descriptor
+			.get_satisfaction((sigs, sequence, lock_time))
+			.map_err(|e| format!("Unable to satisfy {:?} branch: {}", branch, e))?;
+
+		Ok(witness_from_stack(stack))
+	}
+
+	/// Worst-case weight of the witness over all branches
+	pub fn max_weight_to_satisfy(&self) -> Result<usize, String> {
+		self.descriptor()?
+			.max_weight_to_satisfy()
+			.map_err(|e| format!("Error computing satisfaction weight: {}", e))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+	use bitcoin::AddressType;
+
+	fn secret_keys() -> [SecretKey; 3] {
+		[
+			SecretKey::from_slice(&[1u8; 32]).unwrap(),
+			SecretKey::from_slice(&[2u8; 32]).unwrap(),
+			SecretKey::from_slice(&[3u8; 32]).unwrap(),
+		]
+	}
+
+	fn collateral_keys() -> MultisigAddress {
+		let secp = Secp256k1::new();
+		let [borrower, lender, service] =
+			secret_keys().map(|key| PublicKey::new(key.public_key(&secp)));
+		MultisigAddress::new(borrower, lender, service)
+	}
+
+	fn sign(key: &SecretKey) -> ecdsa::Signature {
+		let secp = Secp256k1::new();
+		let message = Message::from_digest([7u8; 32]);
+		ecdsa::Signature::sighash_all(secp.sign_ecdsa(&message, key))
+	}
+
+	#[test]
+	fn test_create_p2wsh_address() {
+		let policy =
+			CollateralPolicy::new(collateral_keys(), 90, RecoveryTimelock::Relative).unwrap();
+		let address = policy.create_p2wsh_address().unwrap();
+
+		assert_eq!(address.address_type(), Some(AddressType::P2wsh));
+		assert_ne!(address, collateral_keys().create_p2wsh_address());
+	}
+
+	#[test]
+	fn test_relative_timelock_limit() {
+		let long_term = CollateralPolicy::new(collateral_keys(), 365, RecoveryTimelock::Relative);
+		assert!(long_term.is_err());
+
+		let absolute = CollateralPolicy::new(
+			collateral_keys(),
+			365,
+			RecoveryTimelock::Absolute {
+				start_height: 800_000,
+			},
+		)
+		.unwrap();
+		assert_eq!(
+			absolute
+				.lock_time(SpendingBranch::BorrowerRecovery)
+				.unwrap(),
+			LockTime::from_height(800_000 + (365 + 180) * BLOCKS_PER_DAY).unwrap()
+		);
+	}
+
+	#[test]
+	fn test_recovery_height_overflow() {
+		let mut policy = CollateralPolicy::new(
+			collateral_keys(),
+			90,
+			RecoveryTimelock::Absolute {
+				start_height: 800_000,
+			},
+		)
+		.unwrap();
+		policy.timelock = RecoveryTimelock::Absolute {
+			start_height: u32::MAX,
+		};
+
+		assert!(policy.lock_time(SpendingBranch::BorrowerRecovery).is_err());
+	}
+
+	#[test]
+	fn test_multisig_branch_witness() {
+		let policy =
+			CollateralPolicy::new(collateral_keys(), 90, RecoveryTimelock::Relative).unwrap();
+		let [borrower, lender, _] = secret_keys();
+		let keys = collateral_keys();
+
+		let mut signatures = BTreeMap::new();
+		signatures.insert(keys.borrower_pubkey, sign(&borrower));
+		signatures.insert(keys.lender_pubkey, sign(&lender));
+
+		let witness = policy
+			.witness(SpendingBranch::Multisig, &signatures)
+			.unwrap();
+
+		// OP_0 dummy, two signatures and the witness script
+		assert_eq!(witness.len(), 4);
+		assert_eq!(
+			witness.last().unwrap(),
+			policy.witness_script().unwrap().as_bytes()
+		);
+	}
+
+	#[test]
+	fn test_borrower_recovery_witness() {
+		let policy =
+			CollateralPolicy::new(collateral_keys(), 90, RecoveryTimelock::Relative).unwrap();
+		let [borrower, _, _] = secret_keys();
+		let keys = collateral_keys();
+
+		let mut signatures = BTreeMap::new();
+		signatures.insert(keys.borrower_pubkey, sign(&borrower));
+
+		assert!(policy
+			.witness(SpendingBranch::Multisig, &signatures)
+			.is_err());
+		assert!(policy
+			.witness(SpendingBranch::BorrowerRecovery, &signatures)
+			.is_ok());
+		assert_eq!(
+			policy.sequence(SpendingBranch::BorrowerRecovery).unwrap(),
+			Sequence::from_height(((90 + 180) * BLOCKS_PER_DAY) as u16)
+		);
+	}
+}
@@ -0,0 +1,93 @@
+use bitcoin::{Amount, OutPoint};
+
+/// An unspent output locked at a loan's collateral multisig address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollateralUtxo {
+	pub outpoint: OutPoint,
+	pub amount: Amount,
+	/// height of the block that confirmed the output, None while it is in the mempool
+	pub height: Option<u32>,
+}
+
+impl CollateralUtxo {
+	pub fn confirmations(&self, tip_height: u32) -> u32 {
+		match self.height {
+			Some(height) if height <= tip_height => tip_height - height + 1,
+			_ => 0,
+		}
+	}
+}
+
+/// Collateral locked across every deposit and top-up of a loan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollateralBalance {
+	/// outputs with at least the required number of confirmations
+	pub confirmed: Amount,
+	/// outputs in the mempool or not yet deep enough
+	pub unconfirmed: Amount,
+}
+
+impl CollateralBalance {
+	pub fn from_utxos(utxos: &[CollateralUtxo], tip_height: u32, depth: u32) -> Self {
+		let (confirmed, unconfirmed): (Vec<_>, Vec<_>) = utxos
+			.iter()
+			.partition(|utxo| utxo.confirmations(tip_height) >= depth);
+
+		Self {
+			confirmed: confirmed.iter().map(|utxo| utxo.amount).sum(),
+			unconfirmed: unconfirmed.iter().map(|utxo| utxo.amount).sum(),
+		}
+	}
+
+	pub fn total(&self) -> Amount {
+		self.confirmed + self.unconfirmed
+	}
+
+	/// Whether the confirmed outputs cover the collateral the loan requires
+	pub fn is_funded(&self, required: Amount) -> bool {
+		required > Amount::ZERO && self.confirmed >= required
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::hashes::Hash;
+	use bitcoin::Txid;
+
+	fn utxo(vout: u32, sats: u64, height: Option<u32>) -> CollateralUtxo {
+		CollateralUtxo {
+			outpoint: OutPoint::new(Txid::all_zeros(), vout),
+			amount: Amount::from_sat(sats),
+			height,
+		}
+	}
+
+	#[test]
+	fn test_confirmations() {
+		assert_eq!(utxo(0, 1_000, Some(100)).confirmations(100), 1);
+		assert_eq!(utxo(0, 1_000, Some(100)).confirmations(105), 6);
+		assert_eq!(utxo(0, 1_000, Some(100)).confirmations(99), 0);
+		assert_eq!(utxo(0, 1_000, None).confirmations(105), 0);
+	}
+
+	#[test]
+	fn test_balance_aggregates_top_ups() {
+		let utxos = [
+			utxo(0, 600_000, Some(100)),
+			utxo(1, 300_000, Some(104)),
+			utxo(2, 100_000, None),
+		];
+		let required = Amount::from_sat(900_000);
+
+		let balance = CollateralBalance::from_utxos(&utxos, 104, 3);
+		assert_eq!(balance.confirmed, Amount::from_sat(600_000));
+		assert_eq!(balance.unconfirmed, Amount::from_sat(400_000));
+		assert_eq!(balance.total(), Amount::from_sat(1_000_000));
+		assert!(!balance.is_funded(required));
+
+		let balance = CollateralBalance::from_utxos(&utxos, 106, 3);
+		assert!(balance.is_funded(required));
+		assert!(!balance.is_funded(Amount::ZERO));
+	}
+}
@@ -0,0 +1,347 @@
+use crate::config::FeeSettings;
+use crate::constants::set_network;
+use crate::domain::MultisigAddress;
+use crate::utils::bitcoind_rpc::get_mempool_entry;
+use crate::utils::transaction_utils::locktime_at_tip;
+use crate::utils::validate_address::validate_address;
+use crate::utils::weight_estimator::{estimate_weight, InputWeight};
+use bitcoin::transaction::Version;
+use bitcoin::{
+	Amount, FeeRate, OutPoint, Psbt, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
+};
+use bitcoincore_rpc::json::GetMempoolEntryResult;
+use bitcoincore_rpc::Client;
+
+/// An unconfirmed transaction together with its unconfirmed ancestors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MempoolPackage {
+	pub vsize: u64,
+	pub fees: Amount,
+}
+
+impl MempoolPackage {
+	pub fn fee_rate(&self) -> FeeRate {
+		// 250 sat/kwu is 1 sat/vB
+		FeeRate::from_sat_per_kwu(self.fees.to_sat() * 250 / self.vsize.max(1))
+	}
+}
+
+impl From<&GetMempoolEntryResult> for MempoolPackage {
+	fn from(entry: &GetMempoolEntryResult) -> Self {
+		Self {
+			vsize: entry.ancestor_size,
+			fees: entry.fees.ancestor,
+		}
+	}
+}
+
+/// Fee a child of `child_vsize` must pay to bring the package to `fee_rate`.
+/// The child always pays at least its own size at that rate.
+pub fn child_fee(
+	package: &MempoolPackage,
+	child_vsize: u64,
+	fee_rate: FeeRate,
+) -> Result<Amount, String> {
+	let overflow = || "Fee calculation overflows".to_string();
+	let package_fee = fee_rate
+		.fee_vb(package.vsize + child_vsize)
+		.ok_or_else(overflow)?;
+	let own_fee = fee_rate.fee_vb(child_vsize).ok_or_else(overflow)?;
+
+	Ok(package_fee
+		.checked_sub(package.fees)
+		.unwrap_or(Amount::ZERO)
+		.max(own_fee))
+}
+
+/// Child-pays-for-parent spend of one output of a stuck transaction: either the
+/// collateral output, re-locked at the same address, or the borrower's change
+#[derive(Debug, Clone)]
+pub struct CpfpTxn {
+	/// the stuck transaction
+	pub parent: Transaction,
+	pub vout: u32,
+	pub destination_address: String,
+	pub input_weight: InputWeight,
+	/// set when the spent output is the collateral P2WSH
+	pub witness_script: Option<ScriptBuf>,
+	/// height the anti-fee-sniping locktime is set from, bitcoind's tip when None
+	pub tip_height: Option<u32>,
+}
+
+impl CpfpTxn {
+	/// Spends the collateral output back into the same 2-of-3; two parties must sign
+	pub fn for_collateral(
+		parent: Transaction,
+		collateral: &MultisigAddress,
+	) -> Result<Self, String> {
+		let address = collateral.create_p2wsh_address();
+		let vout = output_index(&parent, &address.script_pubkey())?;
+		let redeem_script = collateral.redeem_script();
+
+		Ok(Self {
+			parent,
+			vout,
+			destination_address: address.to_string(),
+			input_weight: InputWeight::p2wsh_multisig(2, &redeem_script),
+			witness_script: Some(redeem_script),
+			tip_height: None,
+		})
+	}
+
+	/// Spends the borrower's change to an address of their choosing
+	pub fn for_change(
+		parent: Transaction,
+		change_address: &str,
+		destination_address: String,
+	) -> Result<Self, String> {
+		let script_pubkey = validate_address(change_address, set_network())?.script_pubkey();
+		let vout = output_index(&parent, &script_pubkey)?;
+
+		Ok(Self {
+			parent,
+			vout,
+			destination_address,
+			input_weight: InputWeight::from_script_pubkey(&script_pubkey)?,
+			witness_script: None,
+			tip_height: None,
+		})
+	}
+
+	/// Sets the locktime from this height instead of bitcoind's tip
+	pub fn with_tip_height(mut self, tip_height: u32) -> Self {
+		self.tip_height = Some(tip_height);
+		self
+	}
+
+	fn spent_output(&self) -> TxOut {
+		self.parent.output[self.vout as usize].clone()
+	}
+
+	/// Builds the child for the parent's mempool package, returning it with its fee
+	pub fn construct_trxn(
+		&self,
+		client: Option<&Client>,
+		package: &MempoolPackage,
+		fee_rate: FeeRate,
+		fee_settings: &FeeSettings,
+	) -> Result<(Transaction, Amount), String> {
+		let spent = self.spent_output();
+		let destination =
+			validate_address(&self.destination_address, set_network())?.script_pubkey();
+
+		let mut child = Transaction {
+			version: Version::TWO,
+			lock_time: locktime_at_tip(self.tip_height, client)?,
+			input: vec![TxIn {
+				previous_output: OutPoint::new(self.parent.txid(), self.vout),
+				script_sig: ScriptBuf::new(),
+				sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+				witness: Witness::new(),
+			}],
+			output: vec![TxOut {
+				value: Amount::ZERO,
+				script_pubkey: destination.clone(),
+			}],
+		};
+
+		let vsize = estimate_weight(&child, &[self.input_weight])?.to_vbytes_ceil();
+		let fee = child_fee(package, vsize, fee_rate)?;
+		// the child pays for the whole package, so the limit is taken against everything
+		// the parent moves, the output the child spends included
+		let package_value = self
+			.parent
+			.output
+			.iter()
+			.try_fold(Amount::ZERO, |total, output| {
+				total.checked_add(output.value)
+			})
+			.ok_or_else(|| "Output total overflows".to_string())?;
+		fee_settings.check_fee(fee, package_value)?;
+
+		let value = spent
+			.value
+			.checked_sub(fee)
+			.filter(|value| *value >= destination.dust_value())
+			.ok_or_else(|| {
+				format!(
+					"Output of {} cannot pay a child fee of {}",
+					spent.value, fee
+				)
+			})?;
+		child.output[0].value = value;
+
+		Ok((child, fee))
+	}
+
+	/// Looks up the parent's package in bitcoind's mempool and returns the child as a
+	/// PSBT for signing
+	pub fn create_psbt(
+		&self,
+		client: Option<&Client>,
+		fee_rate: FeeRate,
+		fee_settings: &FeeSettings,
+	) -> Result<Psbt, String> {
+		let txid = self.parent.txid();
+		let entry = get_mempool_entry(txid, client)
+			.map_err(|e| format!("Transaction {} is not in the mempool: {:?}", txid, e))?;
+		let package = MempoolPackage::from(&entry);
+		if package.fee_rate() >= fee_rate {
+			return Err(format!(
+				"The package already pays {} sat/vB",
+				package.fee_rate().to_sat_per_vb_floor()
+			));
+		}
+
+		let (child, _) = self.construct_trxn(client, &package, fee_rate, fee_settings)?;
+		let mut psbt =
+			Psbt::from_unsigned_tx(child).map_err(|e| format!("Error creating PSBT: {}", e))?;
+
+		let spent = self.spent_output();
+		if spent.script_pubkey.is_witness_program() {
+			psbt.inputs[0].witness_utxo = Some(spent);
+		} else {
+			psbt.inputs[0].non_witness_utxo = Some(self.parent.clone());
+		}
+		psbt.inputs[0].witness_script = self.witness_script.clone();
+
+		Ok(psbt)
+	}
+}
+
+fn output_index(parent: &Transaction, script_pubkey: &ScriptBuf) -> Result<u32, String> {
+	parent
+		.output
+		.iter()
+		.position(|output| &output.script_pubkey == script_pubkey)
+		.map(|index| index as u32)
+		.ok_or_else(|| {
+			format!(
+				"Transaction {} has no output to {}",
+				parent.txid(),
+				script_pubkey
+			)
+		})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::absolute::LockTime;
+	use bitcoin::hashes::Hash;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use bitcoin::{PublicKey, Txid};
+
+	fn collateral() -> MultisigAddress {
+		let secp = Secp256k1::new();
+		let [borrower, lender, service] = [1u8, 2, 3].map(|byte| {
+			PublicKey::new(
+				SecretKey::from_slice(&[byte; 32])
+					.unwrap()
+					.public_key(&secp),
+			)
+		});
+		MultisigAddress::new(borrower, lender, service)
+	}
+
+	fn parent(collateral: &MultisigAddress) -> Transaction {
+		Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: vec![TxIn {
+				previous_output: OutPoint::new(Txid::all_zeros(), 0),
+				script_sig: ScriptBuf::new(),
+				sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+				witness: Witness::new(),
+			}],
+			output: vec![TxOut {
+				value: Amount::from_sat(1_000_000),
+				script_pubkey: collateral.create_p2wsh_address().script_pubkey(),
+			}],
+		}
+	}
+
+	#[test]
+	fn test_child_fee() {
+		let package = MempoolPackage {
+			vsize: 200,
+			fees: Amount::from_sat(200),
+		};
+		let fee_rate = FeeRate::from_sat_per_vb(10).unwrap();
+
+		assert_eq!(
+			child_fee(&package, 100, fee_rate).unwrap(),
+			Amount::from_sat(2_800)
+		);
+
+		let paid = MempoolPackage {
+			vsize: 200,
+			fees: Amount::from_sat(5_000),
+		};
+		assert_eq!(
+			child_fee(&paid, 100, fee_rate).unwrap(),
+			Amount::from_sat(1_000)
+		);
+	}
+
+	#[test]
+	fn test_collateral_child_relocks_collateral() {
+		let collateral = collateral();
+		let cpfp = CpfpTxn::for_collateral(parent(&collateral), &collateral)
+			.unwrap()
+			.with_tip_height(800_000);
+		let package = MempoolPackage {
+			vsize: 110,
+			fees: Amount::from_sat(110),
+		};
+
+		let (child, fee) = cpfp
+			.construct_trxn(
+				None,
+				&package,
+				FeeRate::from_sat_per_vb(20).unwrap(),
+				&FeeSettings::default(),
+			)
+			.unwrap();
+
+		assert_eq!(
+			child.output[0].script_pubkey,
+			collateral.create_p2wsh_address().script_pubkey()
+		);
+		assert_eq!(child.output[0].value + fee, Amount::from_sat(1_000_000));
+		assert_eq!(child.input[0].previous_output.vout, 0);
+		assert!((799_901..=800_000).contains(&child.lock_time.to_consensus_u32()));
+		assert!(fee > FeeRate::from_sat_per_vb(20).unwrap().fee_vb(110).unwrap());
+	}
+
+	#[test]
+	fn test_change_child_fee_limit_covers_package() {
+		let change_address = "bcrt1qeygjhsgt5sumtlqnyfu58harh3737z96m0zmqv";
+		let mut parent = parent(&collateral());
+		parent.output.push(TxOut {
+			value: Amount::from_sat(30_000),
+			script_pubkey: validate_address(change_address, set_network())
+				.unwrap()
+				.script_pubkey(),
+		});
+		let cpfp = CpfpTxn::for_change(parent, change_address, change_address.to_string())
+			.unwrap()
+			.with_tip_height(800_000);
+		let package = MempoolPackage {
+			vsize: 200,
+			fees: Amount::from_sat(200),
+		};
+
+		// more than 5% of the change, well under 5% of the package
+		let (_, fee) = cpfp
+			.construct_trxn(
+				None,
+				&package,
+				FeeRate::from_sat_per_vb(20).unwrap(),
+				&FeeSettings::default(),
+			)
+			.unwrap();
+
+		assert!(fee > Amount::from_sat(1_500));
+	}
+}
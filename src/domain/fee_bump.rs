@@ -0,0 +1,328 @@
+use crate::config::TxnKind;
+use crate::repository::transaction_replacement::{latest_replacement, record_replacement};
+use crate::utils::transaction_utils::{ChangeOutcome, ConstructedTxn};
+use crate::utils::weight_estimator::{estimate_weight, InputWeight};
+use bitcoin::{Amount, FeeRate, Psbt, Script, ScriptBuf, Transaction, TxOut, Txid};
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+
+// bitcoin core's default -incrementalrelayfee, the least a replacement must add per vbyte
+pub const INCREMENTAL_RELAY_FEE: FeeRate = FeeRate::from_sat_per_vb_unchecked(1);
+
+/// Rebuilds `original` at `fee_rate` under the BIP-125 replacement rules: the same inputs
+/// and outputs, with a higher absolute fee paid out of the change output. Change that
+/// would fall below dust is dropped into the fee. `output_map` is the original builder's,
+/// whose last entry is the change.
+pub fn bump_fee(
+	original: &Transaction,
+	output_map: &[u32],
+	spent_outputs: &[TxOut],
+	input_weights: &[InputWeight],
+	change_script: &Script,
+	fee_rate: FeeRate,
+) -> Result<ConstructedTxn, String> {
+	if !original.input.iter().any(|input| input.sequence.is_rbf()) {
+		return Err(format!(
+			"Transaction {} does not signal replaceability",
+			original.txid()
+		));
+	}
+	if spent_outputs.len() != original.input.len() {
+		return Err(format!(
+			"Expected {} spent outputs, found {}",
+			original.input.len(),
+			spent_outputs.len()
+		));
+	}
+	let input_total = total(spent_outputs)?;
+	let original_fee = input_total
+		.checked_sub(total(&original.output)?)
+		.ok_or_else(|| "The transaction spends more than its inputs".to_string())?;
+
+	let change_index = output_map
+		.last()
+		.map(|vout| *vout as usize)
+		.filter(|vout| {
+			original
+				.output
+				.get(*vout)
+				.is_some_and(|output| output.script_pubkey.as_script() == change_script)
+		})
+		.ok_or_else(|| "The transaction has no change output to pay for a fee bump".to_string())?;
+	let original_change = original.output[change_index].value;
+
+	let mut replacement = original.clone();
+	for input in replacement.input.iter_mut() {
+		input.script_sig = ScriptBuf::new();
+		input.witness.clear();
+	}
+
+	let vsize = estimate_weight(&replacement, input_weights)?.to_vbytes_ceil();
+	let fee = required_fee(original_fee, vsize, fee_rate)?;
+	let increase = fee - original_fee;
+	if let Some(change) = original_change
+		.checked_sub(increase)
+		.filter(|change| *change >= change_script.dust_value())
+	{
+		replacement.output[change_index].value = change;
+		return Ok(ConstructedTxn {
+			transaction: replacement,
+			fee,
+			change: ChangeOutcome::Change(change),
+			output_map: output_map.to_vec(),
+		});
+	}
+
+	replacement.output.remove(change_index);
+	if replacement.output.is_empty() {
+		return Err("The replacement would have no outputs".to_string());
+	}
+	let vsize = estimate_weight(&replacement, input_weights)?.to_vbytes_ceil();
+	let fee = original_fee + original_change;
+	if fee < required_fee(original_fee, vsize, fee_rate)? {
+		return Err(format!(
+			"Change of {} cannot pay for a fee rate of {} sat/vB",
+			original_change,
+			fee_rate.to_sat_per_vb_ceil()
+		));
+	}
+
+	// the change is gone: the outputs after it move down one place
+	let output_map = output_map[..output_map.len() - 1]
+		.iter()
+		.map(|vout| match *vout as usize > change_index {
+			true => vout - 1,
+			false => *vout,
+		})
+		.collect();
+
+	Ok(ConstructedTxn {
+		output_map,
+		transaction: replacement,
+		fee,
+		change: ChangeOutcome::DroppedDust(original_change),
+	})
+}
+
+/// Records the signed `replacement` of `txid` against the latest transaction it replaces.
+/// Call it once the replacement is signed: signing can change the txid of non-segwit inputs.
+pub async fn record_bump(
+	pool: &PgPool,
+	loan_request_id: Uuid,
+	kind: TxnKind,
+	txid: Txid,
+	replacement: Txid,
+	fee: Amount,
+) -> Result<(), String> {
+	let replaced = latest_replacement(pool, txid)
+		.await
+		.map_err(|e| format!("Error loading replacements of {}: {:?}", txid, e))?;
+	record_replacement(pool, loan_request_id, kind, replaced, replacement, fee)
+		.await
+		.map_err(|e| format!("Error recording replacement {}: {:?}", replacement, e))
+}
+
+/// Records the finalized `signed` replacement of `txid` and returns the replacement's txid
+pub async fn record_signed_bump(
+	pool: &PgPool,
+	loan_request_id: Uuid,
+	kind: TxnKind,
+	txid: Txid,
+	signed: &Psbt,
+) -> Result<Txid, String> {
+	let fee = signed
+		.fee()
+		.map_err(|e| format!("Error computing the replacement fee: {}", e))?;
+	let replacement = signed
+		.clone()
+		.extract_tx()
+		.map_err(|e| format!("Error extracting the replacement: {}", e))?
+		.txid();
+
+	record_bump(pool, loan_request_id, kind, txid, replacement, fee).await?;
+	Ok(replacement)
+}
+
+/// The larger of the fee at `fee_rate` and the original fee plus the relay increment
+fn required_fee(original_fee: Amount, vsize: u64, fee_rate: FeeRate) -> Result<Amount, String> {
+	let overflow = || "Fee calculation overflows".to_string();
+	let at_rate = fee_rate.fee_vb(vsize).ok_or_else(overflow)?;
+	let increment = INCREMENTAL_RELAY_FEE.fee_vb(vsize).ok_or_else(overflow)?;
+	let minimum = original_fee.checked_add(increment).ok_or_else(overflow)?;
+
+	Ok(at_rate.max(minimum))
+}
+
+fn total(outputs: &[TxOut]) -> Result<Amount, String> {
+	outputs
+		.iter()
+		.try_fold(Amount::ZERO, |total, output| {
+			total.checked_add(output.value)
+		})
+		.ok_or_else(|| "Output total overflows".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::absolute::LockTime;
+	use bitcoin::hashes::Hash;
+	use bitcoin::transaction::Version;
+	use bitcoin::{OutPoint, Sequence, TxIn, Txid, Witness};
+
+	fn script(byte: u8) -> ScriptBuf {
+		let mut bytes = vec![0x00, 0x14];
+		bytes.extend([byte; 20]);
+		ScriptBuf::from_bytes(bytes)
+	}
+
+	/// 1-input, 2-output P2WPKH transaction paying 141 vbytes at 2 sat/vB
+	fn original(change: u64) -> (Transaction, Vec<TxOut>) {
+		let transaction = Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: vec![TxIn {
+				previous_output: OutPoint::new(Txid::all_zeros(), 0),
+				script_sig: ScriptBuf::new(),
+				sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+				witness: Witness::new(),
+			}],
+			output: vec![
+				TxOut {
+					value: Amount::from_sat(100_000),
+					script_pubkey: script(1),
+				},
+				TxOut {
+					value: Amount::from_sat(change),
+					script_pubkey: script(2),
+				},
+			],
+		};
+		let spent = vec![TxOut {
+			value: Amount::from_sat(100_000 + change + 282),
+			script_pubkey: script(3),
+		}];
+		(transaction, spent)
+	}
+
+	#[test]
+	fn test_bump_reduces_change() {
+		let (transaction, spent) = original(50_000);
+		let bumped = bump_fee(
+			&transaction,
+			&[0, 1],
+			&spent,
+			&[InputWeight::p2wpkh()],
+			&script(2),
+			FeeRate::from_sat_per_vb(10).unwrap(),
+		)
+		.unwrap();
+
+		assert_eq!(bumped.fee, Amount::from_sat(1_410));
+		assert_eq!(
+			bumped.change,
+			ChangeOutcome::Change(Amount::from_sat(50_000 - 1_128))
+		);
+		assert_eq!(bumped.transaction.input, transaction.input);
+		assert_eq!(bumped.transaction.output[0], transaction.output[0]);
+	}
+
+	#[test]
+	fn test_bump_pays_at_least_the_relay_increment() {
+		let (transaction, spent) = original(50_000);
+		let bumped = bump_fee(
+			&transaction,
+			&[0, 1],
+			&spent,
+			&[InputWeight::p2wpkh()],
+			&script(2),
+			FeeRate::from_sat_per_vb(1).unwrap(),
+		)
+		.unwrap();
+
+		assert_eq!(bumped.fee, Amount::from_sat(282 + 141));
+	}
+
+	#[test]
+	fn test_bump_drops_dust_change() {
+		let (transaction, spent) = original(1_000);
+		let bumped = bump_fee(
+			&transaction,
+			&[0, 1],
+			&spent,
+			&[InputWeight::p2wpkh()],
+			&script(2),
+			FeeRate::from_sat_per_vb(8).unwrap(),
+		)
+		.unwrap();
+
+		assert_eq!(bumped.transaction.output.len(), 1);
+		assert_eq!(bumped.fee, Amount::from_sat(1_282));
+
+		assert!(bump_fee(
+			&transaction,
+			&[0, 1],
+			&spent,
+			&[InputWeight::p2wpkh()],
+			&script(2),
+			FeeRate::from_sat_per_vb(50).unwrap(),
+		)
+		.is_err());
+		assert!(bump_fee(
+			&transaction,
+			&[0, 1],
+			&spent,
+			&[InputWeight::p2wpkh()],
+			&script(9),
+			FeeRate::from_sat_per_vb(8).unwrap(),
+		)
+		.is_err());
+	}
+
+	#[test]
+	fn test_bump_requires_rbf_signal() {
+		let (mut transaction, spent) = original(50_000);
+		transaction.input[0].sequence = Sequence::MAX;
+
+		assert!(bump_fee(
+			&transaction,
+			&[0, 1],
+			&spent,
+			&[InputWeight::p2wpkh()],
+			&script(2),
+			FeeRate::from_sat_per_vb(10).unwrap(),
+		)
+		.is_err());
+	}
+
+	#[test]
+	fn test_bump_keeps_builder_output_map() {
+		// builder order: payment at vout 0, payment at vout 2, change at vout 1
+		let (mut transaction, mut spent) = original(1_000);
+		transaction.output.push(TxOut {
+			value: Amount::from_sat(50_000),
+			script_pubkey: script(4),
+		});
+		spent[0].value += Amount::from_sat(50_000);
+		let bump = |fee_rate| {
+			bump_fee(
+				&transaction,
+				&[0, 2, 1],
+				&spent,
+				&[InputWeight::p2wpkh()],
+				&script(2),
+				FeeRate::from_sat_per_vb(fee_rate).unwrap(),
+			)
+			.unwrap()
+		};
+
+		assert_eq!(bump(2).output_map, vec![0, 2, 1]);
+
+		let dropped = bump(8);
+		assert_eq!(dropped.output_map, vec![0, 1]);
+		assert_eq!(
+			dropped.transaction.output[dropped.output_map[1] as usize].script_pubkey,
+			script(4)
+		);
+	}
+}
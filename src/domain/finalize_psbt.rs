@@ -0,0 +1,276 @@
+use crate::config::TxnKind;
+use crate::domain::fee_bump::record_signed_bump;
+use crate::domain::MultisigAddress;
+use crate::utils::bitcoind_rpc::{broadcast_transaction, test_mempool_accept};
+use bitcoin::ecdsa::Signature;
+use bitcoin::hashes::Hash;
+use bitcoin::psbt::Psbt;
+use bitcoin::secp256k1::{Message, Secp256k1, VerifyOnly};
+use bitcoin::sighash::SighashCache;
+use bitcoin::{Transaction, Txid, Witness};
+use bitcoincore_rpc::Client;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+
+// signatures needed to spend the 2-of-3 collateral
+const THRESHOLD: usize = 2;
+
+/// Merges the parties' partially signed copies of the same unsigned transaction
+pub fn combine_psbts(psbts: Vec<Psbt>) -> Result<Psbt, String> {
+	let mut psbts = psbts.into_iter();
+	let mut combined = psbts
+		.next()
+		.ok_or_else(|| "There are no PSBTs to combine".to_string())?;
+
+	for psbt in psbts {
+		combined
+			.combine(psbt)
+			.map_err(|e| format!("Error combining PSBTs: {}", e))?;
+	}
+	Ok(combined)
+}
+
+/// Valid signatures on input `index`, in the order their keys appear in the witness script
+fn input_signatures(
+	psbt: &Psbt,
+	index: usize,
+	multisig: &MultisigAddress,
+	secp: &Secp256k1<VerifyOnly>,
+) -> Result<Vec<Signature>, String> {
+	let input = &psbt.inputs[index];
+	let witness_script = multisig.redeem_script();
+	let amount = input
+		.witness_utxo
+		.as_ref()
+		.ok_or_else(|| format!("Input {} has no witness utxo", index))?
+		.value;
+	let mut sighash_cache = SighashCache::new(&psbt.unsigned_tx);
+
+	let mut signatures = Vec::new();
+	for pubkey in [
+		multisig.borrower_pubkey,
+		multisig.lender_pubkey,
+		multisig.service_pubkey,
+	] {
+		let Some(signature) = input.partial_sigs.get(&pubkey) else {
+			continue;
+		};
+		let sighash = sighash_cache
+			.p2wsh_signature_hash(index, &witness_script, amount, signature.hash_ty)
+			.map_err(|e| format!("Error computing the sighash of input {}: {}", index, e))?;
+		secp.verify_ecdsa(
+			&Message::from_digest(sighash.to_byte_array()),
+			&signature.sig,
+			&pubkey.inner,
+		)
+		.map_err(|_| format!("Invalid signature by {} on input {}", pubkey, index))?;
+		signatures.push(*signature);
+	}
+	Ok(signatures)
+}
+
+/// Builds the `OP_0 <sig> <sig> <witnessScript>` witness of every input once two parties
+/// have signed it, and clears the signing data as a BIP-174 finalizer does
+pub fn finalize_psbt(mut psbt: Psbt) -> Result<Psbt, String> {
+	let secp = Secp256k1::verification_only();
+
+	for index in 0..psbt.inputs.len() {
+		let witness_script = psbt.inputs[index]
+			.witness_script
+			.clone()
+			.ok_or_else(|| format!("Input {} has no witness script", index))?;
+		let multisig = MultisigAddress::from_redeem_script(&witness_script)?;
+
+		let signatures = input_signatures(&psbt, index, &multisig, &secp)?;
+		if signatures.len() < THRESHOLD {
+			return Err(format!(
+				"Input {} has {} of the {} signatures it needs",
+				index,
+				signatures.len(),
+				THRESHOLD
+			));
+		}
+
+		// CHECKMULTISIG pops one element more than it uses
+		let mut witness = Witness::new();
+		witness.push(Vec::new());
+		for signature in signatures.iter().take(THRESHOLD) {
+			witness.push(signature.to_vec());
+		}
+		witness.push(witness_script.as_bytes());
+
+		let input = &mut psbt.inputs[index];
+		input.final_script_witness = Some(witness);
+		input.partial_sigs.clear();
+		input.sighash_type = None;
+		input.witness_script = None;
+		input.bip32_derivation.clear();
+	}
+	Ok(psbt)
+}
+
+pub fn extract_transaction(psbt: Psbt) -> Result<Transaction, String> {
+	psbt.extract_tx()
+		.map_err(|e| format!("Error extracting the transaction: {}", e))
+}
+
+/// Combines and finalizes the parties' PSBTs, then broadcasts the transaction once
+/// bitcoind's testmempoolaccept accepts it
+pub fn broadcast_psbts(psbts: Vec<Psbt>, client: Option<&Client>) -> Result<Txid, String> {
+	broadcast_finalized(finalize_psbt(combine_psbts(psbts)?)?, client)
+}
+
+/// Broadcasts a finalized PSBT once bitcoind's testmempoolaccept accepts it
+pub fn broadcast_finalized(psbt: Psbt, client: Option<&Client>) -> Result<Txid, String> {
+	let transaction = extract_transaction(psbt)?;
+
+	let result = test_mempool_accept(&transaction, client)
+		.map_err(|e| format!("Error testing mempool acceptance: {:?}", e))?;
+	if !result.allowed {
+		return Err(format!(
+			"Transaction {} was rejected: {}",
+			result.txid,
+			result.reject_reason.unwrap_or_default()
+		));
+	}
+
+	broadcast_transaction(&transaction, client)
+		.map_err(|e| format!("Error broadcasting the transaction: {:?}", e))
+}
+
+/// Like `broadcast_psbts` for a fee bump of `txid`, recording the replacement once it
+/// is signed and its txid is final
+pub async fn broadcast_replacement(
+	pool: &PgPool,
+	loan_request_id: Uuid,
+	kind: TxnKind,
+	txid: Txid,
+	psbts: Vec<Psbt>,
+	client: Option<&Client>,
+) -> Result<Txid, String> {
+	let psbt = finalize_psbt(combine_psbts(psbts)?)?;
+	broadcast_signed_replacement(pool, loan_request_id, kind, txid, psbt, client).await
+}
+
+/// Broadcasts `psbt`, a finalized fee bump of `txid`, and records the replacement so the
+/// next bump builds on it. A funding replacement arrives here finalized by the
+/// borrower's wallet.
+pub async fn broadcast_signed_replacement(
+	pool: &PgPool,
+	loan_request_id: Uuid,
+	kind: TxnKind,
+	txid: Txid,
+	psbt: Psbt,
+	client: Option<&Client>,
+) -> Result<Txid, String> {
+	broadcast_finalized(psbt.clone(), client)?;
+	record_signed_bump(pool, loan_request_id, kind, txid, &psbt).await
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::FeeSettings;
+	use crate::domain::collateral_descriptor::KeyOrigin;
+	use crate::domain::collateral_utxo::CollateralUtxo;
+	use crate::domain::key_derivation::{LoanKeyset, PartyXpub};
+	use crate::domain::redeeming_transaction::RedeemingTxnPSBT;
+	use crate::domain::sign_psbt::sign_psbt;
+	use crate::utils::get_feerate::FeeTarget;
+	use bitcoin::absolute::LockTime;
+	use bitcoin::bip32::{DerivationPath, Xpriv, Xpub};
+	use bitcoin::transaction::Version;
+	use bitcoin::{Amount, Network, OutPoint, Transaction, TxOut};
+	use std::str::FromStr;
+
+	fn masters() -> [Xpriv; 3] {
+		[1u8, 2, 3].map(|seed| Xpriv::new_master(Network::Regtest, &[seed; 32]).unwrap())
+	}
+
+	fn redemption_psbt(masters: &[Xpriv; 3]) -> Psbt {
+		let secp = Secp256k1::new();
+		let path = DerivationPath::from_str("m/48'/1'/0'/2'").unwrap();
+		let [borrower, lender, service] = masters.map(|master| {
+			PartyXpub::new(
+				Xpub::from_priv(&secp, &master.derive_priv(&secp, &path).unwrap()),
+				KeyOrigin {
+					fingerprint: master.fingerprint(&secp),
+					path: path.clone(),
+				},
+			)
+		});
+		let keyset = LoanKeyset::new(borrower, lender, service);
+		let collateral = keyset.derive(0).unwrap().multisig;
+		let deposit = Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: Vec::new(),
+			output: vec![TxOut {
+				value: Amount::from_sat(1_000_000),
+				script_pubkey: collateral.create_p2wsh_address().script_pubkey(),
+			}],
+		};
+
+		RedeemingTxnPSBT::new(
+			"bcrt1qeygjhsgt5sumtlqnyfu58harh3737z96m0zmqv".to_string(),
+			Amount::from_sat(900_000),
+			vec![CollateralUtxo {
+				outpoint: OutPoint::new(deposit.txid(), 0),
+				amount: Amount::from_sat(1_000_000),
+				height: Some(100),
+			}],
+			"bcrt1q8ucxfsyajsdghspzpn8mx8m7gyfv0c8jfn60m7".to_string(),
+			collateral,
+		)
+		.with_previous_txns(vec![deposit])
+		.with_fee_target(FeeTarget::SatPerVb(2))
+		.with_tip_height(800_000)
+		.with_keyset(&keyset, 0)
+		.unwrap()
+		.create_psbt(None, &FeeSettings::default())
+		.unwrap()
+	}
+
+	#[test]
+	fn test_combine_and_finalize() {
+		let masters = masters();
+		let psbt = redemption_psbt(&masters);
+		let witness_script = psbt.inputs[0].witness_script.clone().unwrap();
+		let multisig = MultisigAddress::from_redeem_script(&witness_script).unwrap();
+
+		// the service and the borrower sign their own copies
+		let service = sign_psbt(psbt.clone(), masters[2], &DerivationPath::master()).unwrap();
+		let borrower = sign_psbt(psbt, masters[0], &DerivationPath::master()).unwrap();
+
+		let combined = combine_psbts(vec![service, borrower]).unwrap();
+		let borrower_sig = combined.inputs[0].partial_sigs[&multisig.borrower_pubkey];
+		let service_sig = combined.inputs[0].partial_sigs[&multisig.service_pubkey];
+
+		let transaction = extract_transaction(finalize_psbt(combined).unwrap()).unwrap();
+		let witness = transaction.input[0].witness.to_vec();
+
+		assert_eq!(
+			witness,
+			vec![
+				Vec::new(),
+				borrower_sig.to_vec(),
+				service_sig.to_vec(),
+				witness_script.to_bytes(),
+			]
+		);
+	}
+
+	#[test]
+	fn test_finalize_needs_two_signatures() {
+		let masters = masters();
+		let psbt = redemption_psbt(&masters);
+		let lender = sign_psbt(psbt.clone(), masters[1], &DerivationPath::master()).unwrap();
+
+		assert!(finalize_psbt(lender.clone()).is_err());
+		assert!(combine_psbts(Vec::new()).is_err());
+
+		let mut other = psbt;
+		other.unsigned_tx.lock_time = bitcoin::absolute::LockTime::ZERO;
+		assert!(combine_psbts(vec![lender, other]).is_err());
+	}
+}
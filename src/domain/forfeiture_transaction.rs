@@ -0,0 +1,775 @@
+use crate::config::{FeeSettings, TxnKind};
+use crate::constants::set_network;
+use crate::domain::collateral_utxo::CollateralUtxo;
+use crate::domain::key_derivation::{LoanKeyset, PartyXpub};
+use crate::domain::threshold_multisig::ThresholdMultisig;
+use crate::repository::collateral::{
+	get_borrower_payout_address, get_collateral_address, get_lender_shares,
+};
+use crate::repository::collateral_utxo::get_unspent_utxos;
+use crate::utils::get_feerate::FeeTarget;
+use crate::utils::transaction_utils::{
+	locktime_at_tip, order_outputs, ChangeOutcome, ConstructedTxn, OutputOrdering, Txn,
+};
+use crate::utils::validate_address::validate_address;
+use crate::utils::weight_estimator::InputWeight;
+use bitcoin::bip32::KeySource;
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
+use bitcoin::transaction::Version;
+use bitcoin::{secp256k1, Amount, Psbt, ScriptBuf};
+use bitcoincore_rpc::Client;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+/// A lender's claim on forfeited collateral, weighted by what they lent
+#[derive(Debug, Clone)]
+pub struct LenderShare {
+	pub payout_address: String,
+	/// as stored in `loan_lender.amount_lent`
+	pub amount_lent: f64,
+}
+
+/// What the borrower owes when the loan is liquidated, in the loan's currency
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoanDebt {
+	pub outstanding_amount: f64,
+	pub accrued_interest: f64,
+	/// price of one bitcoin in the loan's currency
+	pub btc_price: f64,
+	/// share of the debt added as a penalty, e.g. 0.05 for 5%
+	pub penalty_rate: f64,
+}
+
+impl LoanDebt {
+	/// The debt and penalty in satoshis. Each part of the debt and the penalised total are
+	/// rounded up to a whole cent, the price down, and the conversion up to a whole
+	/// satoshi, so the lenders are never short.
+	pub fn owed(&self) -> Result<Amount, String> {
+		let values = [
+			self.outstanding_amount,
+			self.accrued_interest,
+			self.btc_price,
+			self.penalty_rate,
+		];
+		if values
+			.iter()
+			.any(|value| !value.is_finite() || *value < 0.0)
+		{
+			return Err("Loan debt values must be finite and not negative".to_string());
+		}
+
+		// read amounts in millionths of a cent, which absorbs the f64 representation error,
+		// and apply the penalty in parts per million so everything after is exact
+		let micro_cents = |value: f64| (value * 100_000_000.0).round() as u128;
+		let debt_cents = micro_cents(self.outstanding_amount).div_ceil(1_000_000)
+			+ micro_cents(self.accrued_interest).div_ceil(1_000_000);
+		let penalty_ppm = (self.penalty_rate * 1_000_000.0).round() as u128;
+		let price_cents = micro_cents(self.btc_price) / 1_000_000;
+		if price_cents == 0 {
+			return Err("Bitcoin price must be positive".to_string());
+		}
+
+		let owed_cents = (debt_cents * (1_000_000 + penalty_ppm)).div_ceil(1_000_000);
+		let sats = (owed_cents * Amount::ONE_BTC.to_sat() as u128).div_ceil(price_cents);
+		if sats > Amount::MAX_MONEY.to_sat() as u128 {
+			return Err(format!(
+				"Debt of {} cents exceeds the bitcoin supply",
+				owed_cents
+			));
+		}
+		Ok(Amount::from_sat(sats as u64))
+	}
+}
+
+/// Fee the service takes from the collateral for settling a liquidation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceFee {
+	pub address: String,
+	pub amount: Amount,
+}
+
+/// How the collateral left after the network fee is divided
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+	/// split pro-rata between the lenders
+	pub lenders: Amount,
+	pub service: Amount,
+	pub borrower: Amount,
+}
+
+/// Sends forfeited collateral to the lenders of a (possibly syndicated) loan,
+/// split pro-rata by their share of the total amount lent.
+/// With a debt set this is a liquidation: the lenders are paid what is owed plus the
+/// penalty, the service its fee, and the borrower gets the surplus. When the collateral
+/// falls short, the lenders are paid first and the service second.
+#[derive(Debug, Clone)]
+pub struct ForfeitureTxn {
+	/// the multisig whose P2WSH outputs are being spent
+	pub collateral: ThresholdMultisig,
+	/// every unspent output at the collateral address, all of which are spent
+	pub utxos: Vec<CollateralUtxo>,
+	pub lenders: Vec<LenderShare>,
+	/// what the borrower owes, the lenders take all of the collateral when None
+	pub debt: Option<LoanDebt>,
+	/// the borrower's registered payout address, paid the surplus of a liquidation. Only
+	/// `for_loan` sets it, so the surplus cannot be sent anywhere else.
+	borrower_address: Option<String>,
+	pub service_fee: Option<ServiceFee>,
+	/// overrides the configured default for liquidations
+	pub fee_target: Option<FeeTarget>,
+	pub output_ordering: OutputOrdering,
+	/// height the anti-fee-sniping locktime is set from, bitcoind's tip when None
+	pub tip_height: Option<u32>,
+	/// where each collateral key was derived from, so wallets can find the key they sign with
+	pub key_derivations: BTreeMap<secp256k1::PublicKey, KeySource>,
+	/// the parties' account xpubs, added to the PSBT's global xpubs
+	pub xpubs: Vec<PartyXpub>,
+	/// transactions that created `utxos`, fetched from bitcoind when missing
+	pub previous_txns: Vec<Transaction>,
+}
+
+impl ForfeitureTxn {
+	pub fn new(
+		collateral: ThresholdMultisig,
+		utxos: Vec<CollateralUtxo>,
+		lenders: Vec<LenderShare>,
+	) -> Self {
+		Self {
+			collateral,
+			utxos,
+			lenders,
+			debt: None,
+			borrower_address: None,
+			service_fee: None,
+			fee_target: None,
+			output_ordering: OutputOrdering::default(),
+			tip_height: None,
+			key_derivations: BTreeMap::new(),
+			xpubs: Vec::new(),
+			previous_txns: Vec::new(),
+		}
+	}
+
+	/// Spends every unspent output recorded at the loan's collateral address to the loan's
+	/// lenders. With `debt` the loan is liquidated and the surplus goes to the borrower's
+	/// registered payout address.
+	pub async fn for_loan(
+		pool: &PgPool,
+		loan_request_id: Uuid,
+		debt: Option<LoanDebt>,
+	) -> Result<Self, String> {
+		let record = get_collateral_address(pool, loan_request_id)
+			.await
+			.map_err(|e| format!("Error loading collateral: {:?}", e))?
+			.ok_or_else(|| format!("Loan {} has no collateral", loan_request_id))?;
+		let utxos = get_unspent_utxos(pool, loan_request_id)
+			.await
+			.map_err(|e| format!("Error loading collateral UTXOs: {:?}", e))?;
+		let lenders = get_lender_shares(pool, loan_request_id)
+			.await
+			.map_err(|e| format!("Error loading lenders: {:?}", e))?;
+
+		let mut txn = Self::new(ThresholdMultisig::from(&record.multisig), utxos, lenders)
+			.with_key_derivations(record.multisig.key_derivations(&record.origins));
+		if let Some(debt) = debt {
+			let borrower_address = get_borrower_payout_address(pool, loan_request_id)
+				.await
+				.map_err(|e| format!("Error loading borrower: {:?}", e))?
+				.ok_or_else(|| {
+					format!(
+						"The borrower of loan {} has no registered payout address",
+						loan_request_id
+					)
+				})?;
+			txn.debt = Some(debt);
+			txn.borrower_address = Some(borrower_address);
+		}
+		Ok(txn)
+	}
+
+	pub fn with_service_fee(mut self, address: String, amount: Amount) -> Self {
+		self.service_fee = Some(ServiceFee { address, amount });
+		self
+	}
+
+	pub fn with_fee_target(mut self, fee_target: FeeTarget) -> Self {
+		self.fee_target = Some(fee_target);
+		self
+	}
+
+	pub fn with_output_ordering(mut self, output_ordering: OutputOrdering) -> Self {
+		self.output_ordering = output_ordering;
+		self
+	}
+
+	/// Sets the locktime from this height instead of bitcoind's tip
+	pub fn with_tip_height(mut self, tip_height: u32) -> Self {
+		self.tip_height = Some(tip_height);
+		self
+	}
+
+	pub fn with_previous_txns(mut self, previous_txns: Vec<Transaction>) -> Self {
+		self.previous_txns = previous_txns;
+		self
+	}
+
+	pub fn with_key_derivations(
+		mut self,
+		key_derivations: BTreeMap<secp256k1::PublicKey, KeySource>,
+	) -> Self {
+		self.key_derivations = key_derivations;
+		self
+	}
+
+	/// Origins and xpubs of a 2-of-3 collateral derived from the parties' xpubs at
+	/// `derivation_index`
+	pub fn with_keyset(
+		mut self,
+		keyset: &LoanKeyset,
+		derivation_index: u32,
+	) -> Result<Self, String> {
+		let derived = keyset.derive(derivation_index)?;
+		if ThresholdMultisig::from(&derived.multisig).redeem_script()
+			!= self.collateral.redeem_script()
+		{
+			return Err(format!(
+				"Keys at index {} do not match the collateral",
+				derivation_index
+			));
+		}
+		self.key_derivations = derived.multisig.key_derivations(&derived.origins);
+		self.xpubs = keyset.xpubs();
+		Ok(self)
+	}
+
+	fn input_weights(&self) -> Vec<InputWeight> {
+		let weight = InputWeight::p2wsh_multisig(
+			self.collateral.threshold,
+			&self.collateral.redeem_script(),
+		);
+		vec![weight; self.utxos.len()]
+	}
+
+	fn input_total(&self) -> Result<Amount, String> {
+		self.utxos
+			.iter()
+			.try_fold(Amount::ZERO, |total, utxo| total.checked_add(utxo.amount))
+			.ok_or_else(|| "Collateral total overflows".to_string())
+	}
+
+	/// Divides `distributable` between the lenders, the service and the borrower, in
+	/// that order of priority
+	pub fn allocate(&self, distributable: Amount) -> Result<Allocation, String> {
+		let lenders = match &self.debt {
+			Some(debt) => debt.owed()?.min(distributable),
+			None => distributable,
+		};
+		let remaining = distributable - lenders;
+		let service = self
+			.service_fee
+			.as_ref()
+			.map_or(Amount::ZERO, |fee| fee.amount.min(remaining));
+
+		Ok(Allocation {
+			lenders,
+			service,
+			borrower: remaining - service,
+		})
+	}
+
+	/// Splits `total` across the lenders in proportion to `amount_lent`.
+	/// Satoshis lost to rounding go to the lenders with the largest remainders.
+	pub fn split_pro_rata(&self, total: Amount) -> Result<Vec<Amount>, String> {
+		if self.lenders.is_empty() {
+			return Err("A forfeiture needs at least one lender".to_string());
+		}
+		if self
+			.lenders
+			.iter()
+			.any(|lender| !lender.amount_lent.is_finite() || lender.amount_lent <= 0.0)
+		{
+			return Err("Every lender must have a positive amount lent".to_string());
+		}
+
+		// weigh by whole cents so the split itself is exact integer arithmetic
+		let weights: Vec<u128> = self
+			.lenders
+			.iter()
+			.map(|lender| (lender.amount_lent * 100.0).round() as u128)
+			.collect();
+		let total_weight: u128 = weights.iter().sum();
+		if total_weight == 0 {
+			return Err("Total amount lent is too small to split".to_string());
+		}
+
+		let total_sats = total.to_sat() as u128;
+		let mut shares: Vec<u64> = Vec::new();
+		let mut remainders: Vec<(u128, usize)> = Vec::new();
+		for (index, weight) in weights.iter().enumerate() {
+			let scaled = total_sats * weight;
+			shares.push((scaled / total_weight) as u64);
+			remainders.push((scaled % total_weight, index));
+		}
+
+		let allocated: u64 = shares.iter().sum();
+		remainders.sort_by_key(|(remainder, _)| Reverse(*remainder));
+		for (_, index) in remainders
+			.into_iter()
+			.take((total.to_sat() - allocated) as usize)
+		{
+			shares[index] += 1;
+		}
+
+		Ok(shares.into_iter().map(Amount::from_sat).collect())
+	}
+
+	/// Script pubkeys of the service when it takes a fee, and of the borrower on a
+	/// liquidation
+	fn derive_output_scripts(&self) -> Result<(Option<ScriptBuf>, Option<ScriptBuf>), String> {
+		let network = set_network();
+		let service_spkh = match &self.service_fee {
+			Some(service_fee) => {
+				Some(validate_address(&service_fee.address, network)?.script_pubkey())
+			}
+			None => None,
+		};
+		let borrower_spkh = match (&self.debt, &self.borrower_address) {
+			(None, _) => None,
+			(Some(_), Some(address)) => Some(validate_address(address, network)?.script_pubkey()),
+			(Some(_), None) => {
+				return Err("A liquidation needs the borrower's address for the surplus".to_string())
+			}
+		};
+
+		Ok((service_spkh, borrower_spkh))
+	}
+
+	fn calculate_outputs(
+		&self,
+		allocation: &Allocation,
+		with_service: bool,
+		with_borrower: bool,
+	) -> Result<Vec<TxOut>, String> {
+		let network = set_network();
+		let (service_spkh, borrower_spkh) = self.derive_output_scripts()?;
+		let shares = self.split_pro_rata(allocation.lenders)?;
+
+		let mut tx_outputs = Vec::new();
+		for (lender, value) in self.lenders.iter().zip(shares) {
+			tx_outputs.push(TxOut {
+				value,
+				script_pubkey: validate_address(&lender.payout_address, network)?.script_pubkey(),
+			});
+		}
+		if let Some(script_pubkey) = service_spkh.filter(|_| with_service) {
+			tx_outputs.push(TxOut {
+				value: allocation.service,
+				script_pubkey,
+			});
+		}
+		if let Some(script_pubkey) = borrower_spkh.filter(|_| with_borrower) {
+			tx_outputs.push(TxOut {
+				value: allocation.borrower,
+				script_pubkey,
+			});
+		}
+		Ok(tx_outputs)
+	}
+
+	/// Builds the forfeiture. A service fee or surplus below the dust limit is left to
+	/// the network fee rather than paid out.
+	pub fn construct_trxn(
+		&self,
+		client: Option<&Client>,
+		fee_settings: &FeeSettings,
+	) -> Result<ConstructedTxn, String> {
+		if self.utxos.is_empty() {
+			return Err("There is no collateral to forfeit".to_string());
+		}
+		let input_total = self.input_total()?;
+
+		let outpoints: Vec<_> = self.utxos.iter().map(|utxo| utxo.outpoint).collect();
+		let tx_inputs = ForfeitureTxn::calculate_inputs(&outpoints);
+		let fee_rate = self
+			.fee_target
+			.unwrap_or(fee_settings.target(TxnKind::Liquidation))
+			.fee_rate()?;
+
+		let (service_spkh, borrower_spkh) = self.derive_output_scripts()?;
+		let below_dust = |script: &Option<ScriptBuf>, value: Amount| {
+			script
+				.as_ref()
+				.is_some_and(|script| value < script.dust_value())
+		};
+		let mut with_service = service_spkh.is_some();
+		let mut with_borrower = borrower_spkh.is_some();
+		let allocation = loop {
+			let placeholder = Allocation {
+				lenders: Amount::ZERO,
+				service: Amount::ZERO,
+				borrower: Amount::ZERO,
+			};
+			let fee = ForfeitureTxn::calculate_fees(
+				self.calculate_outputs(&placeholder, with_service, with_borrower)?,
+				tx_inputs.clone(),
+				&self.input_weights(),
+				fee_rate,
+			)?;
+			let distributable = input_total
+				.checked_sub(fee)
+				.ok_or_else(|| "Collateral does not cover the transaction fees".to_string())?;
+			let allocation = self.allocate(distributable)?;
+
+			if with_borrower && below_dust(&borrower_spkh, allocation.borrower) {
+				with_borrower = false;
+			} else if with_service && below_dust(&service_spkh, allocation.service) {
+				with_service = false;
+			} else {
+				break allocation;
+			}
+		};
+
+		let tx_outputs = self.calculate_outputs(&allocation, with_service, with_borrower)?;
+		for (lender, output) in self.lenders.iter().zip(&tx_outputs) {
+			if output.value < output.script_pubkey.dust_value() {
+				return Err(format!(
+					"Share of {} for {} is below the dust limit",
+					output.value, lender.payout_address
+				));
+			}
+		}
+
+		let paid_out = tx_outputs.iter().map(|output| output.value).sum::<Amount>();
+		let fee = input_total - paid_out;
+		fee_settings.check_fee(fee, input_total)?;
+
+		let change = match (with_borrower, allocation.borrower) {
+			(true, surplus) => ChangeOutcome::Change(surplus),
+			(false, Amount::ZERO) => ChangeOutcome::NoChange,
+			(false, surplus) => ChangeOutcome::DroppedDust(surplus),
+		};
+		let (tx_outputs, output_map) = order_outputs(tx_outputs, self.output_ordering);
+
+		Ok(ConstructedTxn {
+			transaction: Transaction {
+				version: Version::TWO,
+				lock_time: locktime_at_tip(self.tip_height, client)?,
+				input: tx_inputs,
+				output: tx_outputs,
+			},
+			fee,
+			change,
+			output_map,
+		})
+	}
+
+	/// The forfeiture as a PSBT for the service and the lenders to sign
+	pub fn create_psbt(
+		&self,
+		client: Option<&Client>,
+		fee_settings: &FeeSettings,
+	) -> Result<Psbt, String> {
+		let constructed = self.construct_trxn(client, fee_settings)?;
+		let mut psbt = Psbt::from_unsigned_tx(constructed.transaction)
+			.map_err(|e| format!("Error creating PSBT: {}", e))?;
+
+		psbt.inputs = self.collateral.psbt_inputs(
+			&self.utxos,
+			&self.previous_txns,
+			&self.key_derivations,
+			client,
+		)?;
+		psbt.xpub = self
+			.xpubs
+			.iter()
+			.map(|party| {
+				(
+					party.xpub,
+					(party.origin.fingerprint, party.origin.path.clone()),
+				)
+			})
+			.collect();
+
+		Ok(psbt)
+	}
+}
+
+impl Txn for ForfeitureTxn {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::domain::MultisigAddress;
+	use bitcoin::absolute::LockTime;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use bitcoin::{OutPoint, PublicKey};
+
+	const LENDER: &str = "bcrt1qeygjhsgt5sumtlqnyfu58harh3737z96m0zmqv";
+	const SECOND_LENDER: &str = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+	const BORROWER: &str = "bcrt1q8ucxfsyajsdghspzpn8mx8m7gyfv0c8jfn60m7";
+
+	fn collateral() -> ThresholdMultisig {
+		let secp = Secp256k1::new();
+		let [borrower, lender, service] = [1u8, 2, 3].map(|byte| {
+			PublicKey::new(
+				SecretKey::from_slice(&[byte; 32])
+					.unwrap()
+					.public_key(&secp),
+			)
+		});
+		ThresholdMultisig::from(&MultisigAddress::new(borrower, lender, service))
+	}
+
+	fn forfeiture(lenders: &[(&str, f64)], collateral_sats: u64) -> ForfeitureTxn {
+		let deposit = Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: Vec::new(),
+			output: vec![TxOut {
+				value: Amount::from_sat(collateral_sats),
+				script_pubkey: collateral().create_p2wsh_address().script_pubkey(),
+			}],
+		};
+		let lenders = lenders
+			.iter()
+			.map(|(payout_address, amount_lent)| LenderShare {
+				payout_address: payout_address.to_string(),
+				amount_lent: *amount_lent,
+			})
+			.collect();
+
+		ForfeitureTxn::new(
+			collateral(),
+			vec![CollateralUtxo {
+				outpoint: OutPoint::new(deposit.txid(), 0),
+				amount: Amount::from_sat(collateral_sats),
+				height: Some(100),
+			}],
+			lenders,
+		)
+		.with_previous_txns(vec![deposit])
+		.with_fee_target(FeeTarget::SatPerVb(2))
+		.with_tip_height(800_000)
+	}
+
+	/// 10,500 owed with a 10% penalty at 50,000 per bitcoin: 23,100,000 sats
+	fn debt() -> LoanDebt {
+		LoanDebt {
+			outstanding_amount: 10_000.0,
+			accrued_interest: 500.0,
+			btc_price: 50_000.0,
+			penalty_rate: 0.1,
+		}
+	}
+
+	fn liquidation(lenders: &[(&str, f64)], collateral_sats: u64) -> ForfeitureTxn {
+		let mut txn = forfeiture(lenders, collateral_sats);
+		txn.debt = Some(debt());
+		txn.borrower_address = Some(BORROWER.to_string());
+		txn
+	}
+
+	fn paid_to(transaction: &Transaction, address: &str) -> Option<Amount> {
+		let script_pubkey = validate_address(address, set_network())
+			.unwrap()
+			.script_pubkey();
+		transaction
+			.output
+			.iter()
+			.find(|output| output.script_pubkey == script_pubkey)
+			.map(|output| output.value)
+	}
+
+	#[test]
+	fn test_split_pro_rata() {
+		let shares = forfeiture(&[(LENDER, 30_000.0), (LENDER, 10_000.0)], 0)
+			.split_pro_rata(Amount::from_sat(1_000_000))
+			.unwrap();
+
+		assert_eq!(
+			shares,
+			vec![Amount::from_sat(750_000), Amount::from_sat(250_000)]
+		);
+	}
+
+	#[test]
+	fn test_split_keeps_every_satoshi() {
+		let total = Amount::from_sat(100_001);
+		let shares = forfeiture(&[(LENDER, 1.0), (LENDER, 1.0), (LENDER, 1.0)], 0)
+			.split_pro_rata(total)
+			.unwrap();
+
+		assert_eq!(
+			shares.iter().map(|share| share.to_sat()).sum::<u64>(),
+			100_001
+		);
+		assert!(shares
+			.iter()
+			.all(|share| share.to_sat() == 33_333 || share.to_sat() == 33_334));
+	}
+
+	#[test]
+	fn test_invalid_lender_shares() {
+		assert!(forfeiture(&[], 0)
+			.split_pro_rata(Amount::from_sat(1_000))
+			.is_err());
+		assert!(forfeiture(&[(LENDER, 10.0), (LENDER, 0.0)], 0)
+			.split_pro_rata(Amount::from_sat(1_000))
+			.is_err());
+	}
+
+	#[test]
+	fn test_dust_share_rejected() {
+		let txn = forfeiture(&[(LENDER, 999_999.0), (SECOND_LENDER, 1.0)], 1_000_000);
+
+		assert!(txn.construct_trxn(None, &FeeSettings::default()).is_err());
+	}
+
+	#[test]
+	fn test_owed() {
+		assert_eq!(debt().owed().unwrap(), Amount::from_sat(23_100_000));
+
+		let zero_price = LoanDebt {
+			btc_price: 0.0,
+			..debt()
+		};
+		assert!(zero_price.owed().is_err());
+	}
+
+	#[test]
+	fn test_owed_rounds_fractional_cents_up() {
+		// a tenth of a cent of interest is still owed as a whole cent
+		let debt = LoanDebt {
+			outstanding_amount: 1.0,
+			accrued_interest: 0.001,
+			btc_price: 100.0,
+			penalty_rate: 0.0,
+		};
+		assert_eq!(debt.owed().unwrap(), Amount::from_sat(1_010_000));
+
+		// an f64 a hair above a whole cent is not charged another one
+		let debt = LoanDebt {
+			accrued_interest: 0.07,
+			..debt
+		};
+		assert_eq!(debt.owed().unwrap(), Amount::from_sat(1_070_000));
+	}
+
+	#[test]
+	fn test_owed_rounds_half_cent_up() {
+		// 1.00 with a 0.5% penalty is 1.005, which as an f64 sits just below the half
+		// cent; the lenders are owed 1.01 at 100 per bitcoin
+		let debt = LoanDebt {
+			outstanding_amount: 1.0,
+			accrued_interest: 0.0,
+			btc_price: 100.0,
+			penalty_rate: 0.005,
+		};
+
+		assert_eq!(debt.owed().unwrap(), Amount::from_sat(1_010_000));
+	}
+
+	#[test]
+	fn test_forfeiture_goes_to_the_lenders() {
+		let constructed = forfeiture(&[(LENDER, 30_000.0), (SECOND_LENDER, 10_000.0)], 1_000_000)
+			.construct_trxn(None, &FeeSettings::default())
+			.unwrap();
+		let transaction = &constructed.transaction;
+
+		assert_eq!(transaction.output.len(), 2);
+		assert_eq!(constructed.change, ChangeOutcome::NoChange);
+		assert_eq!(
+			paid_to(transaction, LENDER).unwrap() + paid_to(transaction, SECOND_LENDER).unwrap(),
+			Amount::from_sat(1_000_000) - constructed.fee
+		);
+	}
+
+	#[test]
+	fn test_splits_between_lender_service_and_borrower() {
+		let service = "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080";
+		let constructed = liquidation(&[(LENDER, 10_000.0)], 50_000_000)
+			.with_service_fee(service.to_string(), Amount::from_sat(100_000))
+			.construct_trxn(None, &FeeSettings::default())
+			.unwrap();
+		let transaction = &constructed.transaction;
+
+		assert_eq!(
+			paid_to(transaction, LENDER),
+			Some(Amount::from_sat(23_100_000))
+		);
+		assert_eq!(
+			paid_to(transaction, service),
+			Some(Amount::from_sat(100_000))
+		);
+		assert_eq!(
+			constructed.change,
+			ChangeOutcome::Change(Amount::from_sat(26_800_000) - constructed.fee)
+		);
+		assert_eq!(
+			paid_to(transaction, BORROWER),
+			constructed.change.change_amount()
+		);
+	}
+
+	#[test]
+	fn test_syndicated_liquidation_splits_the_debt() {
+		let constructed = liquidation(&[(LENDER, 30_000.0), (SECOND_LENDER, 10_000.0)], 50_000_000)
+			.construct_trxn(None, &FeeSettings::default())
+			.unwrap();
+		let transaction = &constructed.transaction;
+
+		assert_eq!(
+			paid_to(transaction, LENDER),
+			Some(Amount::from_sat(17_325_000))
+		);
+		assert_eq!(
+			paid_to(transaction, SECOND_LENDER),
+			Some(Amount::from_sat(5_775_000))
+		);
+		assert_eq!(
+			paid_to(transaction, BORROWER),
+			Some(Amount::from_sat(26_900_000) - constructed.fee)
+		);
+	}
+
+	#[test]
+	fn test_short_collateral_pays_the_lender_first() {
+		let constructed = liquidation(&[(LENDER, 10_000.0)], 20_000_000)
+			.with_service_fee(LENDER.to_string(), Amount::from_sat(100_000))
+			.construct_trxn(None, &FeeSettings::default())
+			.unwrap();
+
+		assert_eq!(constructed.transaction.output.len(), 1);
+		assert_eq!(constructed.change, ChangeOutcome::NoChange);
+		assert_eq!(
+			constructed.transaction.output[0].value + constructed.fee,
+			Amount::from_sat(20_000_000)
+		);
+	}
+
+	#[test]
+	fn test_liquidation_needs_the_borrower_address() {
+		let mut txn = liquidation(&[(LENDER, 10_000.0)], 50_000_000);
+		txn.borrower_address = None;
+
+		assert!(txn.construct_trxn(None, &FeeSettings::default()).is_err());
+	}
+
+	#[test]
+	fn test_psbt_is_signable_by_the_collateral_keys() {
+		let txn = liquidation(&[(LENDER, 10_000.0)], 50_000_000);
+		let psbt = txn.create_psbt(None, &FeeSettings::default()).unwrap();
+
+		assert_eq!(
+			psbt.inputs[0].witness_script,
+			Some(txn.collateral.redeem_script())
+		);
+		assert_eq!(
+			psbt.inputs[0].witness_utxo.as_ref().unwrap().script_pubkey,
+			txn.collateral.create_p2wsh_address().script_pubkey()
+		);
+		assert!(psbt.inputs[0].non_witness_utxo.is_some());
+	}
+}
@@ -1,26 +1,47 @@
+use crate::config::{FeeSettings, TxnKind};
 use crate::constants::set_network;
-use crate::utils::get_feerate::get_mempool_feerate;
-use crate::utils::transaction_utils::{get_outpoints_total, Txn};
+use crate::domain::collateral_descriptor::KeyOrigin;
+use crate::domain::fee_bump::bump_fee;
+use crate::repository::transaction_replacement::latest_replacement;
+use crate::utils::bitcoind_rpc::get_transaction;
+use crate::utils::coin_selection::{
+	output_vbytes, select_coins, utxos_from_wallet, SelectionTarget, Utxo, TX_OVERHEAD_VBYTES,
+};
+use crate::utils::get_feerate::FeeTarget;
+use crate::utils::transaction_utils::{
+	collateral_output_key, get_outpoints_txouts, locktime_at_tip, recover_output_map,
+	ConstructedTxn, OutputOrdering, Txn,
+};
+use crate::utils::weight_estimator::InputWeight;
+use bdk::database::BatchDatabase;
+use bdk::Wallet;
 use bitcoin::absolute::LockTime;
 use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxOut};
-use bitcoin::transaction::Version;
-use bitcoin::Network;
+use bitcoin::key::XOnlyPublicKey;
+use bitcoin::{Amount, FeeRate, Network, Psbt, PublicKey, ScriptBuf, Txid};
 use bitcoincore_rpc::Client;
+use sqlx::PgPool;
+use std::collections::BTreeMap;
 
-pub const PRECISION: i32 = 8;
+/// Keys the borrower's wallet controls, by the script they lock, with their BIP-32 origin
+pub type ScriptKeyOrigins = BTreeMap<ScriptBuf, (PublicKey, KeyOrigin)>;
 
 #[derive(Debug, Clone)]
 pub struct FundingTxn {
 	receiving_address: String,
-	amount: f64,
+	amount: Amount,
 	inputs: Vec<OutPoint>,
 	change_address: String,
+	/// overrides the configured default for funding transactions
+	fee_target: Option<FeeTarget>,
+	output_ordering: OutputOrdering,
+	tip_height: Option<u32>,
 }
 
 impl FundingTxn {
 	pub fn new(
 		receiving_address: String,
-		amount: f64,
+		amount: Amount,
 		inputs: Vec<OutPoint>,
 		change_address: String,
 	) -> Self {
@@ -29,71 +50,456 @@ impl FundingTxn {
 			amount,
 			inputs,
 			change_address,
+			fee_target: None,
+			output_ordering: OutputOrdering::default(),
+			tip_height: None,
 		}
 	}
 
-	pub fn construct_trxn(&self, client: Option<&Client>) -> Result<Transaction, String> {
-		let input_total = if set_network() == Network::Regtest && client.is_some() {
-			let rpc_client = client.expect("No client supplied");
-			get_outpoints_total(&self.inputs, Some(rpc_client)).map_err(|e| format!("{:?}", e))?
-		} else {
-			get_outpoints_total(&self.inputs, None).map_err(|e| format!("{:?}", e))?
+	pub fn with_fee_target(mut self, fee_target: FeeTarget) -> Self {
+		self.fee_target = Some(fee_target);
+		self
+	}
+
+	pub fn with_output_ordering(mut self, output_ordering: OutputOrdering) -> Self {
+		self.output_ordering = output_ordering;
+		self
+	}
+
+	/// Sets the locktime from this height instead of bitcoind's tip
+	pub fn with_tip_height(mut self, tip_height: u32) -> Self {
+		self.tip_height = Some(tip_height);
+		self
+	}
+
+	/// Picks the inputs from the given UTXOs to fund `amount` at the given fee rate
+	pub fn from_utxos(
+		receiving_address: String,
+		amount: Amount,
+		utxos: &[Utxo],
+		change_address: String,
+		fee_rate: FeeRate,
+	) -> Result<Self, String> {
+		let (receiving_spkh, change_spkh) =
+			FundingTxn::derive_script_pubkeys(&receiving_address, &change_address)?;
+
+		let target = SelectionTarget {
+			amount,
+			fee_rate,
+			base_vbytes: TX_OVERHEAD_VBYTES + output_vbytes(&receiving_spkh),
+			change_script: change_spkh,
 		};
+		let selection = select_coins(utxos, &target)?;
 
-		if input_total < self.amount {
-			return Err(format!("Insufficient amount provided: {}", input_total));
-		}
+		Ok(Self::new(
+			receiving_address,
+			amount,
+			selection.outpoints(),
+			change_address,
+		)
+		.with_fee_target(FeeTarget::SatPerVb(fee_rate.to_sat_per_vb_ceil())))
+	}
 
-		let fee_rates = get_mempool_feerate().map_err(|e| format!("{:?}", e))?;
-		let tx_inputs = FundingTxn::calculate_inputs(&self.inputs);
+	/// Like `from_utxos`, selecting from the unspent outputs of a BDK wallet
+	pub fn from_wallet<D: BatchDatabase>(
+		receiving_address: String,
+		amount: Amount,
+		wallet: &Wallet<D>,
+		change_address: String,
+		fee_rate: FeeRate,
+	) -> Result<Self, String> {
+		let utxos = utxos_from_wallet(wallet)?;
+		Self::from_utxos(receiving_address, amount, &utxos, change_address, fee_rate)
+	}
 
-		let initial_output = self.calculate_outputs(input_total, 0.0)?;
-		let fees = FundingTxn::calculate_fees(initial_output, tx_inputs.clone(), &fee_rates)?;
+	pub fn construct_trxn(
+		&self,
+		client: Option<&Client>,
+		fee_settings: &FeeSettings,
+	) -> Result<ConstructedTxn, String> {
+		let spent_outputs = match client {
+			Some(rpc_client) if set_network() == Network::Regtest => {
+				get_outpoints_txouts(&self.inputs, Some(rpc_client))?
+			}
+			_ => get_outpoints_txouts(&self.inputs, None)?,
+		};
 
-		let tx_outputs = self
-			.calculate_outputs(input_total, fees)
-			.map_err(|err| format!("{:?}", err))?;
+		self.build_trxn(
+			&spent_outputs,
+			fee_settings,
+			locktime_at_tip(self.tip_height, client)?,
+		)
+	}
 
-		Ok(Transaction {
-			version: Version::TWO,
-			lock_time: LockTime::ZERO,
-			input: tx_inputs,
-			output: tx_outputs,
-		})
+	/// Builds the transaction from the outputs its inputs spend
+	fn build_trxn(
+		&self,
+		spent_outputs: &[TxOut],
+		fee_settings: &FeeSettings,
+		lock_time: LockTime,
+	) -> Result<ConstructedTxn, String> {
+		let fee_rate = self
+			.fee_target
+			.unwrap_or(fee_settings.target(TxnKind::Funding))
+			.fee_rate()?;
+		let (_, change_spkh) =
+			FundingTxn::derive_script_pubkeys(&self.receiving_address, &self.change_address)?;
+
+		let constructed = FundingTxn::build_payment_trxn(
+			&self.inputs,
+			spent_outputs,
+			self.calculate_outputs(None)?,
+			&change_spkh,
+			fee_rate,
+			self.output_ordering,
+			lock_time,
+		)?;
+		fee_settings.check_fee(constructed.fee, self.amount)?;
+
+		Ok(constructed)
 	}
 
-	fn calculate_outputs(&self, input_total: f64, fees: f64) -> Result<Vec<TxOut>, String> {
+	fn calculate_outputs(&self, change: Option<Amount>) -> Result<Vec<TxOut>, String> {
 		let (receiving_spkh, change_spkh) =
 			FundingTxn::derive_script_pubkeys(&self.receiving_address, &self.change_address)?;
 
-		let (amount_in_hex, change_amount_hex) =
-			FundingTxn::amount_in_hex(self.amount, fees, input_total)?;
-
 		let mut tx_outputs = Vec::new();
 		let output1 = TxOut {
-			value: amount_in_hex,
+			value: self.amount,
 			script_pubkey: receiving_spkh,
 		};
 		tx_outputs.push(output1);
-		let output2 = TxOut {
-			value: change_amount_hex,
-			script_pubkey: change_spkh,
-		};
-		tx_outputs.push(output2);
+		if let Some(change_amount) = change {
+			let output2 = TxOut {
+				value: change_amount,
+				script_pubkey: change_spkh,
+			};
+			tx_outputs.push(output2);
+		}
 		Ok(tx_outputs)
 	}
+
+	/// Builds a BIP-174 PSBT the borrower can sign in any PSBT-capable wallet. `origins`
+	/// adds BIP-32 derivations for the inputs and change output whose keys are known.
+	pub fn create_psbt(
+		&self,
+		client: Option<&Client>,
+		fee_settings: &FeeSettings,
+		origins: &ScriptKeyOrigins,
+	) -> Result<Psbt, String> {
+		let constructed = self.construct_trxn(client, fee_settings)?;
+		let previous_txns = previous_txns(&self.inputs, client)?;
+
+		self.psbt_from_parts(constructed.transaction, &previous_txns, origins)
+	}
+
+	/// Rebuilds the stuck funding transaction `txid`, or its latest recorded replacement,
+	/// at `fee_rate`, paying the extra fee out of the change, and returns the replacement
+	/// as a PSBT to sign again. Broadcast the signed replacement with
+	/// `broadcast_signed_replacement` so the next bump builds on it.
+	pub async fn bump_fee(
+		&self,
+		pool: &PgPool,
+		txid: Txid,
+		fee_rate: FeeRate,
+		client: Option<&Client>,
+		fee_settings: &FeeSettings,
+		origins: &ScriptKeyOrigins,
+	) -> Result<Psbt, String> {
+		let (receiving_spkh, change_spkh) =
+			FundingTxn::derive_script_pubkeys(&self.receiving_address, &self.change_address)?;
+		let replacement = bump_funding(
+			pool,
+			txid,
+			&self.inputs,
+			&[receiving_spkh],
+			&change_spkh,
+			fee_rate,
+			client,
+		)
+		.await?;
+		fee_settings.check_fee(replacement.fee, self.amount)?;
+
+		let previous_txns = previous_txns(&self.inputs, client)?;
+		self.psbt_from_parts(replacement.transaction, &previous_txns, origins)
+	}
+
+	fn psbt_from_parts(
+		&self,
+		unsigned_txn: Transaction,
+		previous_txns: &[Transaction],
+		origins: &ScriptKeyOrigins,
+	) -> Result<Psbt, String> {
+		let (receiving_spkh, _) =
+			FundingTxn::derive_script_pubkeys(&self.receiving_address, &self.change_address)?;
+		funding_psbt(
+			unsigned_txn,
+			&self.inputs,
+			previous_txns,
+			&[receiving_spkh],
+			origins,
+		)
+	}
 }
 
 impl Txn for FundingTxn {}
 
+/// The transactions that created the inputs, for `non_witness_utxo`
+pub(crate) fn previous_txns(
+	inputs: &[OutPoint],
+	client: Option<&Client>,
+) -> Result<Vec<Transaction>, String> {
+	inputs
+		.iter()
+		.map(|input| {
+			get_transaction(input.txid, client)
+				.map_err(|e| format!("Error fetching transaction {}: {:?}", input.txid, e))
+		})
+		.collect()
+}
+
+/// Rebuilds the latest recorded replacement of the funding transaction `txid` at
+/// `fee_rate`, paying the extra fee out of the change. `payment_scripts` are the
+/// builder's payments, in order.
+pub(crate) async fn bump_funding(
+	pool: &PgPool,
+	txid: Txid,
+	inputs: &[OutPoint],
+	payment_scripts: &[ScriptBuf],
+	change_script: &ScriptBuf,
+	fee_rate: FeeRate,
+	client: Option<&Client>,
+) -> Result<ConstructedTxn, String> {
+	let txid = latest_replacement(pool, txid)
+		.await
+		.map_err(|e| format!("Error loading replacements of {}: {:?}", txid, e))?;
+	let original = get_transaction(txid, client)
+		.map_err(|e| format!("Error fetching transaction {}: {:?}", txid, e))?;
+	let original_inputs: Vec<OutPoint> = original
+		.input
+		.iter()
+		.map(|input| input.previous_output)
+		.collect();
+	if original_inputs != inputs {
+		return Err(format!(
+			"Transaction {} does not spend the funding inputs",
+			txid
+		));
+	}
+
+	let spent_outputs = get_outpoints_txouts(inputs, client)?;
+	let input_weights = spent_outputs
+		.iter()
+		.map(|txout| InputWeight::from_script_pubkey(&txout.script_pubkey))
+		.collect::<Result<Vec<_>, _>>()?;
+
+	let mut builder_scripts = payment_scripts.to_vec();
+	builder_scripts.push(change_script.clone());
+	let output_map = recover_output_map(&original, &builder_scripts)?;
+
+	bump_fee(
+		&original,
+		&output_map,
+		&spent_outputs,
+		&input_weights,
+		change_script,
+		fee_rate,
+	)
+}
+
+/// Builds the PSBT of a transaction funding collateral from the wallet's inputs. Inputs
+/// carry the spent outputs and the key origins `origins` knows; outputs paying to
+/// `collateral_scripts` are marked as collateral, other known outputs get derivations.
+pub(crate) fn funding_psbt(
+	unsigned_txn: Transaction,
+	inputs: &[OutPoint],
+	previous_txns: &[Transaction],
+	collateral_scripts: &[ScriptBuf],
+	origins: &ScriptKeyOrigins,
+) -> Result<Psbt, String> {
+	let mut psbt =
+		Psbt::from_unsigned_tx(unsigned_txn).map_err(|e| format!("Error creating PSBT: {}", e))?;
+
+	for ((input, outpoint), previous_txn) in psbt.inputs.iter_mut().zip(inputs).zip(previous_txns) {
+		if previous_txn.txid() != outpoint.txid {
+			return Err(format!("Missing previous transaction for {}", outpoint));
+		}
+		let spent = previous_txn
+			.output
+			.get(outpoint.vout as usize)
+			.cloned()
+			.ok_or_else(|| format!("Output {} does not exist", outpoint))?;
+
+		if spent.script_pubkey.is_witness_program() {
+			input.witness_utxo = Some(spent.clone());
+		}
+		// taproot signers commit to every spent amount and need no full transaction
+		if !spent.script_pubkey.is_p2tr() {
+			input.non_witness_utxo = Some(previous_txn.clone());
+		}
+
+		if let Some((pubkey, origin)) = origins.get(&spent.script_pubkey) {
+			let source = (origin.fingerprint, origin.path.clone());
+			if spent.script_pubkey.is_p2tr() {
+				input
+					.tap_key_origins
+					.insert(XOnlyPublicKey::from(pubkey.inner), (Vec::new(), source));
+			} else {
+				input.bip32_derivation.insert(pubkey.inner, source);
+			}
+		}
+	}
+
+	for (output, txout) in psbt.outputs.iter_mut().zip(&psbt.unsigned_tx.output) {
+		if collateral_scripts.contains(&txout.script_pubkey) {
+			output
+				.proprietary
+				.insert(collateral_output_key(), Vec::new());
+		} else if let Some((pubkey, origin)) = origins.get(&txout.script_pubkey) {
+			output
+				.bip32_derivation
+				.insert(pubkey.inner, (origin.fingerprint, origin.path.clone()));
+		}
+	}
+
+	Ok(psbt)
+}
+
 #[cfg(test)]
 mod test {
 	use super::*;
+	use crate::domain::funding_transaction::FundingTxn;
 	use crate::utils::test_node::TestNode;
-	use crate::{domain::funding_transaction::FundingTxn, utils::get_feerate::MempoolSpaceFeeRate};
-	use bitcoin::Amount;
+	use crate::utils::weight_estimator::estimate_weight;
+	use bitcoin::hashes::Hash;
+	use bitcoin::transaction::Version;
+	use bitcoin::Txid;
 	use bitcoincore_rpc::RawTx;
-	use round::round_down;
+	use std::str::FromStr;
+
+	#[test]
+	fn test_from_utxos() {
+		let address = "bcrt1qeygjhsgt5sumtlqnyfu58harh3737z96m0zmqv".to_string();
+		let script_pubkey = FundingTxn::derive_script_pubkeys(&address, &address)
+			.unwrap()
+			.0;
+		let utxos: Vec<Utxo> = [30_000_000, 5_000_000, 90_000_000]
+			.iter()
+			.enumerate()
+			.map(|(vout, value)| {
+				Utxo::new(
+					OutPoint::new(Txid::all_zeros(), vout as u32),
+					TxOut {
+						value: Amount::from_sat(*value),
+						script_pubkey: script_pubkey.clone(),
+					},
+				)
+				.unwrap()
+			})
+			.collect();
+
+		let fdn_txn = FundingTxn::from_utxos(
+			address.clone(),
+			Amount::from_sat(50_000_000),
+			&utxos,
+			address,
+			FeeRate::from_sat_per_vb(10).unwrap(),
+		)
+		.unwrap();
+
+		assert_eq!(fdn_txn.inputs, vec![OutPoint::new(Txid::all_zeros(), 2)]);
+	}
+
+	#[test]
+	fn test_from_utxos_keeps_fee_rate() {
+		let address = "bcrt1qeygjhsgt5sumtlqnyfu58harh3737z96m0zmqv".to_string();
+		let script_pubkey = FundingTxn::derive_script_pubkeys(&address, &address)
+			.unwrap()
+			.0;
+		let utxos = vec![Utxo::new(
+			OutPoint::new(Txid::all_zeros(), 0),
+			TxOut {
+				value: Amount::from_sat(90_000_000),
+				script_pubkey: script_pubkey.clone(),
+			},
+		)
+		.unwrap()];
+		let fee_rate = FeeRate::from_sat_per_vb(25).unwrap();
+		let selection = select_coins(
+			&utxos,
+			&SelectionTarget {
+				amount: Amount::from_sat(50_000_000),
+				fee_rate,
+				base_vbytes: TX_OVERHEAD_VBYTES + output_vbytes(&script_pubkey),
+				change_script: script_pubkey,
+			},
+		)
+		.unwrap();
+
+		let fdn_txn = FundingTxn::from_utxos(
+			address.clone(),
+			Amount::from_sat(50_000_000),
+			&utxos,
+			address,
+			fee_rate,
+		)
+		.unwrap();
+		let spent_outputs: Vec<TxOut> = utxos.iter().map(|utxo| utxo.txout.clone()).collect();
+		let constructed = fdn_txn
+			.build_trxn(&spent_outputs, &FeeSettings::default(), LockTime::ZERO)
+			.unwrap();
+
+		assert_eq!(constructed.fee, selection.fee);
+	}
+
+	#[test]
+	fn test_psbt_from_parts() {
+		let secp = bitcoin::secp256k1::Secp256k1::new();
+		let secret = bitcoin::secp256k1::SecretKey::from_slice(&[1u8; 32]).unwrap();
+		let pubkey = PublicKey::new(secret.public_key(&secp));
+		let wallet_address = bitcoin::Address::p2wpkh(&pubkey, Network::Regtest).unwrap();
+		let origin = KeyOrigin::from_str("[c258d2e4/84'/1'/0'/0/0]").unwrap();
+		let origins = ScriptKeyOrigins::from([(wallet_address.script_pubkey(), (pubkey, origin))]);
+
+		let previous_txn = Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: Vec::new(),
+			output: vec![TxOut {
+				value: Amount::from_sat(1_000_000),
+				script_pubkey: wallet_address.script_pubkey(),
+			}],
+		};
+		let fdn_txn = FundingTxn::new(
+			"bcrt1qeygjhsgt5sumtlqnyfu58harh3737z96m0zmqv".to_string(),
+			Amount::from_sat(500_000),
+			vec![OutPoint::new(previous_txn.txid(), 0)],
+			wallet_address.to_string(),
+		);
+		let unsigned_txn = Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: FundingTxn::calculate_inputs(&fdn_txn.inputs),
+			output: fdn_txn
+				.calculate_outputs(Some(Amount::from_sat(499_000)))
+				.unwrap(),
+		};
+
+		let psbt = fdn_txn
+			.psbt_from_parts(unsigned_txn, std::slice::from_ref(&previous_txn), &origins)
+			.unwrap();
+
+		assert_eq!(
+			psbt.inputs[0].witness_utxo,
+			Some(previous_txn.output[0].clone())
+		);
+		assert_eq!(psbt.inputs[0].non_witness_utxo, Some(previous_txn));
+		assert!(psbt.inputs[0].bip32_derivation.contains_key(&pubkey.inner));
+		assert!(psbt.outputs[0]
+			.proprietary
+			.contains_key(&collateral_output_key()));
+		assert!(psbt.outputs[1].bip32_derivation.contains_key(&pubkey.inner));
+	}
 
 	#[test]
 	#[ignore = "failing when run with all the tests but passes as a single or this module"]
@@ -123,18 +529,19 @@ mod test {
 
 		let fdn_txn = FundingTxn::new(
 			receiving_address.to_string(),
-			2.56,
+			Amount::from_sat(256_000_000),
 			txinputs,
 			change_address.to_string(),
 		);
 
 		let txn = fdn_txn
-			.construct_trxn(Some(&client.bitcoind.client))
-			.unwrap();
+			.construct_trxn(Some(&client.bitcoind.client), &FeeSettings::default())
+			.unwrap()
+			.transaction;
 		assert_eq!(txn.version, Version::TWO);
 		assert!(!txn.is_coinbase());
 		assert!(!txn.raw_hex().is_empty());
-		assert_eq!(txn.lock_time, LockTime::ZERO);
+		assert!(txn.lock_time.is_block_height());
 		assert_eq!(txn.output.len(), 2);
 		assert_eq!(txn.input.len(), 1);
 	}
@@ -148,7 +555,7 @@ mod test {
 		let receiving_address = client.new_address(None).unwrap();
 		let change_address = client.new_address(None).unwrap();
 
-		let amount_to_spend = 2.56;
+		let amount_to_spend = Amount::from_sat(256_000_000);
 
 		let _ = client.generate_to_address(101, address_1.clone());
 
@@ -173,37 +580,26 @@ mod test {
 			txinputs,
 			change_address.to_string(),
 		);
-		let input_total =
-			get_outpoints_total(&fdn_txn.inputs, Some(&client.bitcoind.client)).unwrap();
-
-		let txn_details = fdn_txn
-			.construct_trxn(Some(&client.bitcoind.client))
+		let fee_rate = FeeRate::from_sat_per_vb(15).unwrap();
+		let constructed = fdn_txn
+			.clone()
+			.with_fee_target(FeeTarget::SatPerVb(15))
+			.construct_trxn(Some(&client.bitcoind.client), &FeeSettings::default())
 			.unwrap();
+		let txn_details = constructed.transaction.clone();
 
 		let inputs = FundingTxn::calculate_inputs(&fdn_txn.inputs);
-		let tx_outputs = fdn_txn.calculate_outputs(input_total, 0.0).unwrap();
-		let fee_rate = MempoolSpaceFeeRate {
-			fastest_fee: 15,
-			half_hour_fee: 14,
-			hour_fee: 13,
-			economy_fee: 12,
-			minimum_fee: 10,
-		};
-		let computed_fees = FundingTxn::calculate_fees(tx_outputs, inputs, &fee_rate).unwrap();
-
-		let v_size = txn_details.vsize();
-
-		let input_len = txn_details.input.len();
-
-		let total_size = v_size + (input_len * 72);
-
-		let fees = fee_rate.fastest_fee * total_size;
-
-		let fees_in_btc = round_down(
-			Amount::from_sat(fees.try_into().unwrap()).to_btc(),
-			PRECISION,
-		);
-
-		assert_eq!(computed_fees, fees_in_btc);
+		let tx_outputs = txn_details.output.clone();
+		// the test node's wallet uses P2WPKH addresses
+		let input_weights = [InputWeight::p2wpkh()];
+		let computed_fees =
+			FundingTxn::calculate_fees(tx_outputs, inputs, &input_weights, fee_rate).unwrap();
+
+		let v_size = estimate_weight(&txn_details, &input_weights)
+			.unwrap()
+			.to_vbytes_ceil();
+
+		assert_eq!(computed_fees, fee_rate.fee_vb(v_size).unwrap());
+		assert_eq!(constructed.fee, computed_fees);
 	}
 }
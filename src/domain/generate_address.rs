@@ -1,7 +1,10 @@
 use crate::constants::set_network;
+use crate::utils::validate_address::validate_address;
+use crate::utils::validate_publickeys::{validate_publickeys, PublicKeyError};
 use bitcoin::opcodes::all::{OP_CHECKMULTISIG, OP_PUSHNUM_2, OP_PUSHNUM_3};
-use bitcoin::script::Builder;
-use bitcoin::{Address, PublicKey, ScriptBuf};
+use bitcoin::script::{Builder, Instruction};
+use bitcoin::{Address, PublicKey, Script, ScriptBuf};
+use std::collections::HashSet;
 
 #[derive(Debug, Clone)]
 pub struct MultisigAddress {
@@ -23,6 +26,40 @@ impl MultisigAddress {
 		}
 	}
 
+	/// Like `new`, but rejects keys that are not safe to lock collateral with
+	pub fn try_new(
+		borrower_pubkey: PublicKey,
+		lender_pubkey: PublicKey,
+		service_pubkey: PublicKey,
+		used_keys: &HashSet<PublicKey>,
+	) -> Result<Self, PublicKeyError> {
+		validate_publickeys(&borrower_pubkey, &lender_pubkey, &service_pubkey, used_keys)?;
+		Ok(Self::new(borrower_pubkey, lender_pubkey, service_pubkey))
+	}
+
+	/// Recovers the keys from a redeem script built by `redeem_script`
+	pub fn from_redeem_script(script: &Script) -> Result<Self, String> {
+		let mut keys = Vec::new();
+		for instruction in script.instructions() {
+			match instruction.map_err(|e| format!("Invalid redeem script: {:?}", e))? {
+				Instruction::PushBytes(bytes) => keys.push(
+					PublicKey::from_slice(bytes.as_bytes())
+						.map_err(|e| format!("Invalid public key in redeem script: {:?}", e))?,
+				),
+				Instruction::Op(_) => continue,
+			}
+		}
+
+		let multisig = match keys.as_slice() {
+			[borrower, lender, service] => Self::new(*borrower, *lender, *service),
+			_ => return Err("Redeem script is not a 2-of-3 multisig".to_string()),
+		};
+		if multisig.redeem_script().as_script() != script {
+			return Err("Redeem script is not a 2-of-3 multisig".to_string());
+		}
+		Ok(multisig)
+	}
+
 	///Redeem_script: OP_2  [pubkey1] [pubkey2] [pubkey3] OP_3 OP_CHECKMULTISIG
 	pub fn redeem_script(&self) -> ScriptBuf {
 		Builder::new()
@@ -35,6 +72,12 @@ impl MultisigAddress {
 			.into_script()
 	}
 
+	/// Recomputes the P2WSH address and checks it matches the one given to the parties
+	pub fn verify_p2wsh_address(&self, address: &str) -> Result<bool, String> {
+		let address = validate_address(address, set_network())?;
+		Ok(address == self.create_p2wsh_address())
+	}
+
 	/// P2WSH: OP_HASH160 <20-byte hash of redeem script> OP_EQUAL
 	pub fn create_p2wsh_address(&self) -> Address {
 		Address::p2wsh(&self.redeem_script(), set_network())
@@ -80,4 +123,34 @@ mod tests {
 		assert_eq!(result.network(), &network);
 		assert_eq!(result.address_type(), Some(AddressType::P2wsh));
 	}
+
+	#[test]
+	fn test_verify_p2wsh_address() {
+		let keys = valid_publickeys();
+		let address = keys.create_p2wsh_address().to_string();
+		let swapped = MultisigAddress::new(
+			keys.lender_pubkey,
+			keys.borrower_pubkey,
+			keys.service_pubkey,
+		);
+
+		assert_eq!(keys.verify_p2wsh_address(&address), Ok(true));
+		assert_eq!(swapped.verify_p2wsh_address(&address), Ok(false));
+		assert!(keys.verify_p2wsh_address("not an address").is_err());
+	}
+
+	#[test]
+	fn test_from_redeem_script() {
+		let keys = valid_publickeys();
+		let parsed = MultisigAddress::from_redeem_script(&keys.redeem_script()).unwrap();
+
+		assert_eq!(parsed.lender_pubkey, keys.lender_pubkey);
+		assert!(MultisigAddress::try_new(
+			keys.borrower_pubkey,
+			keys.borrower_pubkey,
+			keys.service_pubkey,
+			&HashSet::new()
+		)
+		.is_err());
+	}
 }
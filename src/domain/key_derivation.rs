@@ -0,0 +1,208 @@
+use crate::domain::collateral_descriptor::{CollateralKeyOrigins, KeyOrigin};
+use crate::domain::MultisigAddress;
+use bitcoin::bip32::{ChildNumber, DerivationPath, Xpub};
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::PublicKey;
+use std::fmt;
+use std::str::FromStr;
+
+// collateral keys are derived on the external chain of each account: <account>/0/<index>
+const COLLATERAL_CHAIN: u32 = 0;
+
+/// An account xpub registered by a party, together with its key origin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartyXpub {
+	pub xpub: Xpub,
+	pub origin: KeyOrigin,
+}
+
+impl PartyXpub {
+	pub fn new(xpub: Xpub, origin: KeyOrigin) -> Self {
+		Self { xpub, origin }
+	}
+
+	/// Path below the account xpub for the given loan index
+	fn child_path(index: u32) -> Result<DerivationPath, String> {
+		let chain = ChildNumber::from_normal_idx(COLLATERAL_CHAIN)
+			.map_err(|e| format!("Invalid chain index: {:?}", e))?;
+		let child = ChildNumber::from_normal_idx(index)
+			.map_err(|e| format!("Invalid derivation index {}: {:?}", index, e))?;
+		Ok(DerivationPath::from(vec![chain, child]))
+	}
+
+	/// Derives the public key for the given loan index and its full key origin
+	pub fn derive(&self, index: u32) -> Result<(PublicKey, KeyOrigin), String> {
+		let secp = Secp256k1::verification_only();
+		let child_path = Self::child_path(index)?;
+
+		let child = self
+			.xpub
+			.derive_pub(&secp, &child_path)
+			.map_err(|e| format!("Error deriving child key: {:?}", e))?;
+
+		let origin = KeyOrigin {
+			fingerprint: self.origin.fingerprint,
+			path: self.origin.path.extend(child_path),
+		};
+
+		Ok((PublicKey::new(child.public_key), origin))
+	}
+}
+
+impl fmt::Display for PartyXpub {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}{}", self.origin, self.xpub)
+	}
+}
+
+impl FromStr for PartyXpub {
+	type Err = String;
+
+	/// Parses `[fingerprint/path]xpub`, the format exported by hardware wallets
+	fn from_str(key: &str) -> Result<Self, Self::Err> {
+		let (origin, xpub) = key
+			.split_once(']')
+			.ok_or_else(|| format!("Missing key origin: {}", key))?;
+		let origin = KeyOrigin::from_str(&format!("{}]", origin))?;
+		let xpub = Xpub::from_str(xpub).map_err(|e| format!("Invalid xpub: {:?}", e))?;
+
+		if xpub.depth as usize != origin.path.len() {
+			return Err(format!(
+				"Key origin path has {} levels but the xpub depth is {}",
+				origin.path.len(),
+				xpub.depth
+			));
+		}
+
+		Ok(Self { xpub, origin })
+	}
+}
+
+/// Collateral keys derived for a single loan
+#[derive(Debug, Clone)]
+pub struct DerivedCollateralKeys {
+	pub derivation_index: u32,
+	pub multisig: MultisigAddress,
+	pub origins: CollateralKeyOrigins,
+}
+
+/// The account xpubs of the three parties to a loan
+#[derive(Debug, Clone)]
+pub struct LoanKeyset {
+	pub borrower: PartyXpub,
+	pub lender: PartyXpub,
+	pub service: PartyXpub,
+}
+
+impl LoanKeyset {
+	pub fn new(borrower: PartyXpub, lender: PartyXpub, service: PartyXpub) -> Self {
+		Self {
+			borrower,
+			lender,
+			service,
+		}
+	}
+
+	/// Origins of the collateral keys, rejecting a keyset that does not derive the
+	/// keys of `multisig` at `derivation_index`
+	pub fn origins_for(
+		&self,
+		multisig: &MultisigAddress,
+		derivation_index: u32,
+	) -> Result<CollateralKeyOrigins, String> {
+		let derived = self.derive(derivation_index)?;
+		if derived.multisig.redeem_script() != multisig.redeem_script() {
+			return Err(format!(
+				"Keys at index {} do not match the collateral",
+				derivation_index
+			));
+		}
+		Ok(derived.origins)
+	}
+
+	pub fn xpubs(&self) -> Vec<PartyXpub> {
+		vec![
+			self.borrower.clone(),
+			self.lender.clone(),
+			self.service.clone(),
+		]
+	}
+
+	/// Derives all three collateral keys at the loan's child index
+	pub fn derive(&self, derivation_index: u32) -> Result<DerivedCollateralKeys, String> {
+		let (borrower_pubkey, borrower_origin) = self.borrower.derive(derivation_index)?;
+		let (lender_pubkey, lender_origin) = self.lender.derive(derivation_index)?;
+		let (service_pubkey, service_origin) = self.service.derive(derivation_index)?;
+
+		Ok(DerivedCollateralKeys {
+			derivation_index,
+			multisig: MultisigAddress::new(borrower_pubkey, lender_pubkey, service_pubkey),
+			origins: CollateralKeyOrigins {
+				borrower: Some(borrower_origin),
+				lender: Some(lender_origin),
+				service: Some(service_origin),
+			},
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::bip32::Xpriv;
+	use bitcoin::Network;
+
+	fn account(seed: u8) -> (Xpriv, PartyXpub) {
+		let secp = Secp256k1::new();
+		let master = Xpriv::new_master(Network::Testnet, &[seed; 32]).unwrap();
+		let path = DerivationPath::from_str("m/48'/1'/0'/2'").unwrap();
+		let account = master.derive_priv(&secp, &path).unwrap();
+
+		let party = PartyXpub::new(
+			Xpub::from_priv(&secp, &account),
+			KeyOrigin {
+				fingerprint: master.fingerprint(&secp),
+				path,
+			},
+		);
+		(master, party)
+	}
+
+	#[test]
+	fn test_derive_matches_private_derivation() {
+		let secp = Secp256k1::new();
+		let (master, party) = account(1);
+
+		let (pubkey, origin) = party.derive(7).unwrap();
+		let expected = master.derive_priv(&secp, &origin.path).unwrap();
+
+		assert_eq!(origin.path.to_string(), "m/48'/1'/0'/2'/0/7");
+		assert_eq!(
+			pubkey,
+			PublicKey::new(expected.private_key.public_key(&secp))
+		);
+	}
+
+	#[test]
+	fn test_fresh_address_per_loan() {
+		let keyset = LoanKeyset::new(account(1).1, account(2).1, account(3).1);
+
+		let first = keyset.derive(0).unwrap();
+		let second = keyset.derive(1).unwrap();
+
+		assert_ne!(
+			first.multisig.create_p2wsh_address(),
+			second.multisig.create_p2wsh_address()
+		);
+		assert_eq!(second.derivation_index, 1);
+	}
+
+	#[test]
+	fn test_party_xpub_round_trip() {
+		let (_, party) = account(4);
+		let parsed = PartyXpub::from_str(&party.to_string()).unwrap();
+
+		assert_eq!(parsed, party);
+		assert!(PartyXpub::from_str(&party.xpub.to_string()).is_err());
+	}
+}
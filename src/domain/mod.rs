@@ -1,6 +1,21 @@
+pub mod batch_funding_transaction;
+pub mod bsms;
+pub mod collateral_descriptor;
+pub mod collateral_policy;
+pub mod collateral_utxo;
+pub mod cpfp;
+pub mod fee_bump;
+pub mod finalize_psbt;
+pub mod forfeiture_transaction;
 pub mod funding_transaction;
 pub mod generate_address;
+pub mod key_derivation;
+pub mod nested_multisig;
+pub mod party_role;
+pub mod policy_compiler;
 pub mod redeeming_transaction;
 pub mod sign_psbt;
+pub mod taproot_address;
+pub mod threshold_multisig;
 
 pub use generate_address::MultisigAddress;
@@ -0,0 +1,239 @@
+use crate::constants::set_network;
+use crate::domain::collateral_descriptor::KeyOrigin;
+use crate::domain::MultisigAddress;
+use crate::utils::miniscript_compat::{
+	from_ms_script, to_ms_pubkey, to_ms_signature, witness_from_stack,
+};
+use bdk::bitcoin as ms_bitcoin;
+use bdk::miniscript::Descriptor;
+use bitcoin::psbt::Input;
+use bitcoin::{ecdsa, Address, PublicKey, ScriptBuf, Witness};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+/// A party to the collateral: either a single key or the party's own k-of-n
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PartySlot {
+	Single(PublicKey),
+	Threshold {
+		threshold: usize,
+		keys: Vec<PublicKey>,
+	},
+}
+
+impl PartySlot {
+	pub fn keys(&self) -> Vec<PublicKey> {
+		match self {
+			PartySlot::Single(key) => vec![*key],
+			PartySlot::Threshold { keys, .. } => keys.clone(),
+		}
+	}
+
+	/// Miniscript fragment for the slot: pk(K) or multi(k,...)
+	fn fragment(&self) -> Result<String, String> {
+		match self {
+			PartySlot::Single(key) => Ok(format!("pk({})", to_ms_pubkey(key)?)),
+			PartySlot::Threshold { threshold, keys } => {
+				if *threshold == 0 || *threshold > keys.len() {
+					return Err(format!(
+						"Invalid threshold {} for {} keys",
+						threshold,
+						keys.len()
+					));
+				}
+				let keys = keys
+					.iter()
+					.map(|key| to_ms_pubkey(key).map(|key| key.to_string()))
+					.collect::<Result<Vec<_>, _>>()?;
+				Ok(format!("multi({},{})", threshold, keys.join(",")))
+			}
+		}
+	}
+}
+
+/// 2-of-3 between borrower, lender and service where any slot can be a nested threshold,
+/// e.g. thresh(2,multi(2,A,B,C),a:pk(borrower),a:pk(service)) for an institutional lender
+#[derive(Debug, Clone)]
+pub struct NestedMultisig {
+	pub borrower: PartySlot,
+	pub lender: PartySlot,
+	pub service: PartySlot,
+}
+
+impl NestedMultisig {
+	pub fn new(borrower: PartySlot, lender: PartySlot, service: PartySlot) -> Self {
+		Self {
+			borrower,
+			lender,
+			service,
+		}
+	}
+
+	pub fn keys(&self) -> Vec<PublicKey> {
+		[&self.borrower, &self.lender, &self.service]
+			.iter()
+			.flat_map(|slot| slot.keys())
+			.collect()
+	}
+
+	pub fn descriptor(&self) -> Result<Descriptor<ms_bitcoin::PublicKey>, String> {
+		let descriptor = format!(
+			"wsh(thresh(2,{},a:{},a:{}))",
+			self.borrower.fragment()?,
+			self.lender.fragment()?,
+			self.service.fragment()?
+		);
+
+		Descriptor::from_str(&descriptor).map_err(|e| format!("Invalid nested multisig: {}", e))
+	}
+
+	pub fn witness_script(&self) -> Result<ScriptBuf, String> {
+		let script = self
+			.descriptor()?
+			.explicit_script()
+			.map_err(|e| format!("Error deriving witness script: {}", e))?;
+		Ok(from_ms_script(script))
+	}
+
+	pub fn create_p2wsh_address(&self) -> Result<Address, String> {
+		Ok(Address::p2wsh(&self.witness_script()?, set_network()))
+	}
+
+	/// Adds the witness script and the BIP-32 derivations of every key with a known
+	/// origin, so each signer, including the lender's internal signers, can find its key
+	pub fn annotate_psbt_input(
+		&self,
+		input: &mut Input,
+		origins: &BTreeMap<PublicKey, KeyOrigin>,
+	) -> Result<(), String> {
+		input.witness_script = Some(self.witness_script()?);
+
+		for key in self.keys() {
+			if let Some(origin) = origins.get(&key) {
+				input
+					.bip32_derivation
+					.insert(key.inner, (origin.fingerprint, origin.path.clone()));
+			}
+		}
+		Ok(())
+	}
+
+	/// Builds the input witness from the signatures collected so far; fails until
+	/// two slots, including any nested threshold, are satisfied
+	pub fn witness(
+		&self,
+		signatures: &BTreeMap<PublicKey, ecdsa::Signature>,
+	) -> Result<Witness, String> {
+		let mut sigs = HashMap::new();
+		for key in self.keys() {
+			if let Some(signature) = signatures.get(&key) {
+				sigs.insert(to_ms_pubkey(&key)?, to_ms_signature(signature)?);
+			}
+		}
+
+		let (stack, _) = self
+			.descriptor()?
+			.get_satisfaction(sigs)
+			.map_err(|e| format!("Unable to satisfy nested multisig: {}", e))?;
+
+		Ok(witness_from_stack(stack))
+	}
+
+	/// Sets the final witness of a PSBT input from its partial signatures
+	pub fn finalize_psbt_input(&self, input: &mut Input) -> Result<(), String> {
+		input.final_script_witness = Some(self.witness(&input.partial_sigs)?);
+		Ok(())
+	}
+}
+
+impl From<&MultisigAddress> for NestedMultisig {
+	fn from(multisig: &MultisigAddress) -> Self {
+		Self::new(
+			PartySlot::Single(multisig.borrower_pubkey),
+			PartySlot::Single(multisig.lender_pubkey),
+			PartySlot::Single(multisig.service_pubkey),
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+	use bitcoin::AddressType;
+
+	fn secret_key(byte: u8) -> SecretKey {
+		SecretKey::from_slice(&[byte; 32]).unwrap()
+	}
+
+	fn pubkey(byte: u8) -> PublicKey {
+		PublicKey::new(secret_key(byte).public_key(&Secp256k1::new()))
+	}
+
+	fn sign(byte: u8) -> ecdsa::Signature {
+		let message = Message::from_digest([7u8; 32]);
+		ecdsa::Signature::sighash_all(Secp256k1::new().sign_ecdsa(&message, &secret_key(byte)))
+	}
+
+	fn institutional() -> NestedMultisig {
+		NestedMultisig::new(
+			PartySlot::Single(pubkey(1)),
+			PartySlot::Threshold {
+				threshold: 2,
+				keys: vec![pubkey(2), pubkey(3), pubkey(4)],
+			},
+			PartySlot::Single(pubkey(5)),
+		)
+	}
+
+	#[test]
+	fn test_create_p2wsh_address() {
+		let address = institutional().create_p2wsh_address().unwrap();
+
+		assert_eq!(address.address_type(), Some(AddressType::P2wsh));
+		assert_eq!(institutional().keys().len(), 5);
+	}
+
+	#[test]
+	fn test_nested_branch_satisfaction() {
+		let multisig = institutional();
+
+		let mut signatures = BTreeMap::new();
+		signatures.insert(pubkey(1), sign(1));
+		signatures.insert(pubkey(2), sign(2));
+		assert!(multisig.witness(&signatures).is_err());
+
+		signatures.insert(pubkey(4), sign(4));
+		let witness = multisig.witness(&signatures).unwrap();
+		assert_eq!(
+			witness.last().unwrap(),
+			multisig.witness_script().unwrap().as_bytes()
+		);
+	}
+
+	#[test]
+	fn test_annotate_and_finalize_psbt_input() {
+		let multisig = institutional();
+		let origin = KeyOrigin::from_str("[c258d2e4/48'/1'/0'/2'/0/0]").unwrap();
+		let origins = BTreeMap::from([(pubkey(3), origin.clone())]);
+
+		let mut input = Input::default();
+		multisig.annotate_psbt_input(&mut input, &origins).unwrap();
+
+		assert_eq!(
+			input.witness_script,
+			Some(multisig.witness_script().unwrap())
+		);
+		assert_eq!(
+			input.bip32_derivation.get(&pubkey(3).inner),
+			Some(&(origin.fingerprint, origin.path))
+		);
+
+		input.partial_sigs.insert(pubkey(3), sign(3));
+		input.partial_sigs.insert(pubkey(4), sign(4));
+		input.partial_sigs.insert(pubkey(5), sign(5));
+		multisig.finalize_psbt_input(&mut input).unwrap();
+
+		assert!(input.final_script_witness.is_some());
+	}
+}
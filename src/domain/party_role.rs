@@ -0,0 +1,8 @@
+/// A party to a loan, for errors and records that concern one party's key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "party_role", rename_all = "lowercase")]
+pub enum PartyRole {
+	Borrower,
+	Lender,
+	Service,
+}
@@ -0,0 +1,364 @@
+use crate::constants::set_network;
+use crate::domain::MultisigAddress;
+use crate::utils::miniscript_compat::{
+	from_ms_address, from_ms_script, to_ms_network, to_ms_pubkey,
+};
+use bdk::bitcoin as ms_bitcoin;
+use bdk::miniscript::policy::Concrete;
+use bdk::miniscript::{Descriptor, MiniscriptKey, Segwitv0, Translator};
+use bitcoin::{Address, PublicKey, ScriptBuf};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+// BIP-341 NUMS point, used as the taproot internal key when no party key can be extracted
+const UNSPENDABLE_KEY: &str = "0250929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DescriptorType {
+	Wsh,
+	Tr,
+}
+
+/// Structured spending policy, e.g.
+/// `{"type": "multi", "threshold": 3, "parties": ["borrower", "lender", "service", "backup_agent", "lender_2"]}`
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PolicyTemplate {
+	Key {
+		party: String,
+	},
+	Multi {
+		threshold: usize,
+		parties: Vec<String>,
+	},
+	Thresh {
+		threshold: usize,
+		policies: Vec<PolicyTemplate>,
+	},
+	And {
+		policies: Vec<PolicyTemplate>,
+	},
+	Or {
+		branches: Vec<WeightedPolicy>,
+	},
+	/// absolute block height (CLTV)
+	After {
+		height: u32,
+	},
+	/// relative number of blocks (CSV)
+	Older {
+		blocks: u32,
+	},
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WeightedPolicy {
+	#[serde(default = "default_weight")]
+	pub weight: usize,
+	pub policy: PolicyTemplate,
+}
+
+fn default_weight() -> usize {
+	1
+}
+
+impl PolicyTemplate {
+	/// Renders the template in the miniscript policy language
+	pub fn to_policy_string(&self) -> Result<String, String> {
+		let join = |policies: &[PolicyTemplate]| -> Result<String, String> {
+			Ok(policies
+				.iter()
+				.map(|policy| policy.to_policy_string())
+				.collect::<Result<Vec<String>, String>>()?
+				.join(","))
+		};
+
+		match self {
+			PolicyTemplate::Key { party } => Ok(format!("pk({})", party)),
+			PolicyTemplate::Multi { threshold, parties } => {
+				let keys = parties
+					.iter()
+					.map(|party| format!("pk({})", party))
+					.collect::<Vec<String>>();
+				Ok(format!("thresh({},{})", threshold, keys.join(",")))
+			}
+			PolicyTemplate::Thresh {
+				threshold,
+				policies,
+			} => Ok(format!("thresh({},{})", threshold, join(policies)?)),
+			PolicyTemplate::And { policies } => {
+				if policies.len() != 2 {
+					return Err("An 'and' policy takes exactly two sub-policies".to_string());
+				}
+				Ok(format!("and({})", join(policies)?))
+			}
+			PolicyTemplate::Or { branches } => {
+				if branches.len() != 2 {
+					return Err("An 'or' policy takes exactly two branches".to_string());
+				}
+				let branches = branches
+					.iter()
+					.map(|branch| {
+						Ok(format!(
+							"{}@{}",
+							branch.weight,
+							branch.policy.to_policy_string()?
+						))
+					})
+					.collect::<Result<Vec<String>, String>>()?;
+				Ok(format!("or({})", branches.join(",")))
+			}
+			PolicyTemplate::After { height } => Ok(format!("after({})", height)),
+			PolicyTemplate::Older { blocks } => Ok(format!("older({})", blocks)),
+		}
+	}
+}
+
+/// The parties a custody policy can refer to by name
+#[derive(Debug, Clone, Default)]
+pub struct CustodyParties(BTreeMap<String, PublicKey>);
+
+impl CustodyParties {
+	pub fn with_party(mut self, name: &str, pubkey: PublicKey) -> Self {
+		self.0.insert(name.to_string(), pubkey);
+		self
+	}
+
+	pub fn get(&self, name: &str) -> Option<&PublicKey> {
+		self.0.get(name)
+	}
+}
+
+impl From<&MultisigAddress> for CustodyParties {
+	fn from(multisig: &MultisigAddress) -> Self {
+		CustodyParties::default()
+			.with_party("borrower", multisig.borrower_pubkey)
+			.with_party("lender", multisig.lender_pubkey)
+			.with_party("service", multisig.service_pubkey)
+	}
+}
+
+struct PartyTranslator<'a>(&'a CustodyParties);
+
+impl Translator<String, ms_bitcoin::PublicKey, String> for PartyTranslator<'_> {
+	fn pk(&mut self, party: &String) -> Result<ms_bitcoin::PublicKey, String> {
+		let pubkey = self
+			.0
+			.get(party)
+			.ok_or_else(|| format!("Unknown party in policy: {}", party))?;
+		to_ms_pubkey(pubkey)
+	}
+
+	fn sha256(
+		&mut self,
+		_: &<String as MiniscriptKey>::Sha256,
+	) -> Result<<ms_bitcoin::PublicKey as MiniscriptKey>::Sha256, String> {
+		Err("Hash locks are not supported in custody policies".to_string())
+	}
+
+	fn hash256(
+		&mut self,
+		_: &<String as MiniscriptKey>::Hash256,
+	) -> Result<<ms_bitcoin::PublicKey as MiniscriptKey>::Hash256, String> {
+		Err("Hash locks are not supported in custody policies".to_string())
+	}
+
+	fn ripemd160(
+		&mut self,
+		_: &<String as MiniscriptKey>::Ripemd160,
+	) -> Result<<ms_bitcoin::PublicKey as MiniscriptKey>::Ripemd160, String> {
+		Err("Hash locks are not supported in custody policies".to_string())
+	}
+
+	fn hash160(
+		&mut self,
+		_: &<String as MiniscriptKey>::Hash160,
+	) -> Result<<ms_bitcoin::PublicKey as MiniscriptKey>::Hash160, String> {
+		Err("Hash locks are not supported in custody policies".to_string())
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct CompiledPolicy {
+	/// output descriptor, including its checksum
+	pub descriptor: String,
+	pub address: Address,
+	pub script_pubkey: ScriptBuf,
+	/// the witness script for wsh(), or every tapscript leaf for tr()
+	pub scripts: Vec<ScriptBuf>,
+	/// worst-case weight of the witness needed to spend the output
+	pub max_weight_to_satisfy: usize,
+}
+
+/// A custody contract over named parties, written in the miniscript policy language
+#[derive(Debug, Clone)]
+pub struct CustodyPolicy {
+	parties: CustodyParties,
+	policy: Concrete<String>,
+}
+
+impl CustodyPolicy {
+	/// Parses a policy such as `thresh(3,pk(borrower),pk(lender),pk(service),pk(backup_agent),pk(lender_2))`
+	pub fn from_policy_str(parties: CustodyParties, policy: &str) -> Result<Self, String> {
+		let policy = Concrete::<String>::from_str(policy)
+			.map_err(|e| format!("Invalid spending policy: {}", e))?;
+		Ok(Self { parties, policy })
+	}
+
+	/// Parses a JSON `PolicyTemplate`
+	pub fn from_template_json(parties: CustodyParties, template: &str) -> Result<Self, String> {
+		let template: PolicyTemplate = serde_json::from_str(template)
+			.map_err(|e| format!("Invalid policy template: {}", e))?;
+		Self::from_policy_str(parties, &template.to_policy_string()?)
+	}
+
+	fn keyed_policy(&self) -> Result<Concrete<ms_bitcoin::PublicKey>, String> {
+		self.policy
+			.translate_pk(&mut PartyTranslator(&self.parties))
+	}
+
+	pub fn descriptor(
+		&self,
+		descriptor_type: DescriptorType,
+	) -> Result<Descriptor<ms_bitcoin::PublicKey>, String> {
+		let policy = self.keyed_policy()?;
+
+		match descriptor_type {
+			DescriptorType::Wsh => {
+				let miniscript = policy
+					.compile::<Segwitv0>()
+					.map_err(|e| format!("Error compiling policy: {}", e))?;
+				Descriptor::new_wsh(miniscript)
+					.map_err(|e| format!("Invalid wsh descriptor: {}", e))
+			}
+			DescriptorType::Tr => {
+				let unspendable_key = ms_bitcoin::PublicKey::from_str(UNSPENDABLE_KEY)
+					.map_err(|e| format!("Invalid unspendable key: {:?}", e))?;
+				policy
+					.compile_tr(Some(unspendable_key))
+					.map_err(|e| format!("Error compiling policy: {}", e))
+			}
+		}
+	}
+
+	pub fn compile(&self, descriptor_type: DescriptorType) -> Result<CompiledPolicy, String> {
+		let descriptor = self.descriptor(descriptor_type)?;
+		let network = set_network();
+
+		let ms_address = descriptor
+			.address(to_ms_network(network))
+			.map_err(|e| format!("Error deriving address: {}", e))?;
+
+		let scripts = match &descriptor {
+			Descriptor::Tr(tr) => tr
+				.iter_scripts()
+				.map(|(_, leaf)| from_ms_script(leaf.encode()))
+				.collect(),
+			_ => {
+				vec![from_ms_script(descriptor.explicit_script().map_err(
+					|e| format!("Error deriving witness script: {}", e),
+				)?)]
+			}
+		};
+
+		let max_weight_to_satisfy = descriptor
+			.max_weight_to_satisfy()
+			.map_err(|e| format!("Error computing satisfaction weight: {}", e))?;
+
+		Ok(CompiledPolicy {
+			descriptor: descriptor.to_string(),
+			address: from_ms_address(&ms_address, network)?,
+			script_pubkey: from_ms_script(descriptor.script_pubkey()),
+			scripts,
+			max_weight_to_satisfy,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use bitcoin::AddressType;
+
+	fn parties() -> CustodyParties {
+		let secp = Secp256k1::new();
+		let key = |byte: u8| {
+			PublicKey::new(
+				SecretKey::from_slice(&[byte; 32])
+					.unwrap()
+					.public_key(&secp),
+			)
+		};
+		let multisig = MultisigAddress::new(key(1), key(2), key(3));
+
+		CustodyParties::from(&multisig)
+			.with_party("backup_agent", key(4))
+			.with_party("lender_2", key(5))
+	}
+
+	#[test]
+	fn test_compile_three_of_five_wsh() {
+		let template = r#"{
+			"type": "multi",
+			"threshold": 3,
+			"parties": ["borrower", "lender", "service", "backup_agent", "lender_2"]
+		}"#;
+		let policy = CustodyPolicy::from_template_json(parties(), template).unwrap();
+		let compiled = policy.compile(DescriptorType::Wsh).unwrap();
+
+		assert!(compiled.descriptor.starts_with("wsh(multi(3,"));
+		assert!(compiled.descriptor.contains('#'));
+		assert_eq!(compiled.address.address_type(), Some(AddressType::P2wsh));
+		assert_eq!(compiled.scripts.len(), 1);
+		assert!(compiled.max_weight_to_satisfy > 0);
+	}
+
+	#[test]
+	fn test_compile_timelocked_tr() {
+		let policy = CustodyPolicy::from_policy_str(
+			parties(),
+			"or(9@thresh(2,pk(borrower),pk(lender),pk(service)),1@and(pk(backup_agent),older(52560)))",
+		)
+		.unwrap();
+		let compiled = policy.compile(DescriptorType::Tr).unwrap();
+
+		assert!(compiled.descriptor.starts_with("tr("));
+		assert_eq!(compiled.address.address_type(), Some(AddressType::P2tr));
+		assert!(!compiled.scripts.is_empty());
+	}
+
+	#[test]
+	fn test_unknown_party() {
+		let policy =
+			CustodyPolicy::from_policy_str(parties(), "and(pk(borrower),pk(stranger))").unwrap();
+
+		assert!(policy.compile(DescriptorType::Wsh).is_err());
+	}
+
+	#[test]
+	fn test_template_to_policy_string() {
+		let template = PolicyTemplate::Or {
+			branches: vec![
+				WeightedPolicy {
+					weight: 99,
+					policy: PolicyTemplate::Multi {
+						threshold: 2,
+						parties: vec!["borrower".to_string(), "lender".to_string()],
+					},
+				},
+				WeightedPolicy {
+					weight: 1,
+					policy: PolicyTemplate::After { height: 900_000 },
+				},
+			],
+		};
+
+		assert_eq!(
+			template.to_policy_string().unwrap(),
+			"or(99@thresh(2,pk(borrower),pk(lender)),1@after(900000))"
+		);
+	}
+}
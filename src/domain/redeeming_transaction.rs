@@ -1,140 +1,355 @@
-use crate::utils::bitcoind_rpc::get_transaction_output;
-use crate::utils::get_feerate::get_mempool_feerate;
-use crate::utils::transaction_utils::{get_outpoints_total, Txn};
-use bitcoin::absolute::LockTime;
-use bitcoin::blockdata::transaction::OutPoint;
+use crate::config::{FeeSettings, TxnKind};
+use crate::domain::collateral_descriptor::CollateralKeyOrigins;
+use crate::domain::collateral_utxo::CollateralUtxo;
+use crate::domain::fee_bump::bump_fee;
+use crate::domain::key_derivation::{LoanKeyset, PartyXpub};
+use crate::domain::MultisigAddress;
+use crate::repository::collateral::get_collateral_address;
+use crate::repository::collateral_utxo::get_unspent_utxos;
+use crate::repository::transaction_replacement::latest_replacement;
+use crate::utils::bitcoind_rpc::get_transaction;
+use crate::utils::get_feerate::FeeTarget;
+use crate::utils::transaction_utils::{
+	locktime_at_tip, order_outputs, recover_output_map, ConstructedTxn, OutputOrdering, Txn,
+};
+use crate::utils::weight_estimator::InputWeight;
 use bitcoin::psbt::{Input, Output};
 use bitcoin::transaction::Version;
-use bitcoin::{Psbt, Transaction, TxOut};
+use bitcoin::{Amount, FeeRate, Psbt, Transaction, TxOut, Txid};
+use bitcoincore_rpc::Client;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
 use std::collections::BTreeMap;
 
 #[derive(Debug, Clone)]
 pub struct RedeemingTxnPSBT {
 	pub receiving_address: String,
-	pub amount: f64,
-	pub inputs: Vec<OutPoint>,
+	pub amount: Amount,
+	/// every unspent output at the collateral address, all of which are spent
+	pub utxos: Vec<CollateralUtxo>,
 	// we might charge a fee of 0.025% on the redemption amount
 	pub change_address: String,
+	/// the 2-of-3 whose P2WSH outputs are being spent
+	pub collateral: MultisigAddress,
+	/// overrides the configured default for redemptions
+	pub fee_target: Option<FeeTarget>,
+	pub output_ordering: OutputOrdering,
+	/// height the anti-fee-sniping locktime is set from, bitcoind's tip when None
+	pub tip_height: Option<u32>,
+	/// where each collateral key was derived from, so wallets can find the key they sign with
+	pub origins: CollateralKeyOrigins,
+	/// the parties' account xpubs, added to the PSBT's global xpubs
+	pub xpubs: Vec<PartyXpub>,
+	/// transactions that created the UTXOs, fetched from bitcoind when missing
+	pub previous_txns: Vec<Transaction>,
 }
 
 impl RedeemingTxnPSBT {
 	pub fn new(
 		receiving_address: String,
-		amount: f64,
-		inputs: Vec<OutPoint>,
+		amount: Amount,
+		utxos: Vec<CollateralUtxo>,
 		change_address: String,
+		collateral: MultisigAddress,
 	) -> Self {
 		Self {
 			receiving_address,
 			amount,
-			inputs,
+			utxos,
 			change_address,
+			collateral,
+			fee_target: None,
+			output_ordering: OutputOrdering::default(),
+			tip_height: None,
+			origins: CollateralKeyOrigins::default(),
+			xpubs: Vec::new(),
+			previous_txns: Vec::new(),
 		}
 	}
 
-	pub fn construct_trxn(&self) -> Result<Transaction, String> {
-		let input_total;
-		match get_outpoints_total(&self.inputs, None) {
-			Ok(amount) => {
-				if amount < self.amount {
-					return Err(
-						"The given UTXO set do not have enough value for this transaction"
-							.to_string(),
-					);
-				} else {
-					input_total = amount;
-				}
-			}
-			Err(error) => return Err(format!("{:?}", error)),
-		};
+	/// Spends every unspent output recorded at the loan's collateral address, with the
+	/// collateral script and key origins stored for the loan
+	pub async fn for_loan(
+		pool: &PgPool,
+		loan_request_id: Uuid,
+		receiving_address: String,
+		amount: Amount,
+		change_address: String,
+	) -> Result<Self, String> {
+		let record = get_collateral_address(pool, loan_request_id)
+			.await
+			.map_err(|e| format!("Error loading collateral: {:?}", e))?
+			.ok_or_else(|| format!("Loan {} has no collateral", loan_request_id))?;
+		let utxos = get_unspent_utxos(pool, loan_request_id)
+			.await
+			.map_err(|e| format!("Error loading collateral UTXOs: {:?}", e))?;
+		if utxos.is_empty() {
+			return Err(format!(
+				"Loan {} has no unspent collateral",
+				loan_request_id
+			));
+		}
 
-		let tx_inputs = RedeemingTxnPSBT::calculate_inputs(&self.inputs);
+		Ok(Self::new(
+			receiving_address,
+			amount,
+			utxos,
+			change_address,
+			record.multisig,
+		)
+		.with_key_origins(record.origins))
+	}
 
-		let initial_output = self.calculate_outputs(input_total, 0.0)?;
-		let fee_rates = get_mempool_feerate().unwrap();
-		let fees = RedeemingTxnPSBT::calculate_fees(initial_output, tx_inputs.clone(), &fee_rates)?;
+	pub fn with_fee_target(mut self, fee_target: FeeTarget) -> Self {
+		self.fee_target = Some(fee_target);
+		self
+	}
 
-		let tx_outputs = match self.calculate_outputs(input_total, fees) {
-			Ok(value) => value,
-			Err(error) => return Err(format!("{:?}", error)),
-		};
+	pub fn with_output_ordering(mut self, output_ordering: OutputOrdering) -> Self {
+		self.output_ordering = output_ordering;
+		self
+	}
 
-		Ok(Transaction {
-			version: Version::TWO,
-			lock_time: LockTime::ZERO,
-			input: tx_inputs,
-			output: tx_outputs,
-		})
+	/// Sets the locktime from this height instead of bitcoind's tip
+	pub fn with_tip_height(mut self, tip_height: u32) -> Self {
+		self.tip_height = Some(tip_height);
+		self
 	}
 
-	fn create_psbt_inputs(&self) -> Result<Vec<Input>, String> {
-		let mut inputs = Vec::new();
-
-		for input in &self.inputs {
-			let (segwit_tx_status, tx_outpout, txn) =
-				get_transaction_output(input.txid, input.vout, None)
-					.expect("Error getting transaction details");
-			if segwit_tx_status {
-				inputs.push(Input {
-					witness_utxo: Some(tx_outpout.unwrap()),
-					..Default::default()
-				});
-			} else {
-				inputs.push(Input {
-					non_witness_utxo: Some(txn),
-					..Default::default()
-				});
-			}
-		}
+	/// Supplies transactions that created the UTXOs, so they need not be fetched
+	pub fn with_previous_txns(mut self, previous_txns: Vec<Transaction>) -> Self {
+		self.previous_txns = previous_txns;
+		self
+	}
 
-		Ok(inputs)
+	pub fn with_key_origins(mut self, origins: CollateralKeyOrigins) -> Self {
+		self.origins = origins;
+		self
 	}
 
-	fn create_psbt_outputs(&self) -> Result<Vec<Output>, String> {
-		let (receiving_spkh, change_spkh) =
+	/// Records the origins of keys derived from the parties' account xpubs, rejecting a
+	/// keyset that does not derive the collateral's keys at `derivation_index`
+	pub fn with_keyset(
+		mut self,
+		keyset: &LoanKeyset,
+		derivation_index: u32,
+	) -> Result<Self, String> {
+		self.origins = keyset.origins_for(&self.collateral, derivation_index)?;
+		self.xpubs = keyset.xpubs();
+		Ok(self)
+	}
+
+	fn input_weights(&self) -> Vec<InputWeight> {
+		let weight = InputWeight::p2wsh_multisig(2, &self.collateral.redeem_script());
+		vec![weight; self.utxos.len()]
+	}
+
+	pub fn construct_trxn(
+		&self,
+		client: Option<&Client>,
+		fee_settings: &FeeSettings,
+	) -> Result<ConstructedTxn, String> {
+		if self.utxos.is_empty() {
+			return Err("There is no collateral to redeem".to_string());
+		}
+		let input_total = self.input_total()?;
+		if input_total < self.amount {
+			return Err(
+				"The given UTXO set do not have enough value for this transaction".to_string(),
+			);
+		}
+
+		let outpoints: Vec<_> = self.utxos.iter().map(|utxo| utxo.outpoint).collect();
+		let tx_inputs = RedeemingTxnPSBT::calculate_inputs(&outpoints);
+
+		let fee_rate = self
+			.fee_target
+			.unwrap_or(fee_settings.target(TxnKind::Redemption))
+			.fee_rate()?;
+		let fee_without_change = RedeemingTxnPSBT::calculate_fees(
+			self.calculate_outputs(None)?,
+			tx_inputs.clone(),
+			&self.input_weights(),
+			fee_rate,
+		)?;
+		let fee_with_change = RedeemingTxnPSBT::calculate_fees(
+			self.calculate_outputs(Some(Amount::ZERO))?,
+			tx_inputs.clone(),
+			&self.input_weights(),
+			fee_rate,
+		)?;
+
+		let (_, change_spkh) =
 			RedeemingTxnPSBT::derive_script_pubkeys(&self.receiving_address, &self.change_address)?;
+		let (fee, change) = RedeemingTxnPSBT::apply_change_policy(
+			self.amount,
+			input_total,
+			fee_without_change,
+			fee_with_change,
+			&change_spkh,
+		)?;
+		fee_settings.check_fee(fee, self.amount)?;
 
-		Ok(vec![
-			Output {
-				redeem_script: Some(receiving_spkh),
-				..Default::default()
-			},
-			Output {
-				redeem_script: Some(change_spkh),
-				..Default::default()
+		let (tx_outputs, output_map) = order_outputs(
+			self.calculate_outputs(change.change_amount())?,
+			self.output_ordering,
+		);
+
+		Ok(ConstructedTxn {
+			transaction: Transaction {
+				version: Version::TWO,
+				lock_time: locktime_at_tip(self.tip_height, client)?,
+				input: tx_inputs,
+				output: tx_outputs,
 			},
-		])
+			fee,
+			change,
+			output_map,
+		})
+	}
+
+	fn input_total(&self) -> Result<Amount, String> {
+		self.utxos
+			.iter()
+			.try_fold(Amount::ZERO, |total, utxo| total.checked_add(utxo.amount))
+			.ok_or_else(|| "Collateral total overflows".to_string())
+	}
+
+	fn spent_outputs(&self) -> Vec<TxOut> {
+		let script_pubkey = self.collateral.create_p2wsh_address().script_pubkey();
+
+		self.utxos
+			.iter()
+			.map(|utxo| TxOut {
+				value: utxo.amount,
+				script_pubkey: script_pubkey.clone(),
+			})
+			.collect()
 	}
 
-	fn calculate_outputs(&self, input_total: f64, fees: f64) -> Result<Vec<TxOut>, String> {
+	fn create_psbt_inputs(&self, client: Option<&Client>) -> Result<Vec<Input>, String> {
+		self.collateral
+			.psbt_inputs(&self.utxos, &self.previous_txns, &self.origins, client)
+	}
+
+	/// Only an output back to the collateral address, such as the change of a partial
+	/// redemption, carries metadata; other outputs are not the parties' to describe
+	fn create_psbt_outputs(&self, transaction: &Transaction) -> Vec<Output> {
+		let collateral_spkh = self.collateral.create_p2wsh_address().script_pubkey();
+
+		transaction
+			.output
+			.iter()
+			.map(|txout| {
+				if txout.script_pubkey == collateral_spkh {
+					Output {
+						witness_script: Some(self.collateral.redeem_script()),
+						bip32_derivation: self.collateral.key_derivations(&self.origins),
+						..Default::default()
+					}
+				} else {
+					Output::default()
+				}
+			})
+			.collect()
+	}
+
+	fn calculate_outputs(&self, change: Option<Amount>) -> Result<Vec<TxOut>, String> {
 		let (receiving_spkh, change_spkh) =
 			RedeemingTxnPSBT::derive_script_pubkeys(&self.receiving_address, &self.change_address)?;
 
-		let (amount_in_hex, change_amount_hex) =
-			RedeemingTxnPSBT::amount_in_hex(self.amount, fees, input_total)?;
-
 		let mut tx_outputs = Vec::new();
 		let output1 = TxOut {
-			value: amount_in_hex,
+			value: self.amount,
 			script_pubkey: receiving_spkh,
 		};
 		tx_outputs.push(output1);
-		let output2 = TxOut {
-			value: change_amount_hex,
-			script_pubkey: change_spkh,
-		};
-		tx_outputs.push(output2);
+		if let Some(change_amount) = change {
+			let output2 = TxOut {
+				value: change_amount,
+				script_pubkey: change_spkh,
+			};
+			tx_outputs.push(output2);
+		}
 		Ok(tx_outputs)
 	}
 
-	pub fn create_psbt(&self) -> Result<Psbt, String> {
-		let unsigned_txn = self.construct_trxn()?;
-		let inputs = self.create_psbt_inputs()?;
-		let outputs = self.create_psbt_outputs()?;
+	pub fn create_psbt(
+		&self,
+		client: Option<&Client>,
+		fee_settings: &FeeSettings,
+	) -> Result<Psbt, String> {
+		let constructed = self.construct_trxn(client, fee_settings)?;
+		self.psbt_from_constructed(constructed, client)
+	}
+
+	/// Rebuilds the stuck redemption `txid`, or its latest recorded replacement, at
+	/// `fee_rate`, paying the extra fee out of the change, and returns the replacement as a
+	/// PSBT for the parties to sign again
+	pub async fn bump_fee(
+		&self,
+		pool: &PgPool,
+		txid: Txid,
+		fee_rate: FeeRate,
+		client: Option<&Client>,
+		fee_settings: &FeeSettings,
+	) -> Result<Psbt, String> {
+		let txid = latest_replacement(pool, txid)
+			.await
+			.map_err(|e| format!("Error loading replacements of {}: {:?}", txid, e))?;
+		let original = get_transaction(txid, client)
+			.map_err(|e| format!("Error fetching transaction {}: {:?}", txid, e))?;
+		let spends_collateral = original.input.len() == self.utxos.len()
+			&& original
+				.input
+				.iter()
+				.zip(&self.utxos)
+				.all(|(input, utxo)| input.previous_output == utxo.outpoint);
+		if !spends_collateral {
+			return Err(format!(
+				"Transaction {} does not spend the collateral",
+				txid
+			));
+		}
+
+		let (receiving_spkh, change_spkh) =
+			RedeemingTxnPSBT::derive_script_pubkeys(&self.receiving_address, &self.change_address)?;
+		let output_map = recover_output_map(&original, &[receiving_spkh, change_spkh.clone()])?;
+		let replacement = bump_fee(
+			&original,
+			&output_map,
+			&self.spent_outputs(),
+			&self.input_weights(),
+			&change_spkh,
+			fee_rate,
+		)?;
+		fee_settings.check_fee(replacement.fee, self.amount)?;
+
+		self.psbt_from_constructed(replacement, client)
+	}
+
+	fn psbt_from_constructed(
+		&self,
+		constructed: ConstructedTxn,
+		client: Option<&Client>,
+	) -> Result<Psbt, String> {
+		let unsigned_txn = constructed.transaction;
+		let inputs = self.create_psbt_inputs(client)?;
+		let outputs = self.create_psbt_outputs(&unsigned_txn);
+		let xpub = self
+			.xpubs
+			.iter()
+			.map(|party| {
+				(
+					party.xpub,
+					(party.origin.fingerprint, party.origin.path.clone()),
+				)
+			})
+			.collect();
 
 		Ok(Psbt {
 			unsigned_tx: unsigned_txn,
-			xpub: Default::default(),
+			xpub,
 			version: 0,
 			proprietary: BTreeMap::new(),
 			unknown: BTreeMap::new(),
@@ -149,30 +364,112 @@ impl Txn for RedeemingTxnPSBT {}
 #[cfg(test)]
 mod tests {
 	use super::RedeemingTxnPSBT;
+	use crate::config::FeeSettings;
+	use crate::domain::collateral_descriptor::{CollateralKeyOrigins, KeyOrigin};
+	use crate::domain::collateral_utxo::CollateralUtxo;
+	use crate::domain::MultisigAddress;
+	use crate::utils::get_feerate::FeeTarget;
 	use crate::utils::transaction_utils::convert_txn_hex_to_base64;
-	use bitcoin::{blockdata::transaction::OutPoint, Txid};
+	use bitcoin::absolute::LockTime;
+	use bitcoin::transaction::Version;
+	use bitcoin::{blockdata::transaction::OutPoint, Amount, PublicKey, Transaction, TxOut};
 	use std::str::FromStr;
 
+	fn collateral() -> MultisigAddress {
+		MultisigAddress::new(
+			PublicKey::from_str(
+				"02f0eaa04e609b0044ef1fe09a350dc4b744a5a8604a6fa77bc9bf6443ea50739f",
+			)
+			.unwrap(),
+			PublicKey::from_str(
+				"037c60db011a840523f216e7198054ef071c5acd3d4b466cf2658b7faf30c11e33",
+			)
+			.unwrap(),
+			PublicKey::from_str(
+				"02ca49f36d3de1e135e033052611dd0873af55b57f07d5d0d1090ceb267ac34e6b",
+			)
+			.unwrap(),
+		)
+	}
+
 	fn redeem_txn() -> RedeemingTxnPSBT {
-		let tx_input = vec![OutPoint::new(
-			Txid::from_str("a39122aefe9563c17426bd468d2b650467475ea4c3bb538d0091d2552f6468d3")
-				.unwrap(),
-			1,
-		)];
+		let collateral_spkh = collateral().create_p2wsh_address().script_pubkey();
+		// the initial deposit and a later top-up, paid by the same transaction here
+		let previous_txn = Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: Vec::new(),
+			output: [10_000, 150_000_000, 50_000_000]
+				.into_iter()
+				.map(|sats| TxOut {
+					value: Amount::from_sat(sats),
+					script_pubkey: collateral_spkh.clone(),
+				})
+				.collect(),
+		};
+		let txid = previous_txn.txid();
+		let tx_input = vec![
+			CollateralUtxo {
+				outpoint: OutPoint::new(txid, 1),
+				amount: Amount::from_sat(150_000_000),
+				height: Some(100),
+			},
+			CollateralUtxo {
+				outpoint: OutPoint::new(txid, 2),
+				amount: Amount::from_sat(50_000_000),
+				height: None,
+			},
+		];
 
 		RedeemingTxnPSBT::new(
 			"bcrt1qeygjhsgt5sumtlqnyfu58harh3737z96m0zmqv".to_string(),
-			1.8,
+			Amount::from_sat(180_000_000),
 			tx_input,
 			"bcrt1q8ucxfsyajsdghspzpn8mx8m7gyfv0c8jfn60m7".to_string(),
+			collateral(),
 		)
+		.with_previous_txns(vec![previous_txn])
+	}
+
+	#[test]
+	fn test_psbt_carries_witness_script() {
+		let redeem_txn = redeem_txn()
+			.with_fee_target(FeeTarget::SatPerVb(2))
+			.with_tip_height(800_000)
+			.with_key_origins(CollateralKeyOrigins {
+				borrower: Some(KeyOrigin::from_str("[c258d2e4/48'/1'/0'/2'/0/3]").unwrap()),
+				lender: None,
+				service: None,
+			});
+
+		let psbt = redeem_txn
+			.create_psbt(None, &FeeSettings::default())
+			.unwrap();
+
+		for input in &psbt.inputs {
+			assert_eq!(
+				input.witness_script,
+				Some(redeem_txn.collateral.redeem_script())
+			);
+			assert_eq!(
+				input.bip32_derivation.keys().collect::<Vec<_>>(),
+				vec![&redeem_txn.collateral.borrower_pubkey.inner]
+			);
+		}
+		assert!(psbt
+			.outputs
+			.iter()
+			.all(|output| output.redeem_script.is_none()));
+		assert!(psbt.xpub.is_empty());
 	}
 
 	#[test]
 	fn test_create_psbt() {
-		let redeem_txn = redeem_txn();
+		let redeem_txn = redeem_txn()
+			.with_fee_target(FeeTarget::SatPerVb(2))
+			.with_tip_height(800_000);
 
-		let psbt = redeem_txn.create_psbt();
+		let psbt = redeem_txn.create_psbt(None, &FeeSettings::default());
 
 		let psbt = match psbt {
 			Ok(psbt) => psbt,
@@ -182,5 +479,27 @@ mod tests {
 		let b64 = convert_txn_hex_to_base64(psbt.serialize_hex()).unwrap();
 
 		println!("psbt: {:?}", b64);
+		assert_eq!(psbt.unsigned_tx.input.len(), 2);
+		assert_eq!(psbt.inputs.len(), 2);
+		assert_eq!(
+			psbt.inputs[1].witness_utxo.as_ref().unwrap().script_pubkey,
+			redeem_txn.collateral.create_p2wsh_address().script_pubkey()
+		);
+		assert!(psbt
+			.inputs
+			.iter()
+			.all(|input| input.non_witness_utxo == Some(redeem_txn.previous_txns[0].clone())));
+	}
+
+	#[test]
+	fn test_psbt_rejects_wrong_recorded_amount() {
+		let mut redeem_txn = redeem_txn()
+			.with_fee_target(FeeTarget::SatPerVb(2))
+			.with_tip_height(800_000);
+		redeem_txn.utxos[1].amount = Amount::from_sat(60_000_000);
+
+		assert!(redeem_txn
+			.create_psbt(None, &FeeSettings::default())
+			.is_err());
 	}
 }
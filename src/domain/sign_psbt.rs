@@ -1,6 +1,7 @@
 use anyhow::{anyhow, Context, Result};
 use bitcoin::bip32::{DerivationPath, Xpriv};
 use bitcoin::ecdsa::Signature;
+use bitcoin::hashes::Hash;
 use bitcoin::psbt::{Input, Psbt};
 use bitcoin::secp256k1::{self, All, Message, Secp256k1};
 use bitcoin::sighash::SighashCache;
@@ -72,7 +73,7 @@ pub fn sign_psbt(mut psbt: Psbt, xprv: Xpriv, derivation: &DerivationPath) -> Re
 			return Err(anyhow!("No private keys to sign this psbt"));
 		}
 		for keypair in input_keypairs {
-			let message = &Message::from_digest_slice(sighash.to_string().as_bytes())?;
+			let message = &Message::from_digest(sighash.to_byte_array());
 			let signature = secp.sign_ecdsa(message, &keypair.secret_key());
 			input.partial_sigs.insert(
 				PublicKey::new(keypair.public_key()),
@@ -86,8 +87,20 @@ pub fn sign_psbt(mut psbt: Psbt, xprv: Xpriv, derivation: &DerivationPath) -> Re
 
 #[cfg(test)]
 mod tests {
+	use super::*;
+	use crate::config::FeeSettings;
+	use crate::domain::collateral_descriptor::KeyOrigin;
+	use crate::domain::collateral_utxo::CollateralUtxo;
+	use crate::domain::key_derivation::{LoanKeyset, PartyXpub};
+	use crate::domain::redeeming_transaction::RedeemingTxnPSBT;
 	use crate::domain::MultisigAddress;
-	use bitcoin::{bip32::Xpriv, secp256k1, AddressType, Network::Regtest, PrivateKey, PublicKey};
+	use crate::utils::get_feerate::FeeTarget;
+	use bitcoin::bip32::Xpub;
+	use bitcoin::{
+		absolute::LockTime, bip32::Xpriv, secp256k1, transaction::Version, AddressType, Amount,
+		Network::Regtest, OutPoint, PrivateKey, PublicKey, Transaction, TxOut,
+	};
+	use std::str::FromStr;
 
 	fn get_xprivs() -> (Xpriv, Xpriv, Xpriv) {
 		(
@@ -130,4 +143,88 @@ mod tests {
 		assert_eq!(address.address_type(), Some(AddressType::P2wsh));
 		assert_eq!(address.network(), &Regtest);
 	}
+
+	fn account(master: &Xpriv) -> PartyXpub {
+		let secp = Secp256k1::new();
+		let path = DerivationPath::from_str("m/48'/1'/0'/2'").unwrap();
+		let account = master.derive_priv(&secp, &path).unwrap();
+
+		PartyXpub::new(
+			Xpub::from_priv(&secp, &account),
+			KeyOrigin {
+				fingerprint: master.fingerprint(&secp),
+				path,
+			},
+		)
+	}
+
+	#[test]
+	fn test_two_parties_sign_redemption() {
+		let masters = [1u8, 2, 3].map(|seed| Xpriv::new_master(Regtest, &[seed; 32]).unwrap());
+		let keyset = LoanKeyset::new(
+			account(&masters[0]),
+			account(&masters[1]),
+			account(&masters[2]),
+		);
+		let collateral = keyset.derive(5).unwrap().multisig;
+		let deposit = Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: Vec::new(),
+			output: vec![TxOut {
+				value: Amount::from_sat(1_000_000),
+				script_pubkey: collateral.create_p2wsh_address().script_pubkey(),
+			}],
+		};
+		let utxo = CollateralUtxo {
+			outpoint: OutPoint::new(deposit.txid(), 0),
+			amount: Amount::from_sat(1_000_000),
+			height: Some(100),
+		};
+
+		let psbt = RedeemingTxnPSBT::new(
+			"bcrt1qeygjhsgt5sumtlqnyfu58harh3737z96m0zmqv".to_string(),
+			Amount::from_sat(900_000),
+			vec![utxo],
+			"bcrt1q8ucxfsyajsdghspzpn8mx8m7gyfv0c8jfn60m7".to_string(),
+			collateral,
+		)
+		.with_previous_txns(vec![deposit])
+		.with_fee_target(FeeTarget::SatPerVb(2))
+		.with_tip_height(800_000)
+		.with_keyset(&keyset, 5)
+		.unwrap()
+		.create_psbt(None, &FeeSettings::default())
+		.unwrap();
+
+		assert_eq!(psbt.xpub.len(), 3);
+		assert_eq!(psbt.inputs[0].bip32_derivation.len(), 3);
+		assert!(psbt
+			.outputs
+			.iter()
+			.all(|output| output.redeem_script.is_none()));
+
+		// the borrower and the service sign with their master keys
+		let psbt = sign_psbt(psbt, masters[0], &DerivationPath::master()).unwrap();
+		let psbt = sign_psbt(psbt, masters[2], &DerivationPath::master()).unwrap();
+
+		let input = &psbt.inputs[0];
+		let sighash = SighashCache::new(&psbt.unsigned_tx)
+			.p2wsh_signature_hash(
+				0,
+				input.witness_script.as_ref().unwrap(),
+				Amount::from_sat(1_000_000),
+				EcdsaSighashType::All,
+			)
+			.unwrap();
+		let message = Message::from_digest(sighash.to_byte_array());
+
+		let secp = Secp256k1::new();
+		assert_eq!(input.partial_sigs.len(), 2);
+		for (pubkey, signature) in &input.partial_sigs {
+			assert!(secp
+				.verify_ecdsa(&message, &signature.sig, &pubkey.inner)
+				.is_ok());
+		}
+	}
 }
@@ -0,0 +1,160 @@
+use crate::constants::set_network;
+use crate::domain::MultisigAddress;
+use crate::utils::musig::aggregate_xonly_key;
+use bitcoin::opcodes::all::{OP_CHECKSIG, OP_CHECKSIGVERIFY};
+use bitcoin::script::Builder;
+use bitcoin::secp256k1::{Secp256k1, XOnlyPublicKey};
+use bitcoin::taproot::{ControlBlock, LeafVersion, TaprootBuilder, TaprootSpendInfo};
+use bitcoin::{Address, PublicKey, ScriptBuf};
+
+/// Script-path spends of the taproot collateral; each one needs the service key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptPath {
+	BorrowerService,
+	LenderService,
+}
+
+/// Taproot variant of the 2-of-3 collateral address.
+///
+/// Key path: MuSig2 aggregate of the borrower and lender keys (cooperative close).
+/// Script paths: borrower + service and lender + service tapscript leaves.
+#[derive(Debug, Clone)]
+pub struct TaprootMultisigAddress {
+	pub borrower_pubkey: PublicKey,
+	pub lender_pubkey: PublicKey,
+	pub service_pubkey: PublicKey,
+}
+
+impl TaprootMultisigAddress {
+	pub fn new(
+		borrower_pubkey: PublicKey,
+		lender_pubkey: PublicKey,
+		service_pubkey: PublicKey,
+	) -> Self {
+		Self {
+			borrower_pubkey,
+			lender_pubkey,
+			service_pubkey,
+		}
+	}
+
+	/// MuSig2 aggregate key of the borrower and lender, used as the taproot internal key
+	pub fn cooperative_key(&self) -> Result<XOnlyPublicKey, String> {
+		aggregate_xonly_key(&[self.borrower_pubkey.inner, self.lender_pubkey.inner])
+	}
+
+	/// Leaf script: <party_xonly> OP_CHECKSIGVERIFY <service_xonly> OP_CHECKSIG
+	pub fn leaf_script(&self, path: ScriptPath) -> ScriptBuf {
+		let party_pubkey = match path {
+			ScriptPath::BorrowerService => self.borrower_pubkey,
+			ScriptPath::LenderService => self.lender_pubkey,
+		};
+
+		Builder::new()
+			.push_x_only_key(&party_pubkey.inner.x_only_public_key().0)
+			.push_opcode(OP_CHECKSIGVERIFY)
+			.push_x_only_key(&self.service_pubkey.inner.x_only_public_key().0)
+			.push_opcode(OP_CHECKSIG)
+			.into_script()
+	}
+
+	pub fn spend_info(&self) -> Result<TaprootSpendInfo, String> {
+		let secp = Secp256k1::verification_only();
+		let internal_key = self.cooperative_key()?;
+
+		TaprootBuilder::new()
+			.add_leaf(1, self.leaf_script(ScriptPath::BorrowerService))
+			.and_then(|builder| builder.add_leaf(1, self.leaf_script(ScriptPath::LenderService)))
+			.map_err(|e| format!("Error building taproot tree: {:?}", e))?
+			.finalize(&secp, internal_key)
+			.map_err(|_| "Error finalizing taproot tree".to_string())
+	}
+
+	/// Control block needed to spend through the given script path
+	pub fn control_block(&self, path: ScriptPath) -> Result<ControlBlock, String> {
+		self.spend_info()?
+			.control_block(&(self.leaf_script(path), LeafVersion::TapScript))
+			.ok_or_else(|| format!("No control block found for {:?}", path))
+	}
+
+	/// P2TR: OP_1 <32-byte tweaked output key>
+	pub fn create_p2tr_address(&self) -> Result<Address, String> {
+		let secp = Secp256k1::verification_only();
+		let spend_info = self.spend_info()?;
+
+		Ok(Address::p2tr(
+			&secp,
+			spend_info.internal_key(),
+			spend_info.merkle_root(),
+			set_network(),
+		))
+	}
+}
+
+impl From<&MultisigAddress> for TaprootMultisigAddress {
+	fn from(multisig: &MultisigAddress) -> Self {
+		Self::new(
+			multisig.borrower_pubkey,
+			multisig.lender_pubkey,
+			multisig.service_pubkey,
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::AddressType;
+	use std::str::FromStr;
+
+	fn taproot_address() -> TaprootMultisigAddress {
+		TaprootMultisigAddress::new(
+			PublicKey::from_str(
+				"02f0eaa04e609b0044ef1fe09a350dc4b744a5a8604a6fa77bc9bf6443ea50739f",
+			)
+			.expect("invalid borrower pubkey"),
+			PublicKey::from_str(
+				"037c60db011a840523f216e7198054ef071c5acd3d4b466cf2658b7faf30c11e33",
+			)
+			.expect("invalid lender pubkey"),
+			PublicKey::from_str(
+				"02ca49f36d3de1e135e033052611dd0873af55b57f07d5d0d1090ceb267ac34e6b",
+			)
+			.expect("invalid service pubkey"),
+		)
+	}
+
+	#[test]
+	fn test_create_p2tr_address() {
+		let address = taproot_address().create_p2tr_address().unwrap();
+
+		assert_eq!(address.network(), &set_network());
+		assert_eq!(address.address_type(), Some(AddressType::P2tr));
+	}
+
+	#[test]
+	fn test_leaf_script() {
+		let taproot = taproot_address();
+
+		assert_eq!(
+			taproot.leaf_script(ScriptPath::BorrowerService).to_hex_string(),
+			"20f0eaa04e609b0044ef1fe09a350dc4b744a5a8604a6fa77bc9bf6443ea50739fad20ca49f36d3de1e135e033052611dd0873af55b57f07d5d0d1090ceb267ac34e6bac"
+		);
+	}
+
+	#[test]
+	fn test_control_block_verifies() {
+		let taproot = taproot_address();
+		let secp = Secp256k1::verification_only();
+		let output_key = taproot.spend_info().unwrap().output_key();
+
+		for path in [ScriptPath::BorrowerService, ScriptPath::LenderService] {
+			let control_block = taproot.control_block(path).unwrap();
+			assert!(control_block.verify_taproot_commitment(
+				&secp,
+				output_key.to_inner(),
+				&taproot.leaf_script(path)
+			));
+		}
+	}
+}
@@ -0,0 +1,239 @@
+use crate::constants::set_network;
+use crate::domain::collateral_utxo::CollateralUtxo;
+use crate::domain::MultisigAddress;
+use crate::utils::bitcoind_rpc::get_transaction;
+use bitcoin::bip32::KeySource;
+use bitcoin::opcodes::all::OP_CHECKMULTISIG;
+use bitcoin::psbt::Input;
+use bitcoin::script::Builder;
+use bitcoin::{secp256k1, Address, PublicKey, ScriptBuf, Transaction};
+use bitcoincore_rpc::Client;
+use std::collections::{BTreeMap, HashSet};
+
+// standardness limit for CHECKMULTISIG in a P2WSH witness script
+pub const MAX_MULTISIG_KEYS: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyRole {
+	Borrower,
+	Lender,
+	Service,
+	BackupAgent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CollateralKey {
+	pub role: KeyRole,
+	pub pubkey: PublicKey,
+}
+
+impl CollateralKey {
+	pub fn new(role: KeyRole, pubkey: PublicKey) -> Self {
+		Self { role, pubkey }
+	}
+}
+
+/// m-of-n collateral multisig, e.g. 3-of-5 borrower, two lenders, service and backup agent
+/// for a syndicated loan. Keys appear in the script in the order given.
+#[derive(Debug, Clone)]
+pub struct ThresholdMultisig {
+	pub threshold: usize,
+	pub keys: Vec<CollateralKey>,
+}
+
+impl ThresholdMultisig {
+	pub fn new(threshold: usize, keys: Vec<CollateralKey>) -> Result<Self, String> {
+		if keys.is_empty() || keys.len() > MAX_MULTISIG_KEYS {
+			return Err(format!(
+				"A collateral multisig needs between 1 and {} keys, found {}",
+				MAX_MULTISIG_KEYS,
+				keys.len()
+			));
+		}
+		if threshold == 0 || threshold > keys.len() {
+			return Err(format!(
+				"Invalid threshold {} for {} keys",
+				threshold,
+				keys.len()
+			));
+		}
+
+		let count = |role| keys.iter().filter(|key| key.role == role).count();
+		if count(KeyRole::Borrower) != 1 || count(KeyRole::Service) != 1 {
+			return Err("Exactly one borrower key and one service key are required".to_string());
+		}
+		if count(KeyRole::Lender) == 0 {
+			return Err("At least one lender key is required".to_string());
+		}
+
+		let mut seen = HashSet::new();
+		for key in &keys {
+			if !key.pubkey.compressed {
+				return Err(format!("The {:?} public key must be compressed", key.role));
+			}
+			if !seen.insert(key.pubkey.inner) {
+				return Err(format!("Duplicate public key: {}", key.pubkey));
+			}
+		}
+
+		Ok(Self { threshold, keys })
+	}
+
+	pub fn keys_with_role(&self, role: KeyRole) -> Vec<PublicKey> {
+		self.keys
+			.iter()
+			.filter(|key| key.role == role)
+			.map(|key| key.pubkey)
+			.collect()
+	}
+
+	/// Redeem_script: OP_m [pubkey1] ... [pubkeyn] OP_n OP_CHECKMULTISIG
+	pub fn redeem_script(&self) -> ScriptBuf {
+		let mut builder = Builder::new().push_int(self.threshold as i64);
+		for key in &self.keys {
+			builder = builder.push_key(&key.pubkey);
+		}
+		builder
+			.push_int(self.keys.len() as i64)
+			.push_opcode(OP_CHECKMULTISIG)
+			.into_script()
+	}
+
+	pub fn create_p2wsh_address(&self) -> Address {
+		Address::p2wsh(&self.redeem_script(), set_network())
+	}
+
+	/// PSBT input spending output `vout` of `previous_txn` from the P2WSH: the spent output,
+	/// the whole previous transaction for hardware signers that verify input amounts, the
+	/// witness script and the given key derivations
+	pub fn psbt_input(
+		&self,
+		previous_txn: &Transaction,
+		vout: u32,
+		key_derivations: &BTreeMap<secp256k1::PublicKey, KeySource>,
+	) -> Result<Input, String> {
+		let spent = previous_txn
+			.output
+			.get(vout as usize)
+			.ok_or_else(|| format!("Output {}:{} does not exist", previous_txn.txid(), vout))?;
+		if spent.script_pubkey != self.create_p2wsh_address().script_pubkey() {
+			return Err(format!(
+				"Output {}:{} is not locked by the collateral",
+				previous_txn.txid(),
+				vout
+			));
+		}
+
+		Ok(Input {
+			witness_utxo: Some(spent.clone()),
+			non_witness_utxo: Some(previous_txn.clone()),
+			witness_script: Some(self.redeem_script()),
+			bip32_derivation: key_derivations.clone(),
+			..Default::default()
+		})
+	}
+
+	/// PSBT inputs spending `utxos`, taking each previous transaction from `previous_txns`
+	/// or else from the node, and rejecting a UTXO recorded with the wrong amount
+	pub fn psbt_inputs(
+		&self,
+		utxos: &[CollateralUtxo],
+		previous_txns: &[Transaction],
+		key_derivations: &BTreeMap<secp256k1::PublicKey, KeySource>,
+		client: Option<&Client>,
+	) -> Result<Vec<Input>, String> {
+		utxos
+			.iter()
+			.map(|utxo| {
+				let txid = utxo.outpoint.txid;
+				let previous_txn = match previous_txns.iter().find(|txn| txn.txid() == txid) {
+					Some(txn) => txn.clone(),
+					None => get_transaction(txid, client)
+						.map_err(|e| format!("Error fetching transaction {}: {:?}", txid, e))?,
+				};
+				let input = self.psbt_input(&previous_txn, utxo.outpoint.vout, key_derivations)?;
+				if input.witness_utxo.as_ref().map(|spent| spent.value) != Some(utxo.amount) {
+					return Err(format!(
+						"Collateral UTXO {} is recorded with the wrong amount",
+						utxo.outpoint
+					));
+				}
+				Ok(input)
+			})
+			.collect()
+	}
+}
+
+impl From<&MultisigAddress> for ThresholdMultisig {
+	fn from(multisig: &MultisigAddress) -> Self {
+		Self {
+			threshold: 2,
+			keys: vec![
+				CollateralKey::new(KeyRole::Borrower, multisig.borrower_pubkey),
+				CollateralKey::new(KeyRole::Lender, multisig.lender_pubkey),
+				CollateralKey::new(KeyRole::Service, multisig.service_pubkey),
+			],
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+	fn pubkey(byte: u8) -> PublicKey {
+		let secret = SecretKey::from_slice(&[byte; 32]).unwrap();
+		PublicKey::new(secret.public_key(&Secp256k1::new()))
+	}
+
+	fn syndicated_keys() -> Vec<CollateralKey> {
+		vec![
+			CollateralKey::new(KeyRole::Borrower, pubkey(1)),
+			CollateralKey::new(KeyRole::Lender, pubkey(2)),
+			CollateralKey::new(KeyRole::Lender, pubkey(3)),
+			CollateralKey::new(KeyRole::Service, pubkey(4)),
+			CollateralKey::new(KeyRole::BackupAgent, pubkey(5)),
+		]
+	}
+
+	#[test]
+	fn test_matches_two_of_three() {
+		let multisig = MultisigAddress::new(pubkey(1), pubkey(2), pubkey(3));
+		let threshold = ThresholdMultisig::from(&multisig);
+
+		assert_eq!(threshold.redeem_script(), multisig.redeem_script());
+		assert_eq!(
+			threshold.create_p2wsh_address(),
+			multisig.create_p2wsh_address()
+		);
+	}
+
+	#[test]
+	fn test_three_of_five() {
+		let multisig = ThresholdMultisig::new(3, syndicated_keys()).unwrap();
+		let script = multisig.redeem_script();
+		let bytes = script.as_bytes();
+
+		// OP_3 ... OP_5 OP_CHECKMULTISIG
+		assert_eq!(bytes[0], 0x53);
+		assert_eq!(bytes[bytes.len() - 2], 0x55);
+		assert_eq!(multisig.keys_with_role(KeyRole::Lender).len(), 2);
+	}
+
+	#[test]
+	fn test_invalid_threshold_multisig() {
+		assert!(ThresholdMultisig::new(6, syndicated_keys()).is_err());
+		assert!(ThresholdMultisig::new(0, syndicated_keys()).is_err());
+
+		let mut duplicate = syndicated_keys();
+		duplicate[2].pubkey = pubkey(2);
+		assert!(ThresholdMultisig::new(3, duplicate).is_err());
+
+		let no_lender: Vec<_> = syndicated_keys()
+			.into_iter()
+			.filter(|key| key.role != KeyRole::Lender)
+			.collect();
+		assert!(ThresholdMultisig::new(2, no_lender).is_err());
+	}
+}
@@ -1,6 +1,7 @@
 pub mod config;
 pub mod constants;
 pub mod domain;
+pub mod repository;
 pub mod service;
 pub mod startup;
 pub mod utils;
@@ -1,13 +1,15 @@
-use btc_collateral::{config::Settings, startup::run};
-use sqlx::{Connection, PgConnection};
+use btc_collateral::{config::Settings, service::deposit_watcher::DepositWatcher, startup::run};
+use sqlx::PgPool;
 use std::net::TcpListener;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
+	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 	let settings = Settings::get_configuration().expect("failed to read config");
-	let connection = PgConnection::connect(&settings.database.connection_string())
+	let connection = PgPool::connect(&settings.database.connection_string())
 		.await
 		.expect("Failed to connect to postgres");
+	tokio::spawn(DepositWatcher::new(connection.clone(), settings.watcher.clone()).run());
 	let address = format!("127.0.0.1:{}", settings.application_port);
 	let listener = TcpListener::bind(address).expect("Failed to bind random port");
 	run(listener, connection)?.await
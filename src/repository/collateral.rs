@@ -0,0 +1,421 @@
+use crate::domain::collateral_descriptor::CollateralKeyOrigins;
+use crate::domain::forfeiture_transaction::LenderShare;
+use crate::domain::key_derivation::LoanKeyset;
+use crate::domain::party_role::PartyRole;
+use crate::domain::MultisigAddress;
+use crate::repository::party_xpub::get_party_xpub;
+use anyhow::{anyhow, Result};
+use bitcoin::{Amount, PublicKey, ScriptBuf};
+use sqlx::types::Uuid;
+use sqlx::{PgExecutor, PgPool, Postgres, Transaction};
+use std::collections::HashSet;
+use std::str::FromStr;
+
+/// Reserves a child index that no other loan has used
+pub async fn next_derivation_index(pool: &PgPool) -> Result<u32> {
+	let (index,): (i32,) = sqlx::query_as("SELECT nextval('collateral_derivation_index_seq')::int")
+		.fetch_one(pool)
+		.await?;
+
+	u32::try_from(index).map_err(|_| anyhow!("Invalid derivation index: {}", index))
+}
+
+/// Stores the derivation index and party xpubs the collateral keys were derived from
+pub async fn record_derivation<'c>(
+	executor: impl PgExecutor<'c>,
+	collateral_id: Uuid,
+	derivation_index: u32,
+	xpub_ids: (Uuid, Uuid, Uuid),
+) -> Result<()> {
+	let (borrower_xpub_id, lender_xpub_id, service_xpub_id) = xpub_ids;
+
+	sqlx::query(
+		"UPDATE collateral SET derivation_index = $2, borrower_xpub_id = $3, lender_xpub_id = $4,
+		service_xpub_id = $5, updated_at = NOW() WHERE id = $1",
+	)
+	.bind(collateral_id)
+	.bind(derivation_index as i32)
+	.bind(borrower_xpub_id)
+	.bind(lender_xpub_id)
+	.bind(service_xpub_id)
+	.execute(executor)
+	.await?;
+
+	Ok(())
+}
+
+pub async fn get_collateral_amount(pool: &PgPool, collateral_id: Uuid) -> Result<Amount> {
+	let (sats,): (i64,) =
+		sqlx::query_as("SELECT bitcoin_amount_sats FROM collateral WHERE id = $1")
+			.bind(collateral_id)
+			.fetch_one(pool)
+			.await?;
+
+	let sats = u64::try_from(sats).map_err(|_| anyhow!("Negative collateral amount: {}", sats))?;
+	Ok(Amount::from_sat(sats))
+}
+
+pub async fn set_collateral_amount(
+	pool: &PgPool,
+	collateral_id: Uuid,
+	amount: Amount,
+) -> Result<()> {
+	let sats = i64::try_from(amount.to_sat())
+		.map_err(|_| anyhow!("Collateral amount too large: {}", amount))?;
+
+	sqlx::query("UPDATE collateral SET bitcoin_amount_sats = $2, updated_at = NOW() WHERE id = $1")
+		.bind(collateral_id)
+		.bind(sats)
+		.execute(pool)
+		.await?;
+
+	Ok(())
+}
+
+/// Records the keys locked in a collateral script, for the key reuse check
+pub async fn record_collateral_pubkeys(
+	pool: &PgPool,
+	collateral_id: Uuid,
+	keys: &[(PartyRole, PublicKey)],
+) -> Result<()> {
+	let mut transaction = pool.begin().await?;
+	insert_pubkeys(&mut transaction, collateral_id, keys).await?;
+	transaction.commit().await?;
+
+	Ok(())
+}
+
+async fn insert_pubkeys(
+	transaction: &mut Transaction<'_, Postgres>,
+	collateral_id: Uuid,
+	keys: &[(PartyRole, PublicKey)],
+) -> Result<()> {
+	for (role, pubkey) in keys {
+		sqlx::query(
+			"INSERT INTO collateral_pubkey (collateral_id, role, pubkey) VALUES ($1, $2, $3)
+			ON CONFLICT (collateral_id, pubkey) DO NOTHING",
+		)
+		.bind(collateral_id)
+		.bind(role)
+		.bind(pubkey.to_string())
+		.execute(&mut *transaction)
+		.await?;
+	}
+
+	Ok(())
+}
+
+/// Which of `keys` already secure another loan as a borrower or lender key
+pub async fn used_pubkeys(pool: &PgPool, keys: &[PublicKey]) -> Result<HashSet<PublicKey>> {
+	let keys: Vec<String> = keys.iter().map(PublicKey::to_string).collect();
+	let rows: Vec<(String,)> = sqlx::query_as(
+		"SELECT DISTINCT pubkey FROM collateral_pubkey
+		WHERE pubkey = ANY($1) AND role <> 'service'",
+	)
+	.bind(&keys)
+	.fetch_all(pool)
+	.await?;
+
+	rows.into_iter()
+		.map(|(pubkey,)| PublicKey::from_str(&pubkey).map_err(|e| anyhow!(e)))
+		.collect()
+}
+
+/// Creates the collateral of a loan from the parties' xpubs: reserves a fresh child index,
+/// derives the three keys at it, rejects keys that already secure another loan, and
+/// stores the collateral row with its keys and derivation
+pub async fn create_derived_collateral(
+	pool: &PgPool,
+	loan_request_id: Uuid,
+	xpub_ids: (Uuid, Uuid, Uuid),
+) -> Result<(Uuid, MultisigAddress)> {
+	let (borrower_xpub_id, lender_xpub_id, service_xpub_id) = xpub_ids;
+	let keyset = LoanKeyset::new(
+		get_party_xpub(pool, borrower_xpub_id).await?,
+		get_party_xpub(pool, lender_xpub_id).await?,
+		get_party_xpub(pool, service_xpub_id).await?,
+	);
+	let derivation_index = next_derivation_index(pool).await?;
+	let derived = keyset
+		.derive(derivation_index)
+		.map_err(|e| anyhow!(e))?
+		.multisig;
+
+	let used = used_pubkeys(pool, &[derived.borrower_pubkey, derived.lender_pubkey]).await?;
+	let multisig = MultisigAddress::try_new(
+		derived.borrower_pubkey,
+		derived.lender_pubkey,
+		derived.service_pubkey,
+		&used,
+	)?;
+
+	let mut transaction = pool.begin().await?;
+	let (collateral_id,): (Uuid,) = sqlx::query_as(
+		"INSERT INTO collateral (id, loan_request_id, redeem_script, multisig_address)
+		VALUES (gen_random_uuid(), $1, $2, $3) RETURNING id",
+	)
+	.bind(loan_request_id)
+	.bind(multisig.redeem_script().to_hex_string())
+	.bind(multisig.create_p2wsh_address().to_string())
+	.fetch_one(&mut transaction)
+	.await?;
+	insert_pubkeys(
+		&mut transaction,
+		collateral_id,
+		&[
+			(PartyRole::Borrower, multisig.borrower_pubkey),
+			(PartyRole::Lender, multisig.lender_pubkey),
+			(PartyRole::Service, multisig.service_pubkey),
+		],
+	)
+	.await?;
+	record_derivation(&mut transaction, collateral_id, derivation_index, xpub_ids).await?;
+	transaction.commit().await?;
+
+	Ok((collateral_id, multisig))
+}
+
+/// Collateral keys and address recorded for a loan
+#[derive(Debug, Clone)]
+pub struct CollateralAddressRecord {
+	pub multisig: MultisigAddress,
+	pub multisig_address: String,
+	pub origins: CollateralKeyOrigins,
+}
+
+type CollateralAddressRow = (
+	String,
+	String,
+	Option<i32>,
+	Option<Uuid>,
+	Option<Uuid>,
+	Option<Uuid>,
+);
+
+pub async fn get_collateral_address(
+	pool: &PgPool,
+	loan_request_id: Uuid,
+) -> Result<Option<CollateralAddressRecord>> {
+	let row: Option<CollateralAddressRow> = sqlx::query_as(
+		"SELECT redeem_script, multisig_address, derivation_index, borrower_xpub_id,
+		lender_xpub_id, service_xpub_id FROM collateral WHERE loan_request_id = $1",
+	)
+	.bind(loan_request_id)
+	.fetch_optional(pool)
+	.await?;
+
+	let Some((redeem_script, multisig_address, index, borrower_id, lender_id, service_id)) = row
+	else {
+		return Ok(None);
+	};
+
+	let script = ScriptBuf::from_hex(&redeem_script)?;
+	let multisig = MultisigAddress::from_redeem_script(&script).map_err(|e| anyhow!(e))?;
+
+	// key origins are only known for keys derived from registered xpubs
+	let mut origins = CollateralKeyOrigins::default();
+	if let Some(index) = index {
+		let index = u32::try_from(index).map_err(|_| anyhow!("Invalid derivation index"))?;
+		for (xpub_id, origin) in [
+			(borrower_id, &mut origins.borrower),
+			(lender_id, &mut origins.lender),
+			(service_id, &mut origins.service),
+		] {
+			if let Some(xpub_id) = xpub_id {
+				let (_, key_origin) = get_party_xpub(pool, xpub_id)
+					.await?
+					.derive(index)
+					.map_err(|e| anyhow!(e))?;
+				*origin = Some(key_origin);
+			}
+		}
+	}
+
+	Ok(Some(CollateralAddressRecord {
+		multisig,
+		multisig_address,
+		origins,
+	}))
+}
+
+/// Lenders of a loan with their payout address and amount lent
+pub async fn get_lender_shares(pool: &PgPool, loan_request_id: Uuid) -> Result<Vec<LenderShare>> {
+	let rows: Vec<(String, f64)> = sqlx::query_as(
+		"SELECT payout_address, amount_lent FROM loan_lender WHERE loan_request_id = $1
+		ORDER BY created_at",
+	)
+	.bind(loan_request_id)
+	.fetch_all(pool)
+	.await?;
+
+	Ok(rows
+		.into_iter()
+		.map(|(payout_address, amount_lent)| LenderShare {
+			payout_address,
+			amount_lent,
+		})
+		.collect())
+}
+
+/// Address the borrower of a loan registered for the surplus of a liquidation
+pub async fn get_borrower_payout_address(
+	pool: &PgPool,
+	loan_request_id: Uuid,
+) -> Result<Option<String>> {
+	let row: Option<(Option<String>,)> = sqlx::query_as(
+		"SELECT borrower.payout_address FROM loan_request
+		JOIN borrower ON borrower.id = loan_request.borrower_id WHERE loan_request.id = $1",
+	)
+	.bind(loan_request_id)
+	.fetch_optional(pool)
+	.await?;
+
+	Ok(row.and_then(|(payout_address,)| payout_address))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::domain::collateral_descriptor::KeyOrigin;
+	use crate::domain::key_derivation::PartyXpub;
+	use crate::repository::party_xpub::insert_party_xpub;
+	use crate::utils::validate_publickeys::PublicKeyError;
+	use bitcoin::bip32::{DerivationPath, Xpriv, Xpub};
+	use bitcoin::secp256k1::Secp256k1;
+	use bitcoin::Network;
+
+	fn key(hex: &str) -> PublicKey {
+		PublicKey::from_str(hex).unwrap()
+	}
+
+	#[sqlx::test(fixtures("loan"))]
+	#[ignore = "needs a Postgres server in DATABASE_URL"]
+	async fn test_used_pubkeys(pool: PgPool) -> Result<()> {
+		let collateral_id = Uuid::parse_str("00000000-0000-0000-0000-000000000006")?;
+		let borrower = key("02f0eaa04e609b0044ef1fe09a350dc4b744a5a8604a6fa77bc9bf6443ea50739f");
+		let lender = key("037c60db011a840523f216e7198054ef071c5acd3d4b466cf2658b7faf30c11e33");
+		let service = key("02ca49f36d3de1e135e033052611dd0873af55b57f07d5d0d1090ceb267ac34e6b");
+		let fresh = key("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798");
+
+		record_collateral_pubkeys(
+			&pool,
+			collateral_id,
+			&[
+				(PartyRole::Borrower, borrower),
+				(PartyRole::Lender, lender),
+				(PartyRole::Service, service),
+			],
+		)
+		.await?;
+
+		assert_eq!(
+			used_pubkeys(&pool, &[lender, fresh]).await?,
+			HashSet::from([lender])
+		);
+		assert!(used_pubkeys(&pool, &[service, fresh]).await?.is_empty());
+		Ok(())
+	}
+
+	#[sqlx::test(fixtures("loan"))]
+	#[ignore = "needs a Postgres server in DATABASE_URL"]
+	async fn test_borrower_payout_address(pool: PgPool) -> Result<()> {
+		let loan_request_id = Uuid::parse_str("00000000-0000-0000-0000-000000000005")?;
+
+		assert_eq!(
+			get_borrower_payout_address(&pool, loan_request_id).await?,
+			Some("bcrt1q8ucxfsyajsdghspzpn8mx8m7gyfv0c8jfn60m7".to_string())
+		);
+		assert_eq!(get_borrower_payout_address(&pool, Uuid::nil()).await?, None);
+		Ok(())
+	}
+
+	/// Registers an account xpub for each party, derived from a seed per party
+	async fn register_xpubs(pool: &PgPool) -> Result<(LoanKeyset, (Uuid, Uuid, Uuid))> {
+		let secp = Secp256k1::new();
+		let path = DerivationPath::from_str("m/48'/1'/0'/2'")?;
+		let mut parties = Vec::new();
+		let mut ids = Vec::new();
+		for (seed, role) in [
+			(1u8, PartyRole::Borrower),
+			(2, PartyRole::Lender),
+			(3, PartyRole::Service),
+		] {
+			let master = Xpriv::new_master(Network::Regtest, &[seed; 32])?;
+			let party = PartyXpub::new(
+				Xpub::from_priv(&secp, &master.derive_priv(&secp, &path)?),
+				KeyOrigin {
+					fingerprint: master.fingerprint(&secp),
+					path: path.clone(),
+				},
+			);
+			ids.push(insert_party_xpub(pool, None, role, &party).await?);
+			parties.push(party);
+		}
+
+		let [borrower, lender, service]: [PartyXpub; 3] = parties.try_into().unwrap();
+		Ok((
+			LoanKeyset::new(borrower, lender, service),
+			(ids[0], ids[1], ids[2]),
+		))
+	}
+
+	#[sqlx::test(fixtures("loan"))]
+	#[ignore = "needs a Postgres server in DATABASE_URL"]
+	async fn test_create_derived_collateral(pool: PgPool) -> Result<()> {
+		let loan_request_id = Uuid::parse_str("00000000-0000-0000-0000-000000000005")?;
+		let (keyset, xpub_ids) = register_xpubs(&pool).await?;
+
+		let (collateral_id, multisig) =
+			create_derived_collateral(&pool, loan_request_id, xpub_ids).await?;
+
+		let (index,): (i32,) =
+			sqlx::query_as("SELECT derivation_index FROM collateral WHERE id = $1")
+				.bind(collateral_id)
+				.fetch_one(&pool)
+				.await?;
+		assert_eq!(
+			keyset
+				.derive(index as u32)
+				.map_err(|e| anyhow!(e))?
+				.multisig
+				.redeem_script(),
+			multisig.redeem_script()
+		);
+		assert_eq!(
+			used_pubkeys(&pool, &[multisig.borrower_pubkey]).await?,
+			HashSet::from([multisig.borrower_pubkey])
+		);
+		Ok(())
+	}
+
+	#[sqlx::test(fixtures("loan"))]
+	#[ignore = "needs a Postgres server in DATABASE_URL"]
+	async fn test_reused_key_rejected(pool: PgPool) -> Result<()> {
+		let loan_request_id = Uuid::parse_str("00000000-0000-0000-0000-000000000005")?;
+		let collateral_id = Uuid::parse_str("00000000-0000-0000-0000-000000000006")?;
+		let (keyset, xpub_ids) = register_xpubs(&pool).await?;
+
+		// the key the next index derives already secures the fixture loan
+		let next = keyset.derive(0).map_err(|e| anyhow!(e))?.multisig;
+		record_collateral_pubkeys(
+			&pool,
+			collateral_id,
+			&[(PartyRole::Borrower, next.borrower_pubkey)],
+		)
+		.await?;
+
+		let error = create_derived_collateral(&pool, loan_request_id, xpub_ids)
+			.await
+			.unwrap_err();
+		assert_eq!(
+			error.downcast_ref::<PublicKeyError>(),
+			Some(&PublicKeyError::PreviouslyUsed(PartyRole::Borrower))
+		);
+
+		let (count,): (i64,) = sqlx::query_as("SELECT count(*) FROM collateral")
+			.fetch_one(&pool)
+			.await?;
+		assert_eq!(count, 1);
+		Ok(())
+	}
+}
@@ -0,0 +1,226 @@
+use crate::domain::collateral_utxo::CollateralUtxo;
+use anyhow::{anyhow, Result};
+use bitcoin::{Amount, OutPoint, Txid};
+use sqlx::types::Uuid;
+use sqlx::{PgExecutor, PgPool};
+use std::str::FromStr;
+
+/// Collateral of a loan whose address is watched for deposits and top-ups
+#[derive(Debug, Clone)]
+pub struct WatchedCollateral {
+	pub collateral_id: Uuid,
+	pub loan_request_id: Uuid,
+	pub multisig_address: String,
+	pub required_amount: Amount,
+	/// unix time the collateral was created, where the wallet rescan starts
+	pub created_at: u64,
+	/// whether the address is already watched by the node wallet
+	pub imported: bool,
+}
+
+pub async fn watched_collaterals(pool: &PgPool) -> Result<Vec<WatchedCollateral>> {
+	let rows: Vec<(Uuid, Uuid, String, i64, i64, bool)> = sqlx::query_as(
+		"SELECT c.id, c.loan_request_id, c.multisig_address, c.bitcoin_amount_sats,
+		extract(epoch from c.created_at)::bigint, c.watch_imported_at IS NOT NULL
+		FROM collateral c JOIN loan_request l ON l.id = c.loan_request_id
+		WHERE l.status IN ('approved', 'funded')",
+	)
+	.fetch_all(pool)
+	.await?;
+
+	rows.into_iter()
+		.map(
+			|(collateral_id, loan_request_id, multisig_address, sats, created_at, imported)| {
+				let sats = u64::try_from(sats)
+					.map_err(|_| anyhow!("Negative collateral amount: {}", sats))?;
+				Ok(WatchedCollateral {
+					collateral_id,
+					loan_request_id,
+					multisig_address,
+					required_amount: Amount::from_sat(sats),
+					created_at: u64::try_from(created_at)?,
+					imported,
+				})
+			},
+		)
+		.collect()
+}
+
+/// Records that the collateral address is watched by the node wallet
+pub async fn mark_watch_imported(pool: &PgPool, collateral_id: Uuid) -> Result<()> {
+	sqlx::query("UPDATE collateral SET watch_imported_at = NOW() WHERE id = $1")
+		.bind(collateral_id)
+		.execute(pool)
+		.await?;
+
+	Ok(())
+}
+
+/// Inserts the output or refreshes its height and confirmation count. Recording an
+/// output as unconfirmed never undoes a confirmation already seen.
+pub async fn record_utxo(
+	pool: &PgPool,
+	collateral_id: Uuid,
+	utxo: &CollateralUtxo,
+	confirmations: u32,
+) -> Result<()> {
+	upsert_utxo(pool, collateral_id, utxo, confirmations).await
+}
+
+async fn upsert_utxo<'c>(
+	executor: impl PgExecutor<'c>,
+	collateral_id: Uuid,
+	utxo: &CollateralUtxo,
+	confirmations: u32,
+) -> Result<()> {
+	let sats = i64::try_from(utxo.amount.to_sat())
+		.map_err(|_| anyhow!("UTXO amount too large: {}", utxo.amount))?;
+
+	sqlx::query(
+		"INSERT INTO collateral_utxo (collateral_id, txid, vout, amount_sats, block_height, confirmations)
+		VALUES ($1, $2, $3, $4, $5, $6)
+		ON CONFLICT (txid, vout) DO UPDATE SET
+		block_height = COALESCE($5, collateral_utxo.block_height),
+		confirmations = CASE WHEN $5 IS NULL THEN collateral_utxo.confirmations ELSE $6 END,
+		spent = false, updated_at = NOW()",
+	)
+	.bind(collateral_id)
+	.bind(utxo.outpoint.txid.to_string())
+	.bind(utxo.outpoint.vout as i32)
+	.bind(sats)
+	.bind(utxo.height.map(|height| height as i32))
+	.bind(confirmations as i32)
+	.execute(executor)
+	.await?;
+
+	Ok(())
+}
+
+/// Records the outputs of a batch funding transaction against their collateral rows
+/// before they confirm, all or none of them
+pub async fn record_batch_utxos(pool: &PgPool, utxos: &[(Uuid, CollateralUtxo)]) -> Result<()> {
+	let mut transaction = pool.begin().await?;
+	for (collateral_id, utxo) in utxos {
+		upsert_utxo(&mut transaction, *collateral_id, utxo, 0).await?;
+	}
+	transaction.commit().await?;
+
+	Ok(())
+}
+
+/// Marks the outputs of the collateral the node no longer reports as spent: confirmed
+/// outputs that left the UTXO set, and unconfirmed ones that left the mempool
+pub async fn mark_missing_spent(
+	pool: &PgPool,
+	collateral_id: Uuid,
+	unspent: &[OutPoint],
+) -> Result<()> {
+	let unspent: Vec<String> = unspent
+		.iter()
+		.map(|outpoint| outpoint.to_string())
+		.collect();
+
+	sqlx::query(
+		"UPDATE collateral_utxo SET spent = true, updated_at = NOW()
+		WHERE collateral_id = $1 AND NOT spent
+		AND NOT (txid || ':' || vout = ANY($2))",
+	)
+	.bind(collateral_id)
+	.bind(unspent)
+	.execute(pool)
+	.await?;
+
+	Ok(())
+}
+
+/// Every unspent output locked at the collateral address of the loan
+pub async fn get_unspent_utxos(
+	pool: &PgPool,
+	loan_request_id: Uuid,
+) -> Result<Vec<CollateralUtxo>> {
+	let rows: Vec<(String, i32, i64, Option<i32>)> = sqlx::query_as(
+		"SELECT u.txid, u.vout, u.amount_sats, u.block_height
+		FROM collateral_utxo u JOIN collateral c ON c.id = u.collateral_id
+		WHERE c.loan_request_id = $1 AND NOT u.spent ORDER BY u.created_at",
+	)
+	.bind(loan_request_id)
+	.fetch_all(pool)
+	.await?;
+
+	rows.into_iter()
+		.map(|(txid, vout, sats, height)| {
+			Ok(CollateralUtxo {
+				outpoint: OutPoint::new(Txid::from_str(&txid)?, u32::try_from(vout)?),
+				amount: Amount::from_sat(u64::try_from(sats)?),
+				height: height.map(u32::try_from).transpose()?,
+			})
+		})
+		.collect()
+}
+
+/// Advances an approved loan to funded
+pub async fn mark_loan_funded(pool: &PgPool, loan_request_id: Uuid) -> Result<()> {
+	sqlx::query(
+		"UPDATE loan_request SET status = 'funded', updated_at = NOW()
+		WHERE id = $1 AND status = 'approved'",
+	)
+	.bind(loan_request_id)
+	.execute(pool)
+	.await?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::hashes::Hash;
+
+	fn utxo(byte: u8, height: Option<u32>) -> CollateralUtxo {
+		CollateralUtxo {
+			outpoint: OutPoint::new(Txid::from_byte_array([byte; 32]), 0),
+			amount: Amount::from_sat(500_000),
+			height,
+		}
+	}
+
+	#[sqlx::test(fixtures("loan"))]
+	#[ignore = "needs a Postgres server in DATABASE_URL"]
+	async fn test_unconfirmed_utxo_left_mempool(pool: PgPool) -> Result<()> {
+		let collateral_id = Uuid::parse_str("00000000-0000-0000-0000-000000000006")?;
+		let loan_request_id = Uuid::parse_str("00000000-0000-0000-0000-000000000005")?;
+		let (replaced, confirmed) = (utxo(1, None), utxo(2, Some(100)));
+
+		record_batch_utxos(&pool, &[(collateral_id, replaced.clone())]).await?;
+		record_utxo(&pool, collateral_id, &confirmed, 1).await?;
+		assert_eq!(get_unspent_utxos(&pool, loan_request_id).await?.len(), 2);
+
+		mark_missing_spent(&pool, collateral_id, &[confirmed.outpoint]).await?;
+		assert_eq!(
+			get_unspent_utxos(&pool, loan_request_id).await?,
+			vec![confirmed]
+		);
+		Ok(())
+	}
+
+	#[sqlx::test(fixtures("loan"))]
+	#[ignore = "needs a Postgres server in DATABASE_URL"]
+	async fn test_batch_is_recorded_atomically(pool: PgPool) -> Result<()> {
+		let collateral_id = Uuid::parse_str("00000000-0000-0000-0000-000000000006")?;
+		let loan_request_id = Uuid::parse_str("00000000-0000-0000-0000-000000000005")?;
+		let unknown_collateral = Uuid::from_bytes([9; 16]);
+
+		let result = record_batch_utxos(
+			&pool,
+			&[
+				(collateral_id, utxo(1, None)),
+				(unknown_collateral, utxo(2, None)),
+			],
+		)
+		.await;
+
+		assert!(result.is_err());
+		assert!(get_unspent_utxos(&pool, loan_request_id).await?.is_empty());
+		Ok(())
+	}
+}
@@ -0,0 +1,20 @@
+-- an approved loan with its 2-of-3 collateral
+insert into "user" (id, username, email, password_hash) values
+	('00000000-0000-0000-0000-000000000001', 'borrower', 'borrower@example.com', 'hash'),
+	('00000000-0000-0000-0000-000000000002', 'lender', 'lender@example.com', 'hash');
+
+insert into borrower (id, user_id, payout_address) values
+	('00000000-0000-0000-0000-000000000003', '00000000-0000-0000-0000-000000000001',
+	'bcrt1q8ucxfsyajsdghspzpn8mx8m7gyfv0c8jfn60m7');
+
+insert into lender (id, user_id) values
+	('00000000-0000-0000-0000-000000000004', '00000000-0000-0000-0000-000000000002');
+
+insert into loan_request (id, borrower_id, lender_id, status) values
+	('00000000-0000-0000-0000-000000000005', '00000000-0000-0000-0000-000000000003',
+	'00000000-0000-0000-0000-000000000004', 'approved');
+
+insert into collateral (id, loan_request_id, redeem_script, multisig_address) values
+	('00000000-0000-0000-0000-000000000006', '00000000-0000-0000-0000-000000000005',
+	'522102f0eaa04e609b0044ef1fe09a350dc4b744a5a8604a6fa77bc9bf6443ea50739f21037c60db011a840523f216e7198054ef071c5acd3d4b466cf2658b7faf30c11e332102ca49f36d3de1e135e033052611dd0873af55b57f07d5d0d1090ceb267ac34e6b53ae',
+	'bcrt1qt8aseu8nm4zah5sdj44gedqmuty3t32k59959vu7k6t72dy8n82qqhrec3');
@@ -0,0 +1,4 @@
+pub mod collateral;
+pub mod collateral_utxo;
+pub mod party_xpub;
+pub mod transaction_replacement;
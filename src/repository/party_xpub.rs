@@ -0,0 +1,46 @@
+use crate::domain::collateral_descriptor::KeyOrigin;
+use crate::domain::key_derivation::PartyXpub;
+use crate::domain::party_role::PartyRole;
+use anyhow::{anyhow, Result};
+use bitcoin::bip32::{DerivationPath, Fingerprint, Xpub};
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use std::str::FromStr;
+
+pub async fn insert_party_xpub(
+	pool: &PgPool,
+	user_id: Option<Uuid>,
+	role: PartyRole,
+	party: &PartyXpub,
+) -> Result<Uuid> {
+	let (id,): (Uuid,) = sqlx::query_as(
+		"INSERT INTO party_xpub (user_id, role, xpub, master_fingerprint, derivation_path)
+		VALUES ($1, $2, $3, $4, $5) RETURNING id",
+	)
+	.bind(user_id)
+	.bind(role)
+	.bind(party.xpub.to_string())
+	.bind(party.origin.fingerprint.to_string())
+	.bind(party.origin.path.to_string())
+	.fetch_one(pool)
+	.await?;
+
+	Ok(id)
+}
+
+pub async fn get_party_xpub(pool: &PgPool, id: Uuid) -> Result<PartyXpub> {
+	let (xpub, fingerprint, path): (String, String, String) = sqlx::query_as(
+		"SELECT xpub, master_fingerprint, derivation_path FROM party_xpub WHERE id = $1",
+	)
+	.bind(id)
+	.fetch_one(pool)
+	.await?;
+
+	let xpub = Xpub::from_str(&xpub).map_err(|e| anyhow!("Invalid stored xpub: {:?}", e))?;
+	let fingerprint = Fingerprint::from_str(&fingerprint)
+		.map_err(|e| anyhow!("Invalid stored fingerprint: {:?}", e))?;
+	let path = DerivationPath::from_str(&path)
+		.map_err(|e| anyhow!("Invalid stored derivation path: {:?}", e))?;
+
+	Ok(PartyXpub::new(xpub, KeyOrigin { fingerprint, path }))
+}
@@ -0,0 +1,193 @@
+use crate::config::TxnKind;
+use anyhow::{anyhow, Result};
+use bitcoin::{Amount, Txid};
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+use std::str::FromStr;
+
+/// Records that `replacement` was built to replace `original` with a higher fee
+pub async fn record_replacement(
+	pool: &PgPool,
+	loan_request_id: Uuid,
+	kind: TxnKind,
+	original: Txid,
+	replacement: Txid,
+	fee: Amount,
+) -> Result<()> {
+	let fee_sats = i64::try_from(fee.to_sat()).map_err(|_| anyhow!("Fee too large: {}", fee))?;
+
+	sqlx::query(
+		"INSERT INTO transaction_replacement
+		(loan_request_id, kind, original_txid, replacement_txid, fee_sats)
+		VALUES ($1, $2, $3, $4, $5)",
+	)
+	.bind(loan_request_id)
+	.bind(kind)
+	.bind(original.to_string())
+	.bind(replacement.to_string())
+	.bind(fee_sats)
+	.execute(pool)
+	.await?;
+
+	Ok(())
+}
+
+/// `txid` followed by each transaction that replaced it, the latest last
+pub async fn replacement_chain(pool: &PgPool, txid: Txid) -> Result<Vec<Txid>> {
+	let rows: Vec<(String,)> = sqlx::query_as(
+		"WITH RECURSIVE chain (txid, depth) AS (
+			SELECT $1::text, 0
+			UNION ALL
+			SELECT r.replacement_txid, chain.depth + 1
+			FROM transaction_replacement r JOIN chain ON r.original_txid = chain.txid
+		)
+		SELECT txid FROM chain ORDER BY depth",
+	)
+	.bind(txid.to_string())
+	.fetch_all(pool)
+	.await?;
+
+	rows.into_iter()
+		.map(|(txid,)| Ok(Txid::from_str(&txid)?))
+		.collect()
+}
+
+/// The transaction that currently stands for `txid`: the last replacement recorded for it,
+/// or `txid` itself
+pub async fn latest_replacement(pool: &PgPool, txid: Txid) -> Result<Txid> {
+	Ok(replacement_chain(pool, txid)
+		.await?
+		.last()
+		.copied()
+		.unwrap_or(txid))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::domain::fee_bump::record_signed_bump;
+	use bitcoin::absolute::LockTime;
+	use bitcoin::hashes::Hash;
+	use bitcoin::transaction::Version;
+	use bitcoin::{
+		OutPoint, Psbt, ScriptBuf, Sequence, Transaction, TxIn, TxOut, WPubkeyHash, Witness,
+	};
+
+	fn txid(byte: u8) -> Txid {
+		Txid::from_byte_array([byte; 32])
+	}
+
+	#[sqlx::test(fixtures("loan"))]
+	#[ignore = "needs a Postgres server in DATABASE_URL"]
+	async fn test_replacement_chain(pool: PgPool) -> Result<()> {
+		let loan_request_id = Uuid::parse_str("00000000-0000-0000-0000-000000000005")?;
+		for (original, replacement) in [(1, 2), (2, 3)] {
+			record_replacement(
+				&pool,
+				loan_request_id,
+				TxnKind::Funding,
+				txid(original),
+				txid(replacement),
+				Amount::from_sat(1_000 * replacement as u64),
+			)
+			.await?;
+		}
+
+		assert_eq!(
+			replacement_chain(&pool, txid(1)).await?,
+			vec![txid(1), txid(2), txid(3)]
+		);
+		assert_eq!(
+			replacement_chain(&pool, txid(2)).await?,
+			vec![txid(2), txid(3)]
+		);
+		assert_eq!(replacement_chain(&pool, txid(9)).await?, vec![txid(9)]);
+		assert_eq!(latest_replacement(&pool, txid(1)).await?, txid(3));
+		Ok(())
+	}
+
+	#[sqlx::test(fixtures("loan"))]
+	#[ignore = "needs a Postgres server in DATABASE_URL"]
+	async fn test_replacement_recorded_once(pool: PgPool) -> Result<()> {
+		let loan_request_id = Uuid::parse_str("00000000-0000-0000-0000-000000000005")?;
+		let record = || {
+			record_replacement(
+				&pool,
+				loan_request_id,
+				TxnKind::Redemption,
+				txid(1),
+				txid(2),
+				Amount::from_sat(2_000),
+			)
+		};
+
+		record().await?;
+		assert!(record().await.is_err());
+		Ok(())
+	}
+
+	/// A funding transaction paying 100,000 with 50,000 change, re-signed at a higher fee
+	/// and finalized as the borrower's wallet returns it
+	fn signed_funding_bump(fee: u64) -> Psbt {
+		let transaction = Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: vec![TxIn {
+				previous_output: OutPoint::new(txid(7), 0),
+				script_sig: ScriptBuf::new(),
+				sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+				witness: Witness::new(),
+			}],
+			output: vec![
+				TxOut {
+					value: Amount::from_sat(100_000),
+					script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+				},
+				TxOut {
+					value: Amount::from_sat(50_000 - fee),
+					script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+				},
+			],
+		};
+		let mut psbt = Psbt::from_unsigned_tx(transaction).unwrap();
+		psbt.inputs[0].witness_utxo = Some(TxOut {
+			value: Amount::from_sat(150_000),
+			script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::all_zeros()),
+		});
+		psbt.inputs[0].final_script_witness =
+			Some(Witness::from_slice(&[vec![1u8; 72], vec![2u8; 33]]));
+		psbt
+	}
+
+	#[sqlx::test(fixtures("loan"))]
+	#[ignore = "needs a Postgres server in DATABASE_URL"]
+	async fn test_signed_funding_bumps_are_chained(pool: PgPool) -> Result<()> {
+		let loan_request_id = Uuid::parse_str("00000000-0000-0000-0000-000000000005")?;
+		let original = txid(1);
+		let bump = |fee| {
+			let pool = pool.clone();
+			async move {
+				record_signed_bump(
+					&pool,
+					loan_request_id,
+					TxnKind::Funding,
+					original,
+					&signed_funding_bump(fee),
+				)
+				.await
+				.map_err(|e| anyhow!(e))
+			}
+		};
+
+		let first = bump(1_000).await?;
+		assert_eq!(latest_replacement(&pool, original).await?, first);
+
+		// the second bump is asked for by the original txid and recorded against the first
+		let second = bump(2_000).await?;
+		assert_eq!(
+			replacement_chain(&pool, original).await?,
+			vec![original, first, second]
+		);
+		Ok(())
+	}
+}
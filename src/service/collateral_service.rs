@@ -0,0 +1,199 @@
+use crate::domain::collateral_descriptor::{KeyOrigin, MultisigKind};
+use crate::domain::key_derivation::PartyXpub;
+use crate::domain::party_role::PartyRole;
+use crate::domain::MultisigAddress;
+use crate::repository::collateral::{create_derived_collateral, get_collateral_address};
+use crate::repository::party_xpub::insert_party_xpub;
+use crate::startup::AppState;
+use crate::utils::validate_publickeys::PublicKeyError;
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound};
+use actix_web::{web, HttpResponse};
+use bitcoin::bip32::{DerivationPath, Fingerprint, Xpub};
+use bitcoin::{PublicKey, ScriptBuf};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use std::str::FromStr;
+
+#[derive(Debug, Serialize)]
+struct CollateralAddressResponse {
+	loan_id: String,
+	borrower_pubkey: String,
+	lender_pubkey: String,
+	service_pubkey: String,
+	borrower_key_origin: Option<String>,
+	lender_key_origin: Option<String>,
+	service_key_origin: Option<String>,
+	redeem_script: String,
+	descriptor: String,
+	address: String,
+}
+
+/// Returns everything a party needs to recompute the collateral address of a loan
+pub async fn get_collateral_address_info(
+	path: web::Path<String>,
+	data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+	let loan_id = Uuid::parse_str(&path.into_inner()).map_err(ErrorBadRequest)?;
+
+	let record = get_collateral_address(&data.db, loan_id)
+		.await
+		.map_err(ErrorInternalServerError)?
+		.ok_or_else(|| ErrorNotFound("No collateral address for this loan"))?;
+
+	let origin = |origin: &Option<KeyOrigin>| origin.as_ref().map(|origin| origin.to_string());
+	let multisig = &record.multisig;
+
+	Ok(HttpResponse::Ok().json(CollateralAddressResponse {
+		loan_id: loan_id.to_string(),
+		borrower_pubkey: multisig.borrower_pubkey.to_string(),
+		lender_pubkey: multisig.lender_pubkey.to_string(),
+		service_pubkey: multisig.service_pubkey.to_string(),
+		borrower_key_origin: origin(&record.origins.borrower),
+		lender_key_origin: origin(&record.origins.lender),
+		service_key_origin: origin(&record.origins.service),
+		redeem_script: multisig.redeem_script().to_hex_string(),
+		descriptor: multisig
+			.to_descriptor(MultisigKind::Multi, record.origins.clone())
+			.to_string(),
+		address: record.multisig_address,
+	}))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VerifyAddressRequest {
+	borrower_pubkey: String,
+	lender_pubkey: String,
+	service_pubkey: String,
+	redeem_script: Option<String>,
+	address: String,
+}
+
+#[derive(Debug, Serialize)]
+struct VerifyAddressResponse {
+	valid: bool,
+	redeem_script: String,
+	address: String,
+}
+
+/// Recomputes the P2WSH address from the submitted keys, independently of any stored data
+pub async fn verify_collateral_address(
+	request: web::Json<VerifyAddressRequest>,
+) -> Result<HttpResponse, actix_web::Error> {
+	let pubkey = |key: &str| {
+		PublicKey::from_str(key)
+			.map_err(|e| ErrorBadRequest(format!("Invalid public key {}: {:?}", key, e)))
+	};
+
+	let multisig = MultisigAddress::new(
+		pubkey(&request.borrower_pubkey)?,
+		pubkey(&request.lender_pubkey)?,
+		pubkey(&request.service_pubkey)?,
+	);
+	let redeem_script = multisig.redeem_script();
+
+	let script_matches = match &request.redeem_script {
+		Some(script) => {
+			ScriptBuf::from_hex(script)
+				.map_err(|e| ErrorBadRequest(format!("Invalid redeem script: {:?}", e)))?
+				== redeem_script
+		}
+		None => true,
+	};
+	let address_matches = multisig
+		.verify_p2wsh_address(&request.address)
+		.map_err(ErrorBadRequest)?;
+
+	Ok(HttpResponse::Ok().json(VerifyAddressResponse {
+		valid: script_matches && address_matches,
+		redeem_script: redeem_script.to_hex_string(),
+		address: multisig.create_p2wsh_address().to_string(),
+	}))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterXpubRequest {
+	user_id: Option<String>,
+	role: String,
+	xpub: String,
+	master_fingerprint: String,
+	derivation_path: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RegisterXpubResponse {
+	xpub_id: String,
+}
+
+/// Registers a party's account xpub, from which the keys of each of their loans are derived
+pub async fn register_party_xpub(
+	request: web::Json<RegisterXpubRequest>,
+	data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+	let user_id = match &request.user_id {
+		Some(user_id) => Some(Uuid::parse_str(user_id).map_err(ErrorBadRequest)?),
+		None => None,
+	};
+	let role = match request.role.as_str() {
+		"borrower" => PartyRole::Borrower,
+		"lender" => PartyRole::Lender,
+		"service" => PartyRole::Service,
+		role => return Err(ErrorBadRequest(format!("Unknown role: {}", role))),
+	};
+	let xpub = Xpub::from_str(&request.xpub)
+		.map_err(|e| ErrorBadRequest(format!("Invalid xpub: {:?}", e)))?;
+	let fingerprint = Fingerprint::from_str(&request.master_fingerprint)
+		.map_err(|e| ErrorBadRequest(format!("Invalid fingerprint: {:?}", e)))?;
+	let path = DerivationPath::from_str(&request.derivation_path)
+		.map_err(|e| ErrorBadRequest(format!("Invalid derivation path: {:?}", e)))?;
+
+	let party = PartyXpub::new(xpub, KeyOrigin { fingerprint, path });
+	let xpub_id = insert_party_xpub(&data.db, user_id, role, &party)
+		.await
+		.map_err(ErrorInternalServerError)?;
+
+	Ok(HttpResponse::Created().json(RegisterXpubResponse {
+		xpub_id: xpub_id.to_string(),
+	}))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateCollateralRequest {
+	borrower_xpub_id: String,
+	lender_xpub_id: String,
+	service_xpub_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CreateCollateralResponse {
+	collateral_id: String,
+	redeem_script: String,
+	address: String,
+}
+
+/// Creates the collateral address of a loan from the parties' registered xpubs
+pub async fn create_loan_collateral(
+	path: web::Path<String>,
+	request: web::Json<CreateCollateralRequest>,
+	data: web::Data<AppState>,
+) -> Result<HttpResponse, actix_web::Error> {
+	let loan_id = Uuid::parse_str(&path.into_inner()).map_err(ErrorBadRequest)?;
+	let xpub_id = |id: &str| Uuid::parse_str(id).map_err(ErrorBadRequest);
+	let xpub_ids = (
+		xpub_id(&request.borrower_xpub_id)?,
+		xpub_id(&request.lender_xpub_id)?,
+		xpub_id(&request.service_xpub_id)?,
+	);
+
+	let (collateral_id, multisig) = create_derived_collateral(&data.db, loan_id, xpub_ids)
+		.await
+		.map_err(|e| match e.downcast::<PublicKeyError>() {
+			Ok(key_error) => actix_web::Error::from(key_error),
+			Err(e) => ErrorInternalServerError(e),
+		})?;
+
+	Ok(HttpResponse::Created().json(CreateCollateralResponse {
+		collateral_id: collateral_id.to_string(),
+		redeem_script: multisig.redeem_script().to_hex_string(),
+		address: multisig.create_p2wsh_address().to_string(),
+	}))
+}
@@ -0,0 +1,245 @@
+use crate::config::WatcherSettings;
+use crate::constants::set_network;
+use crate::domain::collateral_utxo::{CollateralBalance, CollateralUtxo};
+use crate::repository::collateral_utxo::{
+	mark_loan_funded, mark_missing_spent, mark_watch_imported, record_utxo, watched_collaterals,
+	WatchedCollateral,
+};
+use crate::utils::bitcoind_rpc::{get_tip_height, import_watch_address, list_address_unspent};
+use crate::utils::validate_address::validate_address;
+use actix_web::rt::{task, time};
+use anyhow::Result;
+use bitcoin::{Address, OutPoint, ScriptBuf};
+use bitcoincore_rpc::json::ListUnspentResultEntry;
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// Watches the multisig address of every approved or funded loan through the node's
+/// wallet, records each deposit and top-up as soon as it is in the mempool, and marks
+/// the loan funded once the required collateral has enough confirmations
+pub struct DepositWatcher {
+	pool: PgPool,
+	settings: WatcherSettings,
+}
+
+impl DepositWatcher {
+	pub fn new(pool: PgPool, settings: WatcherSettings) -> Self {
+		Self { pool, settings }
+	}
+
+	/// Polls until the process exits; failed polls are logged and retried
+	pub async fn run(self) {
+		let interval = Duration::from_secs(self.settings.poll_interval_secs);
+		loop {
+			if let Err(error) = self.poll().await {
+				log::error!("Deposit watcher failed: {:?}", error);
+			}
+			time::sleep(interval).await;
+		}
+	}
+
+	/// Updates every watched collateral; one that cannot be imported or updated is logged
+	/// and skipped so it does not hold up the other loans
+	pub async fn poll(&self) -> Result<()> {
+		let watched = watched_collaterals(&self.pool).await?;
+
+		let mut tracked = Vec::new();
+		for (collateral, address) in valid_addresses(&watched) {
+			if !collateral.imported {
+				if let Err(error) = self.import(collateral).await {
+					log::error!(
+						"Error watching the collateral of loan {}: {:?}",
+						collateral.loan_request_id,
+						error
+					);
+					continue;
+				}
+			}
+			tracked.push((collateral, address));
+		}
+		if tracked.is_empty() {
+			return Ok(());
+		}
+
+		let addresses: Vec<Address> = tracked.iter().map(|(_, address)| address.clone()).collect();
+		let (tip_height, unspent) = task::spawn_blocking(move || -> Result<_> {
+			let tip_height = get_tip_height(None)? as u32;
+			Ok((tip_height, list_address_unspent(&addresses, None)?))
+		})
+		.await??;
+
+		for (collateral, address) in tracked {
+			let utxos = utxos_at(&address.script_pubkey(), &unspent, tip_height);
+			if let Err(error) = self.update(collateral, &utxos, tip_height).await {
+				log::error!(
+					"Error updating the collateral of loan {}: {:?}",
+					collateral.loan_request_id,
+					error
+				);
+			}
+		}
+		Ok(())
+	}
+
+	/// Imports the address into the node wallet, rescanning the blocks since the
+	/// collateral was created
+	async fn import(&self, collateral: &WatchedCollateral) -> Result<()> {
+		let address = collateral.multisig_address.clone();
+		let created_at = collateral.created_at;
+		task::spawn_blocking(move || import_watch_address(&address, created_at, None)).await??;
+		mark_watch_imported(&self.pool, collateral.collateral_id).await
+	}
+
+	async fn update(
+		&self,
+		collateral: &WatchedCollateral,
+		utxos: &[CollateralUtxo],
+		tip_height: u32,
+	) -> Result<()> {
+		for utxo in utxos {
+			record_utxo(
+				&self.pool,
+				collateral.collateral_id,
+				utxo,
+				utxo.confirmations(tip_height),
+			)
+			.await?;
+		}
+		let unspent: Vec<OutPoint> = utxos.iter().map(|utxo| utxo.outpoint).collect();
+		mark_missing_spent(&self.pool, collateral.collateral_id, &unspent).await?;
+
+		let balance = CollateralBalance::from_utxos(utxos, tip_height, self.settings.confirmations);
+		if balance.is_funded(collateral.required_amount) {
+			mark_loan_funded(&self.pool, collateral.loan_request_id).await?;
+		}
+		Ok(())
+	}
+}
+
+/// Watched collaterals with their address; a malformed address is logged and skipped
+fn valid_addresses(watched: &[WatchedCollateral]) -> Vec<(&WatchedCollateral, Address)> {
+	watched
+		.iter()
+		.filter_map(|collateral| {
+			match validate_address(&collateral.multisig_address, set_network()) {
+				Ok(address) => Some((collateral, address)),
+				Err(error) => {
+					log::error!(
+						"Collateral of loan {} has an invalid address: {}",
+						collateral.loan_request_id,
+						error
+					);
+					None
+				}
+			}
+		})
+		.collect()
+}
+
+/// The wallet's unspent outputs locked by the collateral script; outputs still in the
+/// mempool have no height
+fn utxos_at(
+	script_pubkey: &ScriptBuf,
+	unspent: &[ListUnspentResultEntry],
+	tip_height: u32,
+) -> Vec<CollateralUtxo> {
+	unspent
+		.iter()
+		.filter(|utxo| &utxo.script_pub_key == script_pubkey)
+		.map(|utxo| CollateralUtxo {
+			outpoint: OutPoint::new(utxo.txid, utxo.vout),
+			amount: utxo.amount,
+			height: match utxo.confirmations {
+				0 => None,
+				confirmations => Some((tip_height + 1).saturating_sub(confirmations)),
+			},
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::hashes::Hash;
+	use bitcoin::{Amount, Network, Txid};
+	use sqlx::types::Uuid;
+
+	fn unspent(
+		vout: u32,
+		sats: u64,
+		confirmations: u32,
+		script: &ScriptBuf,
+	) -> ListUnspentResultEntry {
+		ListUnspentResultEntry {
+			txid: Txid::all_zeros(),
+			vout,
+			address: None,
+			label: None,
+			redeem_script: None,
+			witness_script: None,
+			script_pub_key: script.clone(),
+			amount: Amount::from_sat(sats),
+			confirmations,
+			spendable: false,
+			solvable: false,
+			descriptor: None,
+			safe: confirmations > 0,
+		}
+	}
+
+	#[test]
+	fn test_unconfirmed_deposit() {
+		let collateral = validate_address(
+			"bcrt1qt8aseu8nm4zah5sdj44gedqmuty3t32k59959vu7k6t72dy8n82qqhrec3",
+			Network::Regtest,
+		)
+		.unwrap()
+		.script_pubkey();
+		let other = validate_address(
+			"bcrt1qeygjhsgt5sumtlqnyfu58harh3737z96m0zmqv",
+			Network::Regtest,
+		)
+		.unwrap()
+		.script_pubkey();
+		let wallet = [
+			unspent(0, 600_000, 0, &collateral),
+			unspent(1, 400_000, 3, &collateral),
+			unspent(2, 900_000, 0, &other),
+		];
+
+		let utxos = utxos_at(&collateral, &wallet, 100);
+
+		assert_eq!(utxos.len(), 2);
+		assert_eq!(utxos[0].height, None);
+		assert_eq!(utxos[1].height, Some(98));
+
+		let balance = CollateralBalance::from_utxos(&utxos, 100, 3);
+		assert_eq!(balance.unconfirmed, Amount::from_sat(600_000));
+		assert_eq!(balance.confirmed, Amount::from_sat(400_000));
+		assert!(!balance.is_funded(Amount::from_sat(1_000_000)));
+	}
+
+	#[test]
+	fn test_invalid_address_is_skipped() {
+		let watched = |multisig_address: &str| WatchedCollateral {
+			collateral_id: Uuid::nil(),
+			loan_request_id: Uuid::nil(),
+			multisig_address: multisig_address.to_string(),
+			required_amount: Amount::from_sat(1_000_000),
+			created_at: 0,
+			imported: true,
+		};
+		let collaterals = [
+			watched("not an address"),
+			watched("bcrt1qt8aseu8nm4zah5sdj44gedqmuty3t32k59959vu7k6t72dy8n82qqhrec3"),
+		];
+
+		let valid = valid_addresses(&collaterals);
+
+		assert_eq!(valid.len(), 1);
+		assert_eq!(
+			valid[0].0.multisig_address,
+			"bcrt1qt8aseu8nm4zah5sdj44gedqmuty3t32k59959vu7k6t72dy8n82qqhrec3"
+		);
+	}
+}
@@ -1,3 +1,5 @@
+pub mod collateral_service;
+pub mod deposit_watcher;
 mod health_check;
 pub mod wallet_service;
 
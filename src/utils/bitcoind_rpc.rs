@@ -1,6 +1,10 @@
 use crate::constants::{environment_vars, set_network};
 use anyhow::{anyhow, Result};
-use bitcoin::{Network, Transaction, TxOut, Txid};
+use bitcoin::{Address, Amount, Network, ScriptBuf, Transaction, TxOut, Txid};
+use bitcoincore_rpc::json::{
+	GetMempoolEntryResult, ImportDescriptors, ImportMultiResult, ListUnspentResultEntry,
+	TestMempoolAcceptResult, Timestamp,
+};
 use bitcoincore_rpc::{Auth, Client, Error, RpcApi};
 
 pub fn connect_bitcoind() -> Client {
@@ -23,13 +27,24 @@ pub fn connect_bitcoind() -> Client {
 	rpc_client
 }
 
-pub fn get_outpoint_value(txid: Txid, vout: u32, client: Option<&Client>) -> anyhow::Result<f64> {
-	let outpoint_value = if set_network() == Network::Regtest && client.is_some() {
-		let rpc = client.expect("Failed to obtain Bitcoin Core RPC client");
-		rpc.get_tx_out(&txid, vout, Some(false))?
-	} else {
-		let rpc = connect_bitcoind();
-		rpc.get_tx_out(&txid, vout, Some(false))?
+pub fn get_outpoint_value(
+	txid: Txid,
+	vout: u32,
+	client: Option<&Client>,
+) -> anyhow::Result<Amount> {
+	Ok(get_outpoint_txout(txid, vout, client)?.value)
+}
+
+/// The unspent output at the given outpoint, including its script pubkey
+pub fn get_outpoint_txout(txid: Txid, vout: u32, client: Option<&Client>) -> anyhow::Result<TxOut> {
+	let outpoint_value = match client {
+		Some(rpc) if set_network() == Network::Regtest => {
+			rpc.get_tx_out(&txid, vout, Some(false))?
+		}
+		_ => {
+			let rpc = connect_bitcoind();
+			rpc.get_tx_out(&txid, vout, Some(false))?
+		}
 	};
 
 	let tx_result = match outpoint_value {
@@ -37,7 +52,44 @@ pub fn get_outpoint_value(txid: Txid, vout: u32, client: Option<&Client>) -> any
 		None => return Err(anyhow!("Error getting UTXO value for for txid: {:?}", txid)),
 	};
 
-	Ok(tx_result.value.to_btc())
+	Ok(TxOut {
+		value: tx_result.value,
+		script_pubkey: ScriptBuf::from_bytes(tx_result.script_pub_key.hex),
+	})
+}
+
+pub fn get_tip_height(client: Option<&Client>) -> Result<u64, Error> {
+	match client {
+		Some(rpc) if set_network() == Network::Regtest => rpc.get_block_count(),
+		_ => {
+			let rpc = connect_bitcoind();
+			rpc.get_block_count()
+		}
+	}
+}
+
+pub fn get_transaction(txid: Txid, client: Option<&Client>) -> Result<Transaction, Error> {
+	match client {
+		Some(rpc) if set_network() == Network::Regtest => rpc.get_raw_transaction(&txid, None),
+		_ => {
+			let rpc = connect_bitcoind();
+			rpc.get_raw_transaction(&txid, None)
+		}
+	}
+}
+
+/// Size and fees of an unconfirmed transaction and its unconfirmed ancestors
+pub fn get_mempool_entry(
+	txid: Txid,
+	client: Option<&Client>,
+) -> Result<GetMempoolEntryResult, Error> {
+	match client {
+		Some(rpc) if set_network() == Network::Regtest => rpc.get_mempool_entry(&txid),
+		_ => {
+			let rpc = connect_bitcoind();
+			rpc.get_mempool_entry(&txid)
+		}
+	}
 }
 
 pub fn get_transaction_output(
@@ -45,25 +97,103 @@ pub fn get_transaction_output(
 	vout: u32,
 	client: Option<&Client>,
 ) -> Result<(bool, Option<TxOut>, Transaction), Error> {
-	let txn = if set_network() == Network::Regtest && client.is_some() {
-		let rpc = client.expect("Failed to obtain Bitcoin Core RPC client");
-		rpc.get_raw_transaction(&txid, None)?
-	} else {
-		let rpc = connect_bitcoind();
-		rpc.get_raw_transaction(&txid, None)?
-	};
+	let txn = get_transaction(txid, client)?;
 
 	let is_segwit_txn = !txn.input.iter().all(|input| input.witness.is_empty());
 
 	let index = vout as usize;
 	Ok((is_segwit_txn, txn.output.get(index).cloned(), txn))
 }
+
+/// Adds the address to the node's wallet as a watch-only descriptor, rescanning the
+/// blocks from `timestamp` so deposits made before the import are found
+pub fn import_watch_address(
+	address: &str,
+	timestamp: u64,
+	client: Option<&Client>,
+) -> anyhow::Result<()> {
+	let import = |rpc: &Client| -> anyhow::Result<Vec<ImportMultiResult>> {
+		let descriptor = rpc.get_descriptor_info(&format!("addr({})", address))?;
+		Ok(rpc.import_descriptors(ImportDescriptors {
+			descriptor: descriptor.descriptor,
+			timestamp: Timestamp::Time(timestamp),
+			active: None,
+			range: None,
+			next_index: None,
+			internal: None,
+			label: None,
+		})?)
+	};
+	let results = match client {
+		Some(rpc) if set_network() == Network::Regtest => import(rpc)?,
+		_ => import(&connect_bitcoind())?,
+	};
+
+	match results.into_iter().next() {
+		Some(result) if result.success => Ok(()),
+		Some(result) => Err(anyhow!("Error importing {}: {:?}", address, result.error)),
+		None => Err(anyhow!("No importdescriptors result for {}", address)),
+	}
+}
+
+/// Unspent outputs paying to any of the watched addresses, including those still in
+/// the mempool
+pub fn list_address_unspent(
+	addresses: &[Address],
+	client: Option<&Client>,
+) -> anyhow::Result<Vec<ListUnspentResultEntry>> {
+	let addresses: Vec<&Address> = addresses.iter().collect();
+	let unspent = match client {
+		Some(rpc) if set_network() == Network::Regtest => {
+			rpc.list_unspent(Some(0), None, Some(&addresses), Some(true), None)?
+		}
+		_ => {
+			let rpc = connect_bitcoind();
+			rpc.list_unspent(Some(0), None, Some(&addresses), Some(true), None)?
+		}
+	};
+	Ok(unspent)
+}
+
+/// Asks bitcoind whether the transaction would be accepted to its mempool, without
+/// broadcasting it
+pub fn test_mempool_accept(
+	transaction: &Transaction,
+	client: Option<&Client>,
+) -> anyhow::Result<TestMempoolAcceptResult> {
+	let results = match client {
+		Some(rpc) if set_network() == Network::Regtest => {
+			rpc.test_mempool_accept(&[transaction])?
+		}
+		_ => {
+			let rpc = connect_bitcoind();
+			rpc.test_mempool_accept(&[transaction])?
+		}
+	};
+	results
+		.into_iter()
+		.next()
+		.ok_or_else(|| anyhow!("No testmempoolaccept result for {}", transaction.txid()))
+}
+
+pub fn broadcast_transaction(
+	transaction: &Transaction,
+	client: Option<&Client>,
+) -> Result<Txid, Error> {
+	match client {
+		Some(rpc) if set_network() == Network::Regtest => rpc.send_raw_transaction(transaction),
+		_ => {
+			let rpc = connect_bitcoind();
+			rpc.send_raw_transaction(transaction)
+		}
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use std::str::FromStr;
 
 	use crate::utils::test_node::TestNode;
-	use bitcoin::Amount;
 
 	use super::*;
 
@@ -88,7 +218,7 @@ mod test {
 		let outpoint_value =
 			get_outpoint_value(txid, vout_index, Some(&client.bitcoind.client)).unwrap();
 
-		assert_eq!(outpoint_value, 5.0);
+		assert_eq!(outpoint_value, Amount::from_int_btc(5));
 	}
 
 	#[test]
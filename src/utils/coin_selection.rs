@@ -0,0 +1,352 @@
+use crate::utils::miniscript_compat::{from_bdk_outpoint, from_bdk_txout};
+use crate::utils::weight_estimator::InputWeight;
+use bdk::database::BatchDatabase;
+use bdk::Wallet;
+use bitcoin::{Amount, FeeRate, OutPoint, ScriptBuf, TxOut};
+use std::cmp::Reverse;
+
+// version, locktime, input/output counts and the segwit marker, rounded up
+pub const TX_OVERHEAD_VBYTES: u64 = 11;
+// give up on branch-and-bound after this many steps and fall back to largest-first
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// A spendable output and the virtual size of the input that spends it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+	pub outpoint: OutPoint,
+	pub txout: TxOut,
+	pub input_vbytes: u64,
+}
+
+impl Utxo {
+	/// Estimates the input size from the script type of the output being spent
+	pub fn new(outpoint: OutPoint, txout: TxOut) -> Result<Self, String> {
+		let input_vbytes = input_vbytes(&txout.script_pubkey)?;
+		Ok(Self {
+			outpoint,
+			txout,
+			input_vbytes,
+		})
+	}
+
+	fn fee(&self, fee_rate: FeeRate) -> Amount {
+		fee_for_vbytes(fee_rate, self.input_vbytes)
+	}
+
+	/// Value left after paying for the input at the given fee rate
+	fn effective_value(&self, fee_rate: FeeRate) -> i64 {
+		self.txout.value.to_sat() as i64 - self.fee(fee_rate).to_sat() as i64
+	}
+}
+
+/// Worst-case virtual size of a single-key input spending the given script
+pub fn input_vbytes(script_pubkey: &ScriptBuf) -> Result<u64, String> {
+	Ok(InputWeight::from_script_pubkey(script_pubkey)?.vbytes())
+}
+
+pub fn output_vbytes(script_pubkey: &ScriptBuf) -> u64 {
+	// value, script length and script
+	8 + 1 + script_pubkey.len() as u64
+}
+
+pub fn fee_for_vbytes(fee_rate: FeeRate, vbytes: u64) -> Amount {
+	Amount::from_sat(fee_rate.to_sat_per_vb_ceil() * vbytes)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinSelection {
+	pub selected: Vec<Utxo>,
+	pub fee: Amount,
+	/// None when the excess was too small for a change output and went to fees
+	pub change: Option<Amount>,
+}
+
+impl CoinSelection {
+	pub fn outpoints(&self) -> Vec<OutPoint> {
+		self.selected.iter().map(|utxo| utxo.outpoint).collect()
+	}
+
+	pub fn input_total(&self) -> Amount {
+		self.selected.iter().map(|utxo| utxo.txout.value).sum()
+	}
+}
+
+/// What the selected coins must pay for
+#[derive(Debug, Clone)]
+pub struct SelectionTarget {
+	/// sum of the non-change outputs
+	pub amount: Amount,
+	pub fee_rate: FeeRate,
+	/// size of the transaction without inputs or change output
+	pub base_vbytes: u64,
+	pub change_script: ScriptBuf,
+}
+
+impl SelectionTarget {
+	fn change_output_fee(&self) -> Amount {
+		fee_for_vbytes(self.fee_rate, output_vbytes(&self.change_script))
+	}
+
+	fn base_fee(&self) -> Amount {
+		fee_for_vbytes(self.fee_rate, self.base_vbytes)
+	}
+}
+
+/// Selects coins for the target: branch-and-bound for a changeless match first,
+/// then largest-first with a change output
+pub fn select_coins(utxos: &[Utxo], target: &SelectionTarget) -> Result<CoinSelection, String> {
+	let needed = target
+		.amount
+		.checked_add(target.base_fee())
+		.ok_or_else(|| "Selection target overflows".to_string())?;
+
+	if let Some(selected) = branch_and_bound(utxos, target, needed) {
+		let input_total: Amount = selected.iter().map(|utxo| utxo.txout.value).sum();
+		return Ok(CoinSelection {
+			fee: input_total - target.amount,
+			selected,
+			change: None,
+		});
+	}
+
+	largest_first(utxos, target, needed)
+}
+
+/// Searches for a set of coins whose effective value covers `needed` without exceeding
+/// it by more than the cost of a change output, preferring the smallest excess
+fn branch_and_bound(utxos: &[Utxo], target: &SelectionTarget, needed: Amount) -> Option<Vec<Utxo>> {
+	let mut pool: Vec<(i64, &Utxo)> = utxos
+		.iter()
+		.map(|utxo| (utxo.effective_value(target.fee_rate), utxo))
+		.filter(|(value, _)| *value > 0)
+		.collect();
+	pool.sort_by_key(|(value, _)| Reverse(*value));
+
+	let cost_of_change = (target.change_output_fee()
+		+ target.change_script.dust_value()
+		+ fee_for_vbytes(
+			target.fee_rate,
+			input_vbytes(&target.change_script).unwrap_or(68),
+		))
+	.to_sat() as i64;
+	let needed = needed.to_sat() as i64;
+	let upper = needed + cost_of_change;
+
+	let mut remaining = vec![0i64; pool.len() + 1];
+	for i in (0..pool.len()).rev() {
+		remaining[i] = remaining[i + 1] + pool[i].0;
+	}
+	if remaining[0] < needed {
+		return None;
+	}
+
+	let mut search = BranchAndBound {
+		pool: &pool,
+		remaining: &remaining,
+		needed,
+		upper,
+		current: Vec::new(),
+		best: None,
+		tries: 0,
+	};
+	search.search(0, 0);
+
+	search
+		.best
+		.map(|(_, indexes)| indexes.iter().map(|i| pool[*i].1.clone()).collect())
+}
+
+struct BranchAndBound<'a> {
+	/// effective values, largest first
+	pool: &'a [(i64, &'a Utxo)],
+	/// remaining[i] is the total effective value of pool[i..]
+	remaining: &'a [i64],
+	needed: i64,
+	upper: i64,
+	current: Vec<usize>,
+	/// smallest excess found and the coins that give it
+	best: Option<(i64, Vec<usize>)>,
+	tries: usize,
+}
+
+impl BranchAndBound<'_> {
+	fn search(&mut self, index: usize, value: i64) {
+		self.tries += 1;
+		if self.tries > BNB_MAX_TRIES
+			|| value > self.upper
+			|| value + self.remaining[index] < self.needed
+		{
+			return;
+		}
+		if value >= self.needed {
+			let excess = value - self.needed;
+			if self
+				.best
+				.as_ref()
+				.map_or(true, |(waste, _)| excess < *waste)
+			{
+				self.best = Some((excess, self.current.clone()));
+			}
+			return;
+		}
+		if index == self.pool.len() {
+			return;
+		}
+
+		self.current.push(index);
+		self.search(index + 1, value + self.pool[index].0);
+		self.current.pop();
+		self.search(index + 1, value);
+	}
+}
+
+fn largest_first(
+	utxos: &[Utxo],
+	target: &SelectionTarget,
+	needed: Amount,
+) -> Result<CoinSelection, String> {
+	let mut pool: Vec<&Utxo> = utxos
+		.iter()
+		.filter(|utxo| utxo.effective_value(target.fee_rate) > 0)
+		.collect();
+	pool.sort_by_key(|utxo| Reverse(utxo.txout.value));
+
+	let with_change = needed + target.change_output_fee();
+	let mut selected = Vec::new();
+	let mut input_total = Amount::ZERO;
+	let mut input_fees = Amount::ZERO;
+
+	for utxo in pool {
+		selected.push(utxo.clone());
+		input_total += utxo.txout.value;
+		input_fees += utxo.fee(target.fee_rate);
+
+		if input_total >= with_change + input_fees {
+			let change = input_total - with_change - input_fees;
+			if change >= target.change_script.dust_value() {
+				return Ok(CoinSelection {
+					fee: input_total - target.amount - change,
+					selected,
+					change: Some(change),
+				});
+			}
+		}
+		if input_total >= needed + input_fees {
+			// excess does not pay for a change output above dust, leave it to the miners
+			return Ok(CoinSelection {
+				fee: input_total - target.amount,
+				selected,
+				change: None,
+			});
+		}
+	}
+
+	Err(format!(
+		"Insufficient funds: {} available, {} needed",
+		input_total,
+		needed + input_fees
+	))
+}
+
+/// Lists the unspent outputs of a BDK wallet as selection candidates
+pub fn utxos_from_wallet<D: BatchDatabase>(wallet: &Wallet<D>) -> Result<Vec<Utxo>, String> {
+	let unspent = wallet
+		.list_unspent()
+		.map_err(|e| format!("Error listing wallet UTXOs: {:?}", e))?;
+
+	unspent
+		.iter()
+		.filter(|utxo| !utxo.is_spent)
+		.map(|utxo| {
+			Utxo::new(
+				from_bdk_outpoint(&utxo.outpoint)?,
+				from_bdk_txout(&utxo.txout)?,
+			)
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::utils::validate_address::validate_address;
+	use bitcoin::hashes::Hash;
+	use bitcoin::{Network, Txid};
+
+	fn script() -> ScriptBuf {
+		validate_address(
+			"bcrt1qeygjhsgt5sumtlqnyfu58harh3737z96m0zmqv",
+			Network::Regtest,
+		)
+		.unwrap()
+		.script_pubkey()
+	}
+
+	fn utxos(values: &[u64]) -> Vec<Utxo> {
+		values
+			.iter()
+			.enumerate()
+			.map(|(vout, value)| {
+				Utxo::new(
+					OutPoint::new(Txid::all_zeros(), vout as u32),
+					TxOut {
+						value: Amount::from_sat(*value),
+						script_pubkey: script(),
+					},
+				)
+				.unwrap()
+			})
+			.collect()
+	}
+
+	fn target(amount: u64) -> SelectionTarget {
+		SelectionTarget {
+			amount: Amount::from_sat(amount),
+			fee_rate: FeeRate::from_sat_per_vb(2).unwrap(),
+			base_vbytes: TX_OVERHEAD_VBYTES + 43,
+			change_script: script(),
+		}
+	}
+
+	#[test]
+	fn test_branch_and_bound_exact_match() {
+		// 100_000 + 50_000 pays 149_620 plus 380 sats of fees for the base and two inputs
+		let selection = select_coins(&utxos(&[100_000, 70_000, 50_000]), &target(149_620)).unwrap();
+
+		assert_eq!(selection.change, None);
+		assert_eq!(selection.input_total(), Amount::from_sat(150_000));
+		assert_eq!(selection.fee, Amount::from_sat(380));
+	}
+
+	#[test]
+	fn test_largest_first_with_change() {
+		let selection =
+			select_coins(&utxos(&[1_000_000, 20_000, 30_000]), &target(40_000)).unwrap();
+
+		assert_eq!(selection.outpoints().len(), 1);
+		let change = selection.change.unwrap();
+		assert_eq!(
+			selection.input_total(),
+			Amount::from_sat(40_000) + selection.fee + change
+		);
+	}
+
+	#[test]
+	fn test_largest_first_excess_too_small_for_change() {
+		// the excess is exactly dust: too much to call dust, too little to pay for a
+		// change output worth more than dust
+		let target = target(40_000);
+		let input_fee = utxos(&[0])[0].fee(target.fee_rate);
+		let needed = target.amount + target.base_fee();
+		let value = needed + input_fee + target.change_script.dust_value();
+		let selection = largest_first(&utxos(&[value.to_sat()]), &target, needed).unwrap();
+
+		assert_eq!(selection.change, None);
+		assert_eq!(selection.fee, value - target.amount);
+	}
+
+	#[test]
+	fn test_insufficient_funds() {
+		assert!(select_coins(&utxos(&[10_000, 20_000]), &target(40_000)).is_err());
+	}
+}
@@ -1,6 +1,37 @@
+use bitcoin::FeeRate;
 use reqwest;
 use serde::{Deserialize, Serialize};
 
+/// Fee rate to build a transaction with: one of the mempool.space recommendations
+/// or an explicit rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeeTarget {
+	Fastest,
+	HalfHour,
+	Hour,
+	Economy,
+	Minimum,
+	/// explicit rate in sat/vB
+	SatPerVb(u64),
+}
+
+impl FeeTarget {
+	/// Resolves the target to a fee rate, only querying mempool.space when the rate
+	/// is not explicit
+	pub fn fee_rate(&self) -> Result<FeeRate, String> {
+		let sat_per_vb = match self {
+			FeeTarget::SatPerVb(rate) => *rate,
+			_ => get_mempool_feerate()?.rate_for(*self),
+		};
+		if sat_per_vb == 0 {
+			return Err("Fee rate must be at least 1 sat/vB".to_string());
+		}
+		FeeRate::from_sat_per_vb(sat_per_vb)
+			.ok_or_else(|| format!("Fee rate of {} sat/vB is out of range", sat_per_vb))
+	}
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MempoolSpaceFeeRate {
 	#[serde(rename = "fastestFee")]
@@ -15,6 +46,21 @@ pub struct MempoolSpaceFeeRate {
 	pub minimum_fee: usize,
 }
 
+impl MempoolSpaceFeeRate {
+	/// Rate in sat/vB recommended for the given target
+	pub fn rate_for(&self, target: FeeTarget) -> u64 {
+		let rate = match target {
+			FeeTarget::Fastest => self.fastest_fee,
+			FeeTarget::HalfHour => self.half_hour_fee,
+			FeeTarget::Hour => self.hour_fee,
+			FeeTarget::Economy => self.economy_fee,
+			FeeTarget::Minimum => self.minimum_fee,
+			FeeTarget::SatPerVb(rate) => return rate,
+		};
+		rate as u64
+	}
+}
+
 #[tokio::main]
 pub async fn get_mempool_feerate() -> Result<MempoolSpaceFeeRate, String> {
 	let response = reqwest::get("https://mempool.space/api/v1/fees/recommended").await;
@@ -34,6 +80,25 @@ pub async fn get_mempool_feerate() -> Result<MempoolSpaceFeeRate, String> {
 mod tests {
 	use super::*;
 
+	#[test]
+	fn test_fee_target() {
+		let rates = MempoolSpaceFeeRate {
+			fastest_fee: 15,
+			half_hour_fee: 14,
+			hour_fee: 13,
+			economy_fee: 12,
+			minimum_fee: 10,
+		};
+
+		assert_eq!(rates.rate_for(FeeTarget::HalfHour), 14);
+		assert_eq!(rates.rate_for(FeeTarget::SatPerVb(3)), 3);
+		assert_eq!(
+			FeeTarget::SatPerVb(3).fee_rate().unwrap(),
+			FeeRate::from_sat_per_vb(3).unwrap()
+		);
+		assert!(FeeTarget::SatPerVb(0).fee_rate().is_err());
+	}
+
 	#[ignore]
 	#[test]
 	fn test_get_feerate() {
@@ -0,0 +1,73 @@
+// Conversions between the rust-bitcoin types used across this crate and the
+// ones re-exported by `bdk::miniscript`, which is pinned to an older rust-bitcoin.
+use super::validate_address::validate_address;
+use bdk::bitcoin as ms_bitcoin;
+use bitcoin::consensus::deserialize;
+use bitcoin::{ecdsa, Address, Network, OutPoint, PublicKey, ScriptBuf, TxOut, Witness};
+
+pub fn to_ms_pubkey(pubkey: &PublicKey) -> Result<ms_bitcoin::PublicKey, String> {
+	ms_bitcoin::PublicKey::from_slice(&pubkey.to_bytes())
+		.map_err(|e| format!("Error converting public key {}: {:?}", pubkey, e))
+}
+
+pub fn from_ms_pubkey(pubkey: &ms_bitcoin::PublicKey) -> Result<PublicKey, String> {
+	PublicKey::from_slice(&pubkey.to_bytes())
+		.map_err(|e| format!("Error converting public key {}: {:?}", pubkey, e))
+}
+
+pub fn to_ms_signature(
+	signature: &ecdsa::Signature,
+) -> Result<ms_bitcoin::ecdsa::Signature, String> {
+	ms_bitcoin::ecdsa::Signature::from_slice(&signature.to_vec())
+		.map_err(|e| format!("Error converting signature: {:?}", e))
+}
+
+pub fn from_ms_script(script: ms_bitcoin::ScriptBuf) -> ScriptBuf {
+	ScriptBuf::from_bytes(script.into_bytes())
+}
+
+pub fn from_ms_address(address: &ms_bitcoin::Address, network: Network) -> Result<Address, String> {
+	validate_address(&address.to_string(), network)
+}
+
+pub fn to_ms_network(network: Network) -> ms_bitcoin::Network {
+	match network {
+		Network::Bitcoin => ms_bitcoin::Network::Bitcoin,
+		Network::Testnet => ms_bitcoin::Network::Testnet,
+		Network::Signet => ms_bitcoin::Network::Signet,
+		_ => ms_bitcoin::Network::Regtest,
+	}
+}
+
+pub fn from_bdk_outpoint(outpoint: &ms_bitcoin::OutPoint) -> Result<OutPoint, String> {
+	deserialize(&ms_bitcoin::consensus::serialize(outpoint))
+		.map_err(|e| format!("Error converting outpoint {}: {:?}", outpoint, e))
+}
+
+pub fn from_bdk_txout(txout: &ms_bitcoin::TxOut) -> Result<TxOut, String> {
+	deserialize(&ms_bitcoin::consensus::serialize(txout))
+		.map_err(|e| format!("Error converting output: {:?}", e))
+}
+
+pub fn witness_from_stack(stack: Vec<Vec<u8>>) -> Witness {
+	Witness::from_slice(&stack)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::str::FromStr;
+
+	#[test]
+	fn test_pubkey_round_trip() {
+		let pubkey = PublicKey::from_str(
+			"02f0eaa04e609b0044ef1fe09a350dc4b744a5a8604a6fa77bc9bf6443ea50739f",
+		)
+		.unwrap();
+
+		let converted = to_ms_pubkey(&pubkey).unwrap();
+
+		assert_eq!(converted.to_string(), pubkey.to_string());
+		assert_eq!(from_ms_pubkey(&converted).unwrap(), pubkey);
+	}
+}
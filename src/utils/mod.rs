@@ -1,5 +1,10 @@
 pub mod bitcoind_rpc;
+pub mod coin_selection;
 pub mod get_feerate;
+pub mod miniscript_compat;
+pub mod musig;
 pub mod test_node;
 pub mod transaction_utils;
 pub mod validate_address;
+pub mod validate_publickeys;
+pub mod weight_estimator;
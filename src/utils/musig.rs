@@ -0,0 +1,164 @@
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{PublicKey, Scalar, Secp256k1, XOnlyPublicKey};
+
+/// Order of the secp256k1 group, big-endian
+const CURVE_ORDER: [u8; 32] = [
+	0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+	0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+
+/// BIP-340 style tagged hash: sha256(sha256(tag) || sha256(tag) || msg)
+fn tagged_hash(tag: &str, msg: &[u8]) -> [u8; 32] {
+	let tag_hash = sha256::Hash::hash(tag.as_bytes());
+	let mut engine = sha256::Hash::engine();
+	engine.input(tag_hash.as_ref());
+	engine.input(tag_hash.as_ref());
+	engine.input(msg);
+	sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// Reduces a 256-bit big-endian integer modulo the curve order.
+/// Any 256-bit value is below 2n, so a single subtraction is enough.
+fn reduce_mod_order(mut value: [u8; 32]) -> [u8; 32] {
+	if value < CURVE_ORDER {
+		return value;
+	}
+	let mut borrow = 0i16;
+	for i in (0..32).rev() {
+		let mut diff = value[i] as i16 - CURVE_ORDER[i] as i16 - borrow;
+		borrow = if diff < 0 {
+			diff += 256;
+			1
+		} else {
+			0
+		};
+		value[i] = diff as u8;
+	}
+	value
+}
+
+/// BIP-327 KeySort: orders the public keys lexicographically by their compressed encoding
+pub fn key_sort(pubkeys: &[PublicKey]) -> Vec<PublicKey> {
+	let mut sorted = pubkeys.to_vec();
+	sorted.sort_by_key(|key| key.serialize());
+	sorted
+}
+
+/// BIP-327 KeyAgg: aggregates the given public keys (in the given order)
+/// into the MuSig2 aggregate public key Q
+pub fn key_agg(pubkeys: &[PublicKey]) -> Result<PublicKey, String> {
+	if pubkeys.is_empty() {
+		return Err("At least one public key is required for key aggregation".to_string());
+	}
+
+	let serialized_keys = pubkeys
+		.iter()
+		.map(|key| key.serialize())
+		.collect::<Vec<[u8; 33]>>();
+
+	let key_list_hash = tagged_hash("KeyAgg list", &serialized_keys.concat());
+	let second_key = serialized_keys
+		.iter()
+		.find(|key| **key != serialized_keys[0])
+		.copied();
+
+	let secp = Secp256k1::verification_only();
+	let mut tweaked_keys = Vec::with_capacity(pubkeys.len());
+
+	for (pubkey, serialized) in pubkeys.iter().zip(serialized_keys.iter()) {
+		if Some(*serialized) == second_key {
+			tweaked_keys.push(*pubkey);
+			continue;
+		}
+
+		let mut coefficient_msg = key_list_hash.to_vec();
+		coefficient_msg.extend_from_slice(serialized);
+		let coefficient = reduce_mod_order(tagged_hash("KeyAgg coefficient", &coefficient_msg));
+		let coefficient = Scalar::from_be_bytes(coefficient)
+			.map_err(|e| format!("Invalid key aggregation coefficient: {:?}", e))?;
+
+		let tweaked = pubkey
+			.mul_tweak(&secp, &coefficient)
+			.map_err(|e| format!("Error tweaking public key: {:?}", e))?;
+		tweaked_keys.push(tweaked);
+	}
+
+	let key_refs = tweaked_keys.iter().collect::<Vec<&PublicKey>>();
+	PublicKey::combine_keys(&key_refs)
+		.map_err(|e| format!("Error aggregating public keys: {:?}", e))
+}
+
+/// The x-only MuSig2 aggregate key of the sorted public keys, usable as a taproot internal key
+pub fn aggregate_xonly_key(pubkeys: &[PublicKey]) -> Result<XOnlyPublicKey, String> {
+	let aggregate = key_agg(&key_sort(pubkeys))?;
+	Ok(aggregate.x_only_public_key().0)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::str::FromStr;
+
+	// test vectors from BIP-327 key_agg_vectors.json
+	fn vector_keys() -> Vec<PublicKey> {
+		[
+			"02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+			"03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+			"023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+		]
+		.iter()
+		.map(|key| PublicKey::from_str(key).unwrap())
+		.collect()
+	}
+
+	fn aggregate_hex(indices: &[usize]) -> String {
+		let keys = vector_keys();
+		let selected = indices.iter().map(|i| keys[*i]).collect::<Vec<_>>();
+		key_agg(&selected)
+			.unwrap()
+			.x_only_public_key()
+			.0
+			.to_string()
+			.to_uppercase()
+	}
+
+	#[test]
+	fn test_key_agg_vectors() {
+		assert_eq!(
+			aggregate_hex(&[0, 1, 2]),
+			"90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C"
+		);
+		assert_eq!(
+			aggregate_hex(&[2, 1, 0]),
+			"6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B"
+		);
+		assert_eq!(
+			aggregate_hex(&[0, 0, 0]),
+			"B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935"
+		);
+		assert_eq!(
+			aggregate_hex(&[0, 0, 1, 1]),
+			"69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E"
+		);
+	}
+
+	#[test]
+	fn test_aggregate_key_is_order_independent() {
+		let keys = vector_keys();
+		let forward = aggregate_xonly_key(&keys).unwrap();
+		let reversed = aggregate_xonly_key(&[keys[2], keys[1], keys[0]]).unwrap();
+
+		assert_eq!(forward, reversed);
+	}
+
+	#[test]
+	fn test_reduce_mod_order() {
+		let mut above_order = CURVE_ORDER;
+		above_order[31] += 5;
+		let mut expected = [0u8; 32];
+		expected[31] = 5;
+
+		assert_eq!(reduce_mod_order(above_order), expected);
+		assert_eq!(reduce_mod_order(expected), expected);
+	}
+}
@@ -1,31 +1,199 @@
 use super::{
-	bitcoind_rpc::get_outpoint_value, get_feerate::MempoolSpaceFeeRate,
+	bitcoind_rpc::{get_outpoint_txout, get_tip_height},
 	validate_address::validate_address,
+	weight_estimator::estimate_weight,
+	weight_estimator::InputWeight,
 };
-use crate::{constants::set_network, domain::funding_transaction::PRECISION};
+use crate::constants::set_network;
 use base64::{engine::general_purpose, Engine as _};
+use bitcoin::psbt::raw::ProprietaryKey;
+use bitcoin::secp256k1::rand::{seq::SliceRandom, thread_rng, Rng};
 use bitcoin::{
-	absolute::LockTime, transaction::Version, Amount, Network, OutPoint, ScriptBuf, Sequence,
-	Transaction, TxIn, TxOut, Witness,
+	absolute::LockTime, transaction::Version, Amount, FeeRate, Network, OutPoint, Script,
+	ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
 };
 use bitcoincore_rpc::Client;
-use round::round_down;
+use std::fmt;
+
+/// Prefix of the proprietary PSBT keys this service annotates transactions with
+pub const PSBT_PROPRIETARY_PREFIX: &[u8] = b"btccol";
+/// Proprietary output subtype marking the output that pays into the collateral multisig
+pub const PSBT_OUT_COLLATERAL: u8 = 0x00;
+
+pub fn collateral_output_key() -> ProprietaryKey {
+	ProprietaryKey {
+		prefix: PSBT_PROPRIETARY_PREFIX.to_vec(),
+		subtype: PSBT_OUT_COLLATERAL,
+		key: Vec::new(),
+	}
+}
+
+/// What happened to the value left over after the amount and fees
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeOutcome {
+	/// a change output with this value was added
+	Change(Amount),
+	/// the inputs covered the amount and fees exactly
+	NoChange,
+	/// the leftover was below the dust threshold of the change script and went to fees
+	DroppedDust(Amount),
+}
+
+impl ChangeOutcome {
+	pub fn change_amount(&self) -> Option<Amount> {
+		match self {
+			ChangeOutcome::Change(amount) => Some(*amount),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for ChangeOutcome {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ChangeOutcome::Change(amount) => write!(f, "{} returned as change", amount),
+			ChangeOutcome::NoChange => write!(f, "The inputs match the amount and fees exactly"),
+			ChangeOutcome::DroppedDust(amount) => write!(
+				f,
+				"{} of change is below the dust threshold and was added to the fee",
+				amount
+			),
+		}
+	}
+}
+
+/// An unsigned transaction with its fee and change decision
+#[derive(Debug, Clone)]
+pub struct ConstructedTxn {
+	pub transaction: Transaction,
+	pub fee: Amount,
+	pub change: ChangeOutcome,
+	/// final vout of each output in the order the builder created them, payments
+	/// first and change last
+	pub output_map: Vec<u32>,
+}
+
+impl ConstructedTxn {
+	/// vout of the first payment: the collateral for a funding transaction, the
+	/// receiving address for a redemption
+	pub fn payment_vout(&self) -> u32 {
+		self.output_map[0]
+	}
+}
+
+/// How builders order their outputs, so the payment is not always first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputOrdering {
+	/// BIP-69: by amount, then by script pubkey
+	#[default]
+	Bip69,
+	Random,
+}
+
+/// Orders the outputs and returns them with the final vout of each original output
+pub fn order_outputs(outputs: Vec<TxOut>, ordering: OutputOrdering) -> (Vec<TxOut>, Vec<u32>) {
+	let mut indexed: Vec<(usize, TxOut)> = outputs.into_iter().enumerate().collect();
+	match ordering {
+		OutputOrdering::Bip69 => indexed.sort_by(|(_, a), (_, b)| {
+			a.value
+				.cmp(&b.value)
+				.then_with(|| a.script_pubkey.as_bytes().cmp(b.script_pubkey.as_bytes()))
+		}),
+		OutputOrdering::Random => indexed.shuffle(&mut thread_rng()),
+	}
+
+	let mut output_map = vec![0u32; indexed.len()];
+	for (vout, (original, _)) in indexed.iter().enumerate() {
+		output_map[*original] = vout as u32;
+	}
+	(
+		indexed.into_iter().map(|(_, output)| output).collect(),
+		output_map,
+	)
+}
+
+/// Recovers the `output_map` of a transaction whose builder created outputs to `scripts`
+/// in this order, payments first and change last
+pub fn recover_output_map(
+	transaction: &Transaction,
+	scripts: &[ScriptBuf],
+) -> Result<Vec<u32>, String> {
+	let mut used = vec![false; transaction.output.len()];
+
+	scripts
+		.iter()
+		.map(|script| {
+			let vout = transaction
+				.output
+				.iter()
+				.enumerate()
+				.position(|(vout, output)| !used[vout] && &output.script_pubkey == script)
+				.ok_or_else(|| {
+					format!(
+						"Transaction {} has no output to {}",
+						transaction.txid(),
+						script
+					)
+				})?;
+			used[vout] = true;
+			Ok(vout as u32)
+		})
+		.collect()
+}
 
-pub fn get_outpoints_total(inputs: &[OutPoint], client: Option<&Client>) -> Result<f64, String> {
-	let mut inputs_total: f64 = 0.0;
+/// Anti-fee-sniping locktime: the tip height, or one time in ten up to 99 blocks
+/// before it as bitcoin core does, so the transaction cannot be mined into a
+/// reorganised copy of the tip
+pub fn anti_fee_sniping_locktime(tip_height: u32) -> Result<LockTime, String> {
+	let mut rng = thread_rng();
+	let mut height = tip_height;
+	if rng.gen_ratio(1, 10) {
+		height = height.saturating_sub(rng.gen_range(0..100));
+	}
+	LockTime::from_height(height).map_err(|e| format!("Invalid locktime height: {}", e))
+}
+
+/// Anti-fee-sniping locktime at `tip_height`, or at bitcoind's tip when it is not given
+pub fn locktime_at_tip(
+	tip_height: Option<u32>,
+	client: Option<&Client>,
+) -> Result<LockTime, String> {
+	let tip_height = match tip_height {
+		Some(height) => height,
+		None => {
+			let height = get_tip_height(client)
+				.map_err(|e| format!("Error fetching the tip height: {:?}", e))?;
+			u32::try_from(height).map_err(|_| format!("Invalid tip height: {}", height))?
+		}
+	};
+	anti_fee_sniping_locktime(tip_height)
+}
+
+pub fn get_outpoints_total(inputs: &[OutPoint], client: Option<&Client>) -> Result<Amount, String> {
+	get_outpoints_txouts(inputs, client)?
+		.iter()
+		.try_fold(Amount::ZERO, |total, txout| total.checked_add(txout.value))
+		.ok_or_else(|| "Input total overflows".to_string())
+}
+
+/// The outputs spent by the given outpoints, in order
+pub fn get_outpoints_txouts(
+	inputs: &[OutPoint],
+	client: Option<&Client>,
+) -> Result<Vec<TxOut>, String> {
+	let mut txouts = Vec::new();
 
 	for input in inputs {
-		let outpoint_value = if client.is_some() && set_network() == Network::Regtest {
-			let node_client = client.expect("No client was supplied");
-			get_outpoint_value(input.txid, input.vout, Some(node_client))
-		} else {
-			get_outpoint_value(input.txid, input.vout, None)
+		let txout = match client {
+			Some(node_client) if set_network() == Network::Regtest => {
+				get_outpoint_txout(input.txid, input.vout, Some(node_client))
+			}
+			_ => get_outpoint_txout(input.txid, input.vout, None),
 		};
-		let value = outpoint_value.map_err(|e| format!("{:?}", e))?;
-		inputs_total += value;
+		txouts.push(txout.map_err(|e| format!("{:?}", e))?);
 	}
 
-	Ok(inputs_total)
+	Ok(txouts)
 }
 
 /// transaction hex (txn_hex) should be in hex format
@@ -51,11 +219,13 @@ pub trait Txn {
 			.collect::<Vec<TxIn>>()
 	}
 
+	/// Fee for the transaction once signed; `input_weights` describes how each input is satisfied
 	fn calculate_fees(
 		tx_outputs: Vec<TxOut>,
 		tx_inputs: Vec<TxIn>,
-		fees: &MempoolSpaceFeeRate,
-	) -> Result<f64, String> {
+		input_weights: &[InputWeight],
+		fee_rate: FeeRate,
+	) -> Result<Amount, String> {
 		let initial_transaction = Transaction {
 			version: Version::TWO,
 			lock_time: LockTime::ZERO,
@@ -63,15 +233,10 @@ pub trait Txn {
 			output: tx_outputs,
 		};
 
-		let txn_initial_size = initial_transaction.vsize();
-		let input_length = initial_transaction.input.len();
-
-		// worse-case size for a signature is 72-bytes
-		let final_size = txn_initial_size + (input_length * 72);
-		let total_fees = fees.fastest_fee * final_size;
-		let fee_rate = Amount::from_sat(total_fees.try_into().unwrap());
-
-		Ok(fee_rate.to_btc())
+		let final_size = estimate_weight(&initial_transaction, input_weights)?.to_vbytes_ceil();
+		fee_rate
+			.fee_vb(final_size)
+			.ok_or_else(|| "Fee calculation overflows".to_string())
 	}
 
 	fn derive_script_pubkeys(
@@ -88,18 +253,128 @@ pub trait Txn {
 		Ok((receiving_script_pubkey_hash, change_script_pubkey_hash))
 	}
 
-	fn amount_in_hex(amount: f64, fees: f64, input_total: f64) -> Result<(Amount, Amount), String> {
-		let input_amount = round_down(input_total, PRECISION);
-		let balance = round_down(input_amount - amount, PRECISION);
-		let change_amount = round_down(balance - fees, PRECISION);
+	/// Returns the output amount and the change left after `amount` and `fees`
+	fn amount_in_hex(
+		amount: Amount,
+		fees: Amount,
+		input_total: Amount,
+	) -> Result<(Amount, Amount), String> {
+		let balance = input_total.checked_sub(amount).ok_or_else(|| {
+			format!(
+				"Inputs of {} do not cover the amount of {}",
+				input_total, amount
+			)
+		})?;
+
+		let change_amount = balance.checked_sub(fees).ok_or_else(|| {
+			format!(
+				"Negative change: {} left after the amount cannot pay fees of {}",
+				balance, fees
+			)
+		})?;
+
+		Ok((amount, change_amount))
+	}
+
+	/// Decides whether a change output is worth creating. Returns the fee to pay and the
+	/// decision; `fee_with_change` must include the cost of the change output.
+	fn apply_change_policy(
+		amount: Amount,
+		input_total: Amount,
+		fee_without_change: Amount,
+		fee_with_change: Amount,
+		change_script: &Script,
+	) -> Result<(Amount, ChangeOutcome), String> {
+		let (_, excess) = Self::amount_in_hex(amount, fee_without_change, input_total)?;
+		if excess == Amount::ZERO {
+			return Ok((fee_without_change, ChangeOutcome::NoChange));
+		}
+
+		let change_output_fee = fee_with_change
+			.checked_sub(fee_without_change)
+			.unwrap_or(Amount::ZERO);
+		match excess.checked_sub(change_output_fee) {
+			Some(change) if change >= change_script.dust_value() => {
+				Ok((fee_with_change, ChangeOutcome::Change(change)))
+			}
+			_ => Ok((
+				fee_without_change + excess,
+				ChangeOutcome::DroppedDust(excess),
+			)),
+		}
+	}
+
+	/// Builds a transaction paying `payments` out of single-key inputs, adding change to
+	/// `change_script` when it is worth creating. `output_map` follows the order of
+	/// `payments`, with the change last.
+	fn build_payment_trxn(
+		inputs: &[OutPoint],
+		spent_outputs: &[TxOut],
+		payments: Vec<TxOut>,
+		change_script: &Script,
+		fee_rate: FeeRate,
+		output_ordering: OutputOrdering,
+		lock_time: LockTime,
+	) -> Result<ConstructedTxn, String> {
+		let input_total = spent_outputs
+			.iter()
+			.try_fold(Amount::ZERO, |total, txout| total.checked_add(txout.value))
+			.ok_or_else(|| "Input total overflows".to_string())?;
+		let input_weights = spent_outputs
+			.iter()
+			.map(|txout| InputWeight::from_script_pubkey(&txout.script_pubkey))
+			.collect::<Result<Vec<_>, _>>()?;
+		let amount = payments
+			.iter()
+			.try_fold(Amount::ZERO, |total, txout| total.checked_add(txout.value))
+			.ok_or_else(|| "Payment total overflows".to_string())?;
 
-		let amount_in_hex = Amount::from_btc(amount)
-			.map_err(|err| format!("Error parsing given amount: {}", err))?;
+		if input_total < amount {
+			return Err(format!("Insufficient amount provided: {}", input_total));
+		}
 
-		let change_amount_hex = Amount::from_btc(change_amount)
-			.map_err(|e| format!("Error parsing change amount: {:?}", e))?;
+		let tx_inputs = Self::calculate_inputs(inputs);
+		let change_output = |value| TxOut {
+			value,
+			script_pubkey: change_script.to_owned(),
+		};
 
-		Ok((amount_in_hex, change_amount_hex))
+		let fee_without_change = Self::calculate_fees(
+			payments.clone(),
+			tx_inputs.clone(),
+			&input_weights,
+			fee_rate,
+		)?;
+		let mut with_change = payments.clone();
+		with_change.push(change_output(Amount::ZERO));
+		let fee_with_change =
+			Self::calculate_fees(with_change, tx_inputs.clone(), &input_weights, fee_rate)?;
+
+		let (fee, change) = Self::apply_change_policy(
+			amount,
+			input_total,
+			fee_without_change,
+			fee_with_change,
+			change_script,
+		)?;
+
+		let mut tx_outputs = payments;
+		if let Some(change_amount) = change.change_amount() {
+			tx_outputs.push(change_output(change_amount));
+		}
+		let (tx_outputs, output_map) = order_outputs(tx_outputs, output_ordering);
+
+		Ok(ConstructedTxn {
+			transaction: Transaction {
+				version: Version::TWO,
+				lock_time,
+				input: tx_inputs,
+				output: tx_outputs,
+			},
+			fee,
+			change,
+			output_map,
+		})
 	}
 }
 
@@ -154,7 +429,7 @@ mod tests {
 
 		let outpoints_total =
 			get_outpoints_total(&outpoints, Some(&client.bitcoind.client)).unwrap();
-		assert_eq!(outpoints_total, 5.0);
+		assert_eq!(outpoints_total, Amount::from_int_btc(5));
 	}
 
 	#[test]
@@ -267,15 +542,153 @@ mod tests {
 
 	#[test]
 	fn test_amount_hex() {
-		let input_total = 4.6875;
-		let fees = 0.0000453;
-		let tx_amount = 2.56;
+		let input_total = Amount::from_sat(468_750_000);
+		let fees = Amount::from_sat(4_530);
+		let tx_amount = Amount::from_sat(256_000_000);
 
 		let (derived_amount, change_amount) =
 			domain::funding_transaction::FundingTxn::amount_in_hex(tx_amount, fees, input_total)
 				.unwrap();
 
-		assert_eq!(derived_amount.to_btc(), 2.56000000);
-		assert_eq!(change_amount.to_btc(), 2.12745470);
+		assert_eq!(derived_amount, Amount::from_sat(256_000_000));
+		assert_eq!(change_amount, Amount::from_sat(212_745_470));
+	}
+
+	#[test]
+	fn test_bip69_output_order() {
+		let script = |byte: u8| ScriptBuf::from_bytes(vec![byte; 22]);
+		let outputs = vec![
+			TxOut {
+				value: Amount::from_sat(500_000),
+				script_pubkey: script(1),
+			},
+			TxOut {
+				value: Amount::from_sat(20_000),
+				script_pubkey: script(2),
+			},
+			TxOut {
+				value: Amount::from_sat(20_000),
+				script_pubkey: script(1),
+			},
+		];
+
+		let (ordered, output_map) = order_outputs(outputs.clone(), OutputOrdering::Bip69);
+		assert_eq!(output_map, vec![2, 1, 0]);
+		for (original, vout) in output_map.iter().enumerate() {
+			assert_eq!(ordered[*vout as usize], outputs[original]);
+		}
+
+		let (shuffled, output_map) = order_outputs(outputs.clone(), OutputOrdering::Random);
+		for (original, vout) in output_map.iter().enumerate() {
+			assert_eq!(shuffled[*vout as usize], outputs[original]);
+		}
+	}
+
+	#[test]
+	fn test_recover_output_map() {
+		let script = |byte: u8| ScriptBuf::from_bytes(vec![byte; 22]);
+		let outputs = vec![
+			TxOut {
+				value: Amount::from_sat(500_000),
+				script_pubkey: script(1),
+			},
+			TxOut {
+				value: Amount::from_sat(20_000),
+				script_pubkey: script(2),
+			},
+			TxOut {
+				value: Amount::from_sat(20_000),
+				script_pubkey: script(1),
+			},
+		];
+		let (ordered, output_map) = order_outputs(outputs, OutputOrdering::Random);
+		let transaction = Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: Vec::new(),
+			output: ordered,
+		};
+
+		let recovered =
+			recover_output_map(&transaction, &[script(1), script(1), script(2)]).unwrap();
+		assert_eq!(recovered[2], output_map[1]);
+		assert!(recover_output_map(&transaction, &[script(3)]).is_err());
+	}
+
+	#[test]
+	fn test_anti_fee_sniping_locktime() {
+		for _ in 0..50 {
+			let height = anti_fee_sniping_locktime(800_000)
+				.unwrap()
+				.to_consensus_u32();
+			assert!((799_901..=800_000).contains(&height));
+		}
+	}
+
+	#[test]
+	fn test_change_policy() {
+		let (_, change_script) = domain::funding_transaction::FundingTxn::derive_script_pubkeys(
+			"bcrt1qq935ysfqnlj9k4jd88hjj093xu00s9ge0a7l5m",
+			"bcrt1qq935ysfqnlj9k4jd88hjj093xu00s9ge0a7l5m",
+		)
+		.unwrap();
+		let policy = |input_total| {
+			domain::funding_transaction::FundingTxn::apply_change_policy(
+				Amount::from_sat(100_000),
+				Amount::from_sat(input_total),
+				Amount::from_sat(1_000),
+				Amount::from_sat(1_310),
+				&change_script,
+			)
+			.unwrap()
+		};
+
+		assert_eq!(
+			policy(101_000),
+			(Amount::from_sat(1_000), ChangeOutcome::NoChange)
+		);
+		// 500 sats left, 190 after paying for the change output: below the 294 sat dust limit
+		assert_eq!(
+			policy(101_500),
+			(
+				Amount::from_sat(1_500),
+				ChangeOutcome::DroppedDust(Amount::from_sat(500))
+			)
+		);
+		assert_eq!(
+			policy(111_310),
+			(
+				Amount::from_sat(1_310),
+				ChangeOutcome::Change(Amount::from_sat(10_000))
+			)
+		);
+		assert!(
+			domain::funding_transaction::FundingTxn::apply_change_policy(
+				Amount::from_sat(100_000),
+				Amount::from_sat(100_500),
+				Amount::from_sat(1_000),
+				Amount::from_sat(1_310),
+				&change_script,
+			)
+			.is_err()
+		);
+	}
+
+	#[test]
+	fn test_amount_hex_negative_change() {
+		let input_total = Amount::from_sat(100_000);
+
+		assert!(domain::funding_transaction::FundingTxn::amount_in_hex(
+			Amount::from_sat(100_001),
+			Amount::ZERO,
+			input_total
+		)
+		.is_err());
+		assert!(domain::funding_transaction::FundingTxn::amount_in_hex(
+			Amount::from_sat(99_000),
+			Amount::from_sat(1_001),
+			input_total
+		)
+		.is_err());
 	}
 }
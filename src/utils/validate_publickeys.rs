@@ -0,0 +1,169 @@
+use crate::domain::party_role::PartyRole;
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
+use bitcoin::PublicKey;
+use std::collections::HashSet;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicKeyError {
+	/// uncompressed keys are non-standard in P2WSH
+	Uncompressed(PartyRole),
+	/// the same key was given for two roles
+	Duplicate(PartyRole, PartyRole),
+	/// a borrower or lender submitted the service's own key
+	ServiceKey(PartyRole),
+	/// the key already secures another loan
+	PreviouslyUsed(PartyRole),
+}
+
+impl fmt::Display for PublicKeyError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			PublicKeyError::Uncompressed(role) => {
+				write!(f, "The {:?} public key must be compressed", role)
+			}
+			PublicKeyError::Duplicate(first, second) => write!(
+				f,
+				"The {:?} and {:?} public keys must be different",
+				first, second
+			),
+			PublicKeyError::ServiceKey(role) => {
+				write!(f, "The {:?} public key belongs to the service", role)
+			}
+			PublicKeyError::PreviouslyUsed(role) => write!(
+				f,
+				"The {:?} public key has already been used for another loan",
+				role
+			),
+		}
+	}
+}
+
+impl std::error::Error for PublicKeyError {}
+
+impl ResponseError for PublicKeyError {
+	fn status_code(&self) -> StatusCode {
+		StatusCode::UNPROCESSABLE_ENTITY
+	}
+}
+
+/// Checks the collateral keys before they are put in a multisig script.
+/// `used_keys` holds the borrower and lender keys already locked in another loan's
+/// collateral; the service key is the same for every loan and is not checked for reuse.
+pub fn validate_publickeys(
+	borrower_pubkey: &PublicKey,
+	lender_pubkey: &PublicKey,
+	service_pubkey: &PublicKey,
+	used_keys: &HashSet<PublicKey>,
+) -> Result<(), PublicKeyError> {
+	let keys = [
+		(PartyRole::Borrower, borrower_pubkey),
+		(PartyRole::Lender, lender_pubkey),
+		(PartyRole::Service, service_pubkey),
+	];
+
+	for (role, key) in keys {
+		if !key.compressed {
+			return Err(PublicKeyError::Uncompressed(role));
+		}
+	}
+
+	for (role, key) in [keys[0], keys[1]] {
+		if key.inner == service_pubkey.inner {
+			return Err(PublicKeyError::ServiceKey(role));
+		}
+	}
+
+	if borrower_pubkey.inner == lender_pubkey.inner {
+		return Err(PublicKeyError::Duplicate(
+			PartyRole::Borrower,
+			PartyRole::Lender,
+		));
+	}
+
+	for (role, key) in [keys[0], keys[1]] {
+		if used_keys.contains(key) {
+			return Err(PublicKeyError::PreviouslyUsed(role));
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::str::FromStr;
+
+	fn keys() -> (PublicKey, PublicKey, PublicKey) {
+		(
+			PublicKey::from_str(
+				"02f0eaa04e609b0044ef1fe09a350dc4b744a5a8604a6fa77bc9bf6443ea50739f",
+			)
+			.unwrap(),
+			PublicKey::from_str(
+				"037c60db011a840523f216e7198054ef071c5acd3d4b466cf2658b7faf30c11e33",
+			)
+			.unwrap(),
+			PublicKey::from_str(
+				"02ca49f36d3de1e135e033052611dd0873af55b57f07d5d0d1090ceb267ac34e6b",
+			)
+			.unwrap(),
+		)
+	}
+
+	#[test]
+	fn test_valid_publickeys() {
+		let (borrower, lender, service) = keys();
+
+		assert!(validate_publickeys(&borrower, &lender, &service, &HashSet::new()).is_ok());
+	}
+
+	#[test]
+	fn test_uncompressed_publickey() {
+		let (borrower, lender, service) = keys();
+		let uncompressed = PublicKey::new_uncompressed(borrower.inner);
+
+		assert_eq!(
+			validate_publickeys(&uncompressed, &lender, &service, &HashSet::new()),
+			Err(PublicKeyError::Uncompressed(PartyRole::Borrower))
+		);
+	}
+
+	#[test]
+	fn test_duplicate_and_service_publickeys() {
+		let (borrower, lender, service) = keys();
+
+		assert_eq!(
+			validate_publickeys(&borrower, &borrower, &service, &HashSet::new()),
+			Err(PublicKeyError::Duplicate(
+				PartyRole::Borrower,
+				PartyRole::Lender
+			))
+		);
+		assert_eq!(
+			validate_publickeys(&borrower, &service, &service, &HashSet::new()),
+			Err(PublicKeyError::ServiceKey(PartyRole::Lender))
+		);
+		assert_eq!(
+			validate_publickeys(&borrower, &lender, &lender, &HashSet::new()),
+			Err(PublicKeyError::ServiceKey(PartyRole::Lender))
+		);
+	}
+
+	#[test]
+	fn test_previously_used_publickey() {
+		let (borrower, lender, service) = keys();
+		let used_keys = HashSet::from([lender]);
+
+		assert_eq!(
+			validate_publickeys(&borrower, &lender, &service, &used_keys),
+			Err(PublicKeyError::PreviouslyUsed(PartyRole::Lender))
+		);
+
+		// the service signs every loan with the same key
+		let used_keys = HashSet::from([service]);
+		assert!(validate_publickeys(&borrower, &lender, &service, &used_keys).is_ok());
+	}
+}
@@ -0,0 +1,231 @@
+use bitcoin::{ScriptBuf, Transaction, VarInt, Weight};
+
+// worst-case ECDSA signature push: length byte, 72-byte DER signature and sighash byte
+const ECDSA_SIGNATURE_PUSH: usize = 1 + 72;
+// compressed public key push
+const PUBKEY_PUSH: usize = 1 + 33;
+// schnorr signature push with a non-default sighash byte
+const SCHNORR_SIGNATURE_PUSH: usize = 1 + 65;
+// push of the 22-byte P2WPKH program in a P2SH-P2WPKH scriptSig
+const P2SH_P2WPKH_SCRIPT_SIG: usize = 1 + 22;
+
+/// Worst-case size of the data that satisfies a single input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputWeight {
+	/// scriptSig length in bytes
+	pub script_sig_len: usize,
+	/// witness stack in weight units, excluding the stack item count
+	pub witness_weight: usize,
+	pub segwit: bool,
+}
+
+impl InputWeight {
+	pub fn p2pkh() -> Self {
+		Self {
+			script_sig_len: ECDSA_SIGNATURE_PUSH + PUBKEY_PUSH,
+			witness_weight: 0,
+			segwit: false,
+		}
+	}
+
+	pub fn p2sh_p2wpkh() -> Self {
+		Self {
+			script_sig_len: P2SH_P2WPKH_SCRIPT_SIG,
+			..Self::p2wpkh()
+		}
+	}
+
+	pub fn p2wpkh() -> Self {
+		Self {
+			script_sig_len: 0,
+			witness_weight: ECDSA_SIGNATURE_PUSH + PUBKEY_PUSH,
+			segwit: true,
+		}
+	}
+
+	pub fn p2tr_key_spend() -> Self {
+		Self {
+			script_sig_len: 0,
+			witness_weight: SCHNORR_SIGNATURE_PUSH,
+			segwit: true,
+		}
+	}
+
+	/// CHECKMULTISIG spend: the dummy element, `threshold` signatures and the witness script
+	pub fn p2wsh_multisig(threshold: usize, witness_script: &ScriptBuf) -> Self {
+		let script_len = witness_script.len();
+		Self {
+			script_sig_len: 0,
+			witness_weight: 1
+				+ threshold * ECDSA_SIGNATURE_PUSH
+				+ VarInt::from(script_len).size()
+				+ script_len,
+			segwit: true,
+		}
+	}
+
+	/// Any P2WSH script, from a miniscript descriptor's `max_weight_to_satisfy`
+	pub fn p2wsh(max_weight_to_satisfy: usize) -> Self {
+		Self {
+			script_sig_len: 0,
+			witness_weight: max_weight_to_satisfy,
+			segwit: true,
+		}
+	}
+
+	/// Estimates a single-key input from the script it spends. P2SH is assumed to wrap
+	/// P2WPKH; P2WSH needs the witness script and is rejected.
+	pub fn from_script_pubkey(script_pubkey: &ScriptBuf) -> Result<Self, String> {
+		if script_pubkey.is_p2wpkh() {
+			Ok(Self::p2wpkh())
+		} else if script_pubkey.is_p2tr() {
+			Ok(Self::p2tr_key_spend())
+		} else if script_pubkey.is_p2sh() {
+			Ok(Self::p2sh_p2wpkh())
+		} else if script_pubkey.is_p2pkh() {
+			Ok(Self::p2pkh())
+		} else {
+			Err(format!(
+				"Cannot estimate the input weight for script {}",
+				script_pubkey
+			))
+		}
+	}
+
+	/// Weight the input adds once signed, on top of the unsigned transaction
+	fn satisfaction_weight(&self) -> usize {
+		let script_sig_growth =
+			VarInt::from(self.script_sig_len).size() - VarInt::from(0usize).size();
+		(script_sig_growth + self.script_sig_len) * 4 + self.witness_weight
+	}
+
+	/// Virtual size of the signed input, for coin selection
+	pub fn vbytes(&self) -> u64 {
+		// outpoint, scriptSig length and sequence
+		let base = (36 + 1 + 4) * 4;
+		let count = usize::from(self.segwit);
+		Weight::from_wu((base + count + self.satisfaction_weight()) as u64).to_vbytes_ceil()
+	}
+}
+
+/// Worst-case weight of `transaction` once every input is signed. `inputs` gives the
+/// satisfaction of each input, in order; the transaction's own scriptSigs and
+/// witnesses are ignored.
+pub fn estimate_weight(
+	transaction: &Transaction,
+	inputs: &[InputWeight],
+) -> Result<Weight, String> {
+	if transaction.input.len() != inputs.len() {
+		return Err(format!(
+			"Expected {} input weights, found {}",
+			transaction.input.len(),
+			inputs.len()
+		));
+	}
+
+	let mut unsigned = transaction.clone();
+	for input in unsigned.input.iter_mut() {
+		input.script_sig = ScriptBuf::new();
+		input.witness.clear();
+	}
+
+	let mut weight = unsigned.weight().to_wu() as usize;
+	weight += inputs
+		.iter()
+		.map(InputWeight::satisfaction_weight)
+		.sum::<usize>();
+
+	if inputs.iter().any(|input| input.segwit) {
+		// segwit marker and flag, and a stack item count for every input
+		weight += 2 + inputs.len();
+	}
+
+	Ok(Weight::from_wu(weight as u64))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::domain::MultisigAddress;
+	use bitcoin::absolute::LockTime;
+	use bitcoin::hashes::Hash;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use bitcoin::transaction::Version;
+	use bitcoin::{Amount, OutPoint, PublicKey, Sequence, TxIn, TxOut, Txid, Witness};
+
+	fn unsigned(inputs: usize) -> Transaction {
+		Transaction {
+			version: Version::TWO,
+			lock_time: LockTime::ZERO,
+			input: (0..inputs)
+				.map(|vout| TxIn {
+					previous_output: OutPoint::new(Txid::all_zeros(), vout as u32),
+					script_sig: ScriptBuf::new(),
+					sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+					witness: Witness::new(),
+				})
+				.collect(),
+			output: vec![TxOut {
+				value: Amount::from_sat(10_000),
+				script_pubkey: ScriptBuf::from_bytes(vec![0u8; 34]),
+			}],
+		}
+	}
+
+	fn multisig_script() -> ScriptBuf {
+		let secp = Secp256k1::new();
+		let [borrower, lender, service] = [1u8, 2, 3].map(|byte| {
+			PublicKey::new(
+				SecretKey::from_slice(&[byte; 32])
+					.unwrap()
+					.public_key(&secp),
+			)
+		});
+		MultisigAddress::new(borrower, lender, service).redeem_script()
+	}
+
+	#[test]
+	fn test_p2wsh_multisig_matches_signed_weight() {
+		let mut transaction = unsigned(2);
+		let script = multisig_script();
+		let estimate =
+			estimate_weight(&transaction, &[InputWeight::p2wsh_multisig(2, &script); 2]).unwrap();
+
+		for input in transaction.input.iter_mut() {
+			input.witness =
+				Witness::from_slice(&[vec![], vec![0u8; 72], vec![0u8; 72], script.to_bytes()]);
+		}
+
+		assert_eq!(script.len(), 105);
+		assert_eq!(estimate, transaction.weight());
+	}
+
+	#[test]
+	fn test_mixed_inputs_match_signed_weight() {
+		let mut transaction = unsigned(3);
+		let estimate = estimate_weight(
+			&transaction,
+			&[
+				InputWeight::p2wpkh(),
+				InputWeight::p2pkh(),
+				InputWeight::p2sh_p2wpkh(),
+			],
+		)
+		.unwrap();
+
+		transaction.input[0].witness = Witness::from_slice(&[vec![0u8; 72], vec![0u8; 33]]);
+		transaction.input[1].script_sig = ScriptBuf::from_bytes(vec![0u8; 107]);
+		transaction.input[2].script_sig = ScriptBuf::from_bytes(vec![0u8; 23]);
+		transaction.input[2].witness = Witness::from_slice(&[vec![0u8; 72], vec![0u8; 33]]);
+
+		assert_eq!(estimate, transaction.weight());
+	}
+
+	#[test]
+	fn test_input_vbytes() {
+		assert_eq!(InputWeight::p2wpkh().vbytes(), 68);
+		assert_eq!(InputWeight::p2pkh().vbytes(), 148);
+		assert_eq!(InputWeight::p2tr_key_spend().vbytes(), 58);
+		assert!(estimate_weight(&unsigned(2), &[InputWeight::p2wpkh()]).is_err());
+	}
+}
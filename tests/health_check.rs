@@ -1,5 +1,5 @@
 use btc_collateral::config::Settings;
-use sqlx::{Connection, PgConnection};
+use sqlx::PgPool;
 use std::net::TcpListener;
 
 #[ignore]
@@ -12,7 +12,7 @@ async fn health_check_works() {
 
 	// Act
 	let response = client
-		.get(&format!("{}/health_check", &address))
+		.get(format!("{}/health_check", &address))
 		.send()
 		.await
 		.expect("Failed to execute request");
@@ -26,7 +26,7 @@ async fn spawn_app() -> String {
 	let port = listener.local_addr().unwrap().port();
 
 	let configuration = Settings::get_configuration().expect("Failed to read config");
-	let connection_pool = PgConnection::connect(&configuration.database.connection_string())
+	let connection_pool = PgPool::connect(&configuration.database.connection_string())
 		.await
 		.expect("Failed to connect to Postgres");
 